@@ -0,0 +1,422 @@
+//! Google Drive sign-ins and their cached tokens
+//!
+//! Signing in through a browser or with a device code leaves OAuth
+//! tokens, including a long-lived refresh token, in a cache file. It's
+//! kept under the data directory unless configured otherwise, and only
+//! the current user may read it.
+
+use std::fs::{self, DirBuilder};
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use google_drive3::api::{About, User};
+use google_drive3::hyper::{self, header, Body, Method, Request, StatusCode};
+use google_drive3::oauth2::storage::TokenInfo;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+
+use crate::gdrive::{self, AuthMethod};
+use crate::output::{Event, Reporter};
+use crate::paths;
+use crate::retry::RetryPolicy;
+
+const TOKEN_CACHE_FILE: &str = "tokencache.json";
+const REVOKE_URL: &str = "https://oauth2.googleapis.com/revoke";
+const ABOUT_URL: &str = "https://www.googleapis.com/drive/v3/about";
+const ABOUT_FIELDS: &str = "user(displayName, emailAddress)";
+
+/// Who Google Drive is signed in as.
+#[derive(Debug, Serialize)]
+pub struct AuthStatus {
+    pub method: AuthMethod,
+    pub token_cache: PathBuf,
+    pub signed_in: bool,
+
+    /// Whether a cached sign-in has run out or been revoked
+    pub expired: bool,
+    pub name: Option<String>,
+    pub email: Option<String>,
+}
+
+/// An entry of the token cache, as written by yup-oauth2
+#[derive(Deserialize)]
+struct CachedToken {
+    token: TokenInfo,
+}
+
+/// What the token cache holds for a user sign-in.
+enum SignIn {
+    None,
+    Expired,
+
+    /// An access token that hasn't expired
+    Token(String),
+}
+
+/// A refreshed access token, as Google's token endpoint returns it
+#[derive(Deserialize)]
+struct RefreshedToken {
+    access_token: String,
+}
+
+/// Path of the token cache, by default in the data directory.
+pub fn token_cache_path(configured: Option<&str>) -> PathBuf {
+    match configured {
+        Some(path) => PathBuf::from(path),
+        None => paths::data_dir().join(TOKEN_CACHE_FILE),
+    }
+}
+
+/// Keep the token cache private: create its directory readable only
+/// by the current user, and restrict an existing cache to 0600. New
+/// caches are created with 0600 already.
+pub fn secure_token_cache(path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+
+    match fs::metadata(path) {
+        Ok(meta) if meta.permissions().mode() & 0o777 != 0o600 => {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+                .with_context(|| format!("Failed to restrict access to {}", path.display()))
+        }
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Show which account Google Drive is signed in as, without starting
+/// a new sign-in.
+///
+/// User sign-ins are read from the token cache, and an expired access
+/// token is refreshed directly with Google. Building an authenticator
+/// instead would start a new sign-in when the refresh fails.
+pub async fn status(
+    secrets_file: Option<String>,
+    method: AuthMethod,
+    token_cache: &Path,
+    retry: RetryPolicy,
+    out: &Reporter,
+) -> Result<()> {
+    let mut status = AuthStatus {
+        method,
+        token_cache: token_cache.to_path_buf(),
+        signed_in: false,
+        expired: false,
+        name: None,
+        email: None,
+    };
+
+    let user = if method == AuthMethod::ServiceAccount {
+        // Service accounts sign in with their key alone
+        let hub = gdrive::get_drivehub(secrets_file, method, token_cache, retry, out).await?;
+        let (_, about) = hub.about()
+            .get()
+            .param("fields", ABOUT_FIELDS)
+            .delegate(&mut hub.retrier("account lookup"))
+            .doit()
+            .await
+            .context("Failed to look up the signed in account")?;
+
+        Some(about.user.unwrap_or_default())
+    } else {
+        let client = gdrive::https_client()?;
+
+        match cached_sign_in(&client, secrets_file, token_cache).await? {
+            SignIn::None => None,
+            SignIn::Expired => {
+                status.expired = true;
+                None
+            }
+            SignIn::Token(token) => {
+                let user = account(&client, &token).await?;
+                status.expired = user.is_none();
+                user
+            }
+        }
+    };
+
+    if let Some(user) = user {
+        status.signed_in = true;
+        status.name = user.display_name;
+        status.email = user.email_address;
+    }
+
+    if out.is_human() {
+        let account = match (&status.name, &status.email) {
+            (_, _) if status.expired => "Sign-in expired".to_string(),
+            (_, _) if !status.signed_in => "Not signed in".to_string(),
+            (Some(name), Some(email)) => format!("{} <{}>", name, email),
+            (None, Some(email)) => email.clone(),
+            (Some(name), None) => name.clone(),
+            (None, None) => "Unknown account".to_string(),
+        };
+
+        println!("Account:     {}", account);
+        println!("Method:      {}", method_name(method));
+        if method != AuthMethod::ServiceAccount {
+            println!("Token cache: {}", token_cache.display());
+        }
+    }
+    out.emit(Event::Auth(status));
+
+    Ok(())
+}
+
+/// Revoke the cached tokens with Google and delete the cache.
+pub async fn logout(token_cache: &Path, out: &Reporter) -> Result<()> {
+    let json = match fs::read_to_string(token_cache) {
+        Ok(json) => json,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            out.say(format!("Not signed in, no token cache at {}", token_cache.display()));
+            out.emit(Event::Logout {
+                token_cache: token_cache.to_path_buf(),
+                revoked: 0,
+                removed: false,
+            });
+            return Ok(());
+        }
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {}", token_cache.display()));
+        }
+    };
+
+    // A cache that can't be parsed is still removed
+    let tokens: Vec<CachedToken> = serde_json::from_str(&json).unwrap_or_else(|e| {
+        out.error(None, None, format!("Failed to parse {}: {}", token_cache.display(), e));
+        Vec::new()
+    });
+
+    let client = gdrive::https_client()?;
+    let mut revoked = 0;
+
+    for cached in tokens {
+        // Revoking the refresh token revokes its access tokens too
+        let Some(token) = cached.token.refresh_token.or(cached.token.access_token) else {
+            continue;
+        };
+
+        match revoke(&client, &token).await {
+            Ok(()) => revoked += 1,
+            Err(e) => out.error(None, None, format!("{:#}", e)),
+        }
+    }
+
+    fs::remove_file(token_cache)
+        .with_context(|| format!("Failed to remove {}", token_cache.display()))?;
+
+    out.say(format!("Revoked {} tokens and removed {}", revoked, token_cache.display()));
+    out.emit(Event::Logout {
+        token_cache: token_cache.to_path_buf(),
+        revoked,
+        removed: true,
+    });
+
+    Ok(())
+}
+
+/// Find a usable access token in the token cache, refreshing expired
+/// ones with their refresh token.
+async fn cached_sign_in<C>(
+    client: &hyper::Client<C>,
+    secrets_file: Option<String>,
+    token_cache: &Path,
+) -> Result<SignIn>
+where
+    C: hyper::client::connect::Connect + Clone + Send + Sync + 'static,
+{
+    let json = match fs::read_to_string(token_cache) {
+        Ok(json) => json,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(SignIn::None),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {}", token_cache.display()));
+        }
+    };
+    let tokens: Vec<CachedToken> = serde_json::from_str(&json)
+        .with_context(|| format!("Failed to parse {}", token_cache.display()))?;
+
+    if tokens.is_empty() {
+        return Ok(SignIn::None);
+    }
+
+    let valid = tokens.iter()
+        .filter(|cached| !cached.token.is_expired())
+        .find_map(|cached| cached.token.access_token.clone());
+    if let Some(token) = valid {
+        return Ok(SignIn::Token(token));
+    }
+
+    let mut refresh_tokens = tokens.into_iter()
+        .filter_map(|cached| cached.token.refresh_token)
+        .peekable();
+    if refresh_tokens.peek().is_none() {
+        return Ok(SignIn::Expired);
+    }
+
+    let app = gdrive::client_app(&gdrive::secrets_path(secrets_file))?;
+    for refresh_token in refresh_tokens {
+        let body = [
+            ("client_id", app.client_id.as_str()),
+            ("client_secret", app.client_secret.as_str()),
+            ("refresh_token", refresh_token.as_str()),
+            ("grant_type", "refresh_token"),
+        ]
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, utf8_percent_encode(value, NON_ALPHANUMERIC)))
+        .collect::<Vec<_>>()
+        .join("&");
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(&app.token_uri)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(body))?;
+
+        let response = client.request(request).await.context("Failed to refresh token")?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap_or_default();
+
+        // Google answers revoked and run out refresh tokens with 400
+        // `invalid_grant`
+        if status == StatusCode::BAD_REQUEST || status == StatusCode::UNAUTHORIZED {
+            continue;
+        }
+        if !status.is_success() {
+            bail!(
+                "Failed to refresh token: {} {}",
+                status,
+                String::from_utf8_lossy(&body).trim(),
+            );
+        }
+
+        let refreshed: RefreshedToken = serde_json::from_slice(&body)
+            .context("Failed to parse refreshed token")?;
+        return Ok(SignIn::Token(refreshed.access_token));
+    }
+
+    Ok(SignIn::Expired)
+}
+
+/// The account an access token belongs to, or `None` if Drive turns
+/// the token away.
+async fn account<C>(client: &hyper::Client<C>, token: &str) -> Result<Option<User>>
+where
+    C: hyper::client::connect::Connect + Clone + Send + Sync + 'static,
+{
+    let fields = utf8_percent_encode(ABOUT_FIELDS, NON_ALPHANUMERIC);
+    let request = Request::builder()
+        .uri(format!("{}?fields={}", ABOUT_URL, fields))
+        .header(header::AUTHORIZATION, format!("Bearer {}", token))
+        .body(Body::empty())?;
+
+    let response = client.request(request)
+        .await
+        .context("Failed to look up the signed in account")?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap_or_default();
+
+    if status == StatusCode::UNAUTHORIZED {
+        return Ok(None);
+    }
+    if !status.is_success() {
+        bail!(
+            "Failed to look up the signed in account: {} {}",
+            status,
+            String::from_utf8_lossy(&body).trim(),
+        );
+    }
+
+    let about: About = serde_json::from_slice(&body)
+        .context("Failed to parse the signed in account")?;
+
+    Ok(Some(about.user.unwrap_or_default()))
+}
+
+async fn revoke<C>(client: &hyper::Client<C>, token: &str) -> Result<()>
+where
+    C: hyper::client::connect::Connect + Clone + Send + Sync + 'static,
+{
+    let body = format!("token={}", utf8_percent_encode(token, NON_ALPHANUMERIC));
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(REVOKE_URL)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(Body::from(body))?;
+
+    let response = client.request(request).await.context("Failed to revoke token")?;
+    let status = response.status();
+
+    if !status.is_success() {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap_or_default();
+        bail!(
+            "Failed to revoke token: {} {}",
+            status,
+            String::from_utf8_lossy(&body).trim(),
+        );
+    }
+
+    Ok(())
+}
+
+fn method_name(method: AuthMethod) -> &'static str {
+    match method {
+        AuthMethod::Browser => "browser",
+        AuthMethod::Device => "device code",
+        AuthMethod::ServiceAccount => "service account",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// An `expires_at` as yup-oauth2 writes it, long past
+    const EXPIRED: &str = "[2001, 1, 0, 0, 0, 0, 0, 0, 0]";
+
+    async fn sign_in(cache: Option<&str>) -> SignIn {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join(TOKEN_CACHE_FILE);
+        if let Some(cache) = cache {
+            fs::write(&path, cache).unwrap();
+        }
+
+        let client = gdrive::https_client().unwrap();
+        cached_sign_in(&client, None, &path).await.unwrap()
+    }
+
+    fn cache(access_token: &str, refresh_token: Option<&str>, expires_at: &str) -> String {
+        let token = serde_json::json!({
+            "access_token": access_token,
+            "refresh_token": refresh_token,
+            "expires_at": serde_json::from_str::<serde_json::Value>(expires_at).unwrap(),
+            "id_token": null,
+        });
+        let scopes = ["https://www.googleapis.com/auth/drive"];
+        serde_json::json!([{ "scopes": scopes, "token": token }]).to_string()
+    }
+
+    #[tokio::test]
+    async fn no_cache_is_signed_out() {
+        assert!(matches!(sign_in(None).await, SignIn::None));
+        assert!(matches!(sign_in(Some("[]")).await, SignIn::None));
+    }
+
+    #[tokio::test]
+    async fn unexpired_tokens_are_used_as_they_are() {
+        let cache = cache("ya29.valid", Some("1//refresh"), "null");
+        assert!(matches!(sign_in(Some(&cache)).await, SignIn::Token(t) if t == "ya29.valid"));
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_without_a_refresh_token_are_expired() {
+        let cache = cache("ya29.old", None, EXPIRED);
+        assert!(matches!(sign_in(Some(&cache)).await, SignIn::Expired));
+    }
+}
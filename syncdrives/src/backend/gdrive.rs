@@ -0,0 +1,259 @@
+//! Google Drive, rooted at a folder
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use google_drive3::api::File;
+
+use super::{join, split, RemoteBackend};
+use crate::download;
+use crate::gdrive::{self as drive, Hub, UploadOptions};
+use crate::output::Reporter;
+use crate::progress::ByteCounter;
+use crate::remote::RemoteEntry;
+
+/// Locks by folder id and file name
+type NameLocks = HashMap<(String, String), Arc<tokio::sync::Mutex<()>>>;
+
+/// Files in a folder, once it's been listed
+type Listing = Arc<tokio::sync::Mutex<Option<Vec<File>>>>;
+
+pub struct GdriveBackend {
+    hub: Hub,
+    folder_id: String,
+    opts: UploadOptions,
+    out: Reporter,
+
+    /// Ids of the folders looked up so far, by path
+    folders: Mutex<HashMap<String, String>>,
+
+    /// Held while looking up or creating a missing folder, by path
+    creating: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+
+    /// Files in the folders uploaded to so far, by folder id
+    listings: Mutex<HashMap<String, Listing>>,
+
+    /// Held while uploading a name into a folder, by folder id and name
+    uploading: Mutex<NameLocks>,
+}
+
+impl GdriveBackend {
+    pub fn new(hub: Hub, folder_id: &str, opts: UploadOptions, out: Reporter) -> Self {
+        Self {
+            hub,
+            folder_id: folder_id.to_string(),
+            opts,
+            out,
+            folders: Mutex::new(HashMap::new()),
+            creating: Mutex::new(HashMap::new()),
+            listings: Mutex::new(HashMap::new()),
+            uploading: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// File named `name` in a folder, listing the folder only the first
+    /// time. Converted files are found by their Google Docs type, others
+    /// mustn't be Google Docs files.
+    async fn find_in_folder(
+        &self,
+        folder_id: &str,
+        name: &str,
+        google_type: Option<&str>,
+    ) -> Result<Option<File>> {
+        // Only uploads into the same folder wait for its listing
+        let listing = self.listing(folder_id);
+        let mut files = listing.lock().await;
+        if files.is_none() {
+            *files = Some(drive::list_folder(&self.hub, folder_id).await?);
+        }
+
+        Ok(files.iter()
+            .flatten()
+            .find(|f| {
+                let mime_type = f.mime_type.as_deref().unwrap_or_default();
+                let same_type = match google_type {
+                    Some(google_type) => mime_type == google_type,
+                    None => !mime_type.starts_with("application/vnd.google-apps."),
+                };
+                f.name.as_deref() == Some(name) && same_type
+            })
+            .cloned())
+    }
+
+    /// A folder's listing, shared by every upload into it.
+    fn listing(&self, folder_id: &str) -> Listing {
+        Arc::clone(self.listings.lock().unwrap().entry(folder_id.to_string()).or_default())
+    }
+
+    /// Id of the folder at `path`, which is created along with its
+    /// parents if missing and `create` is set.
+    async fn find_folder(&self, path: &str, create: bool) -> Result<Option<String>> {
+        let mut id = self.folder_id.clone();
+        let mut current = String::new();
+
+        for name in path.split('/').filter(|n| !n.is_empty()) {
+            current = join(&current, name);
+
+            if let Some(known) = self.folders.lock().unwrap().get(&current) {
+                id = known.clone();
+                continue;
+            }
+
+            // Uploads running side by side mustn't both create a folder,
+            // so the first looks it up while others for it wait
+            let lock = create.then(|| {
+                Arc::clone(self.creating.lock().unwrap().entry(current.clone()).or_default())
+            });
+            let _creating = match &lock {
+                Some(lock) => Some(lock.lock().await),
+                None => None,
+            };
+            if let Some(known) = self.folders.lock().unwrap().get(&current) {
+                id = known.clone();
+                continue;
+            }
+
+            id = match drive::find_folder(&self.hub, name, &id).await? {
+                Some(found) => found,
+                None if create => drive::create_folder(&self.hub, name, &id).await?,
+                None => return Ok(None),
+            };
+            self.folders.lock().unwrap().insert(current.clone(), id.clone());
+        }
+
+        Ok(Some(id))
+    }
+
+    /// The file or folder at `path`.
+    async fn find(&self, path: &str) -> Result<File> {
+        let (dir, name) = split(path);
+        let parent_id = self.find_folder(dir, false)
+            .await?
+            .with_context(|| format!("No folder '{}' on Google Drive", dir))?;
+
+        drive::list_folder(&self.hub, &parent_id)
+            .await?
+            .into_iter()
+            .find(|f| f.name.as_deref() == Some(name))
+            .with_context(|| format!("No file '{}' on Google Drive", path))
+    }
+}
+
+#[async_trait]
+impl RemoteBackend for GdriveBackend {
+    async fn list(&self, path: &str, recursive: bool) -> Result<Vec<RemoteEntry>> {
+        let Some(folder_id) = self.find_folder(path, false).await? else {
+            bail!("No folder '{}' on Google Drive", path);
+        };
+
+        let mut entries = Vec::new();
+        let mut pending = vec![(PathBuf::new(), folder_id)];
+
+        while let Some((rel_dir, id)) = pending.pop() {
+            for file in drive::list_folder(&self.hub, &id).await? {
+                let entry = to_entry(&rel_dir, file)?;
+
+                if recursive && entry.folder {
+                    pending.push((entry.path.clone(), entry.id.clone()));
+                }
+                entries.push(entry);
+            }
+        }
+
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(entries)
+    }
+
+    async fn is_folder(&self, path: &str) -> Result<bool> {
+        if path.trim_matches('/').is_empty() {
+            return Ok(true);
+        }
+
+        Ok(drive::is_folder(&self.find(path).await?))
+    }
+
+    async fn upload(&self, local: &Path, dir: &str, sent: &ByteCounter) -> Result<RemoteEntry> {
+        let (name, google_type) = drive::drive_name(local, &self.opts)?;
+        let parent_id = self.find_folder(dir, true)
+            .await?
+            .context("No folder id returned")?;
+
+        // Uploads running side by side mustn't both create a file
+        let lock = Arc::clone(
+            self.uploading.lock().unwrap().entry((parent_id.clone(), name.clone())).or_default(),
+        );
+        let _uploading = lock.lock().await;
+
+        let existing = self.find_in_folder(&parent_id, &name, google_type).await?;
+
+        let (file, bytes) = match existing {
+            Some(file) => {
+                let id = file.id.as_deref().context("Drive file has no id")?;
+                let bytes = drive::update_file(&self.hub, local, id, &self.opts, sent, &self.out).await?;
+                (file, bytes)
+            }
+            None => {
+                let (file, bytes) =
+                    drive::create_file(&self.hub, local, Some(&parent_id), &self.opts, sent, &self.out).await?;
+                if let Some(files) = self.listing(&parent_id).lock().await.as_mut() {
+                    files.push(file.clone());
+                }
+                (file, bytes)
+            }
+        };
+
+        let mut entry = to_entry(Path::new(dir), file)?;
+        entry.size = Some(bytes);
+
+        Ok(entry)
+    }
+
+    async fn download(&self, path: &str, local: &Path) -> Result<u64> {
+        let file = self.find(path).await?;
+        let mime_type = file.mime_type.as_deref().unwrap_or_default();
+
+        if drive::is_folder(&file) {
+            bail!("'{}' is a folder", path);
+        }
+        if mime_type.starts_with("application/vnd.google-apps.") {
+            bail!("'{}' is a Google Docs file, export it with `download --id`", path);
+        }
+
+        download::fetch_file(&self.hub, &file, local).await
+    }
+
+    /// Files are moved to the trash rather than deleted outright.
+    async fn delete(&self, path: &str) -> Result<()> {
+        let file = self.find(path).await?;
+        let id = file.id.as_deref().context("Drive file has no id")?;
+
+        drive::trash_file(&self.hub, id).await?;
+
+        let listings: Vec<Listing> = self.listings.lock().unwrap().values().cloned().collect();
+        for listing in listings {
+            if let Some(files) = listing.lock().await.as_mut() {
+                files.retain(|f| f.id.as_deref() != Some(id));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn to_entry(rel_dir: &Path, file: File) -> Result<RemoteEntry> {
+    let name = file.name.clone().unwrap_or_default();
+
+    Ok(RemoteEntry {
+        path: rel_dir.join(&name),
+        id: file.id.clone().context("Drive file has no id")?,
+        folder: drive::is_folder(&file),
+        mime_type: file.mime_type,
+        size: file.size.map(|s| s as u64),
+        modified: file.modified_time.map(DateTime::<Local>::from),
+        md5: file.md5_checksum,
+    })
+}
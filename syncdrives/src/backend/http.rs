@@ -0,0 +1,110 @@
+//! Plain HTTP requests for the S3 and WebDAV backends
+
+use std::error::Error as StdError;
+use std::path::Path;
+use anyhow::{bail, Context, Result};
+use futures::{stream, TryStreamExt};
+use google_drive3::{hyper, hyper_rustls};
+use hyper::client::HttpConnector;
+use hyper::{body, Body, Response, StatusCode};
+use hyper_rustls::HttpsConnector;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC};
+use tokio::io::AsyncReadExt;
+
+use crate::progress::ByteCounter;
+
+pub type Client = hyper::Client<HttpsConnector<HttpConnector>>;
+pub type RequestBuilder = hyper::http::request::Builder;
+
+/// Characters escaped in paths, leaving RFC 3986's unreserved ones
+pub const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Body streaming a local file, with the file's size. Bytes are
+/// counted with `sent` as they're handed to the connection.
+pub async fn file_body(path: &Path, sent: &ByteCounter) -> Result<(Body, u64)> {
+    let file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let size = file.metadata()
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?
+        .len();
+
+    let path = path.to_path_buf();
+    let sent = sent.clone();
+
+    // A read error ends the body with that error, failing the request
+    let chunks = stream::try_unfold(file, move |mut file| {
+        let (path, sent) = (path.clone(), sent.clone());
+        async move {
+            let mut buf = vec![0; CHUNK_SIZE];
+            let n = file.read(&mut buf)
+                .await
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+
+            if n == 0 {
+                return Ok(None);
+            }
+            buf.truncate(n);
+            sent.add(n as u64);
+
+            Ok::<_, anyhow::Error>(Some((buf, file)))
+        }
+    });
+    let body = Body::wrap_stream(chunks.map_err(Box::<dyn StdError + Send + Sync>::from));
+
+    Ok((body, size))
+}
+
+/// Fail with the server's explanation unless a response succeeded.
+pub async fn check(response: Response<Body>, what: &str) -> Result<Response<Body>> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let text = body::to_bytes(response.into_body()).await.unwrap_or_default();
+    let text = String::from_utf8_lossy(&text);
+
+    if status == StatusCode::NOT_FOUND {
+        bail!("{}: not found", what);
+    }
+    bail!("{}: {} {}", what, status, text.trim());
+}
+
+/// Read a whole response body as text.
+pub async fn text(response: Response<Body>) -> Result<String> {
+    let bytes = body::to_bytes(response.into_body())
+        .await
+        .context("Failed to read response")?;
+
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn streams_files_and_fails_on_read_errors() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("a.txt");
+        std::fs::write(&path, "hello").unwrap();
+
+        let (body, size) = file_body(&path, &ByteCounter::default()).await.unwrap();
+        assert_eq!(size, 5);
+        assert_eq!(&body::to_bytes(body).await.unwrap()[..], b"hello");
+
+        // Directories open fine but can't be read
+        let (body, _) = file_body(tmp.path(), &ByteCounter::default()).await.unwrap();
+        let err = anyhow::Error::from(body::to_bytes(body).await.unwrap_err());
+        assert!(format!("{:#}", err).contains("Failed to read"), "{:#}", err);
+    }
+}
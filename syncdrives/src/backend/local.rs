@@ -0,0 +1,419 @@
+//! A local directory standing in for cloud storage
+//!
+//! Files and folders get ids and parents the way they do on Google
+//! Drive, with their names, md5s and other metadata kept in an index
+//! next to the stored content. Failures can be simulated to try out
+//! error handling without a network.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use md5::{Digest, Md5};
+use mime::Mime;
+use serde::{Deserialize, Serialize};
+
+use super::{join, RemoteBackend};
+use crate::gdrive::{self, UploadOptions};
+use crate::mimetype;
+use crate::progress::ByteCounter;
+use crate::remote::RemoteEntry;
+use crate::sync::temp_path;
+
+const INDEX_FILE: &str = "index.json";
+const CONTENT_DIR: &str = "content";
+
+/// Id of the root folder
+const ROOT_ID: &str = "root";
+
+/// A directory standing in for a remote.
+#[derive(Clone, Debug, Deserialize)]
+pub struct LocalConfig {
+    pub path: String,
+
+    /// Operations to make fail on purpose
+    #[serde(default)]
+    pub fail: SimulatedFailures,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SimulatedFailures {
+    /// Fail every nth operation
+    pub every: Option<usize>,
+
+    /// Fail operations on these paths and anything under them
+    #[serde(default)]
+    pub paths: Vec<String>,
+
+    /// Only fail these kinds of operations, by default any
+    #[serde(default)]
+    pub ops: Vec<Operation>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Operation {
+    List,
+    Upload,
+    Download,
+    Delete,
+}
+
+/// Everything stored, by id
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Index {
+    next_id: u64,
+    files: BTreeMap<String, StoredFile>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct StoredFile {
+    name: String,
+    parent: String,
+    folder: bool,
+    mime_type: Option<String>,
+    size: Option<u64>,
+    md5: Option<String>,
+    modified: DateTime<Local>,
+}
+
+pub struct LocalBackend {
+    dir: PathBuf,
+    fail: SimulatedFailures,
+    mime_type: Option<Mime>,
+
+    /// Operations so far, for failing every nth one
+    ops: AtomicUsize,
+
+    /// Held while reading and writing the index
+    lock: Mutex<()>,
+}
+
+impl LocalBackend {
+    pub fn new(cfg: &LocalConfig, opts: UploadOptions) -> Result<Self> {
+        let dir = PathBuf::from(&cfg.path);
+        fs::create_dir_all(dir.join(CONTENT_DIR))
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+
+        Ok(Self {
+            dir,
+            fail: cfg.fail.clone(),
+            mime_type: opts.mime_type,
+            ops: AtomicUsize::new(0),
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Fail if this operation is one to simulate a failure for.
+    fn check_failure(&self, op: Operation, path: &str) -> Result<()> {
+        if !self.fail.ops.is_empty() && !self.fail.ops.contains(&op) {
+            return Ok(());
+        }
+
+        let count = self.ops.fetch_add(1, Ordering::SeqCst) + 1;
+        let every = self.fail.every.is_some_and(|n| n > 0 && count.is_multiple_of(n));
+        let matched = self.fail.paths.iter().any(|p| {
+            let p = p.trim_matches('/');
+            path == p || path.starts_with(&format!("{}/", p))
+        });
+
+        if every || matched {
+            bail!("Simulated failure to {} '{}'", format!("{:?}", op).to_lowercase(), path);
+        }
+
+        Ok(())
+    }
+
+    fn content_path(&self, id: &str) -> PathBuf {
+        self.dir.join(CONTENT_DIR).join(id)
+    }
+
+    fn read_index(&self) -> Result<Index> {
+        let path = self.dir.join(INDEX_FILE);
+
+        match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)
+                .with_context(|| format!("Failed to parse {}", path.display())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Index::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    fn write_index(&self, index: &Index) -> Result<()> {
+        let path = self.dir.join(INDEX_FILE);
+        let tmp_path = path.with_extension("json.tmp");
+
+        fs::write(&tmp_path, serde_json::to_string_pretty(index)?)
+            .and_then(|_| fs::rename(&tmp_path, &path))
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+impl Index {
+    fn new_id(&mut self) -> String {
+        self.next_id += 1;
+        format!("local-{:06}", self.next_id)
+    }
+
+    fn child(&self, parent: &str, name: &str) -> Option<(&String, &StoredFile)> {
+        self.files.iter().find(|(_, f)| f.parent == parent && f.name == name)
+    }
+
+    fn children<'a>(&'a self, parent: &'a str) -> impl Iterator<Item = (&'a String, &'a StoredFile)> {
+        self.files.iter().filter(move |(_, f)| f.parent == parent)
+    }
+
+    /// Id of the file or folder at `path`.
+    fn find(&self, path: &str) -> Option<String> {
+        let mut id = ROOT_ID.to_string();
+
+        for name in path.split('/').filter(|n| !n.is_empty()) {
+            id = self.child(&id, name)?.0.clone();
+        }
+
+        Some(id)
+    }
+
+    /// Id of the folder at `path`, creating it and its parents if missing.
+    fn make_folders(&mut self, path: &str) -> Result<String> {
+        let mut id = ROOT_ID.to_string();
+
+        for name in path.split('/').filter(|n| !n.is_empty()) {
+            id = match self.child(&id, name) {
+                Some((child_id, file)) if file.folder => child_id.clone(),
+                Some(_) => bail!("'{}' is a file, not a folder", name),
+                None => {
+                    let new_id = self.new_id();
+                    self.files.insert(new_id.clone(), StoredFile {
+                        name: name.to_string(),
+                        parent: id,
+                        folder: true,
+                        mime_type: None,
+                        size: None,
+                        md5: None,
+                        modified: Local::now(),
+                    });
+                    new_id
+                }
+            };
+        }
+
+        Ok(id)
+    }
+
+    /// Ids of a file or folder and everything in it.
+    fn subtree(&self, id: &str) -> Vec<String> {
+        let mut ids = vec![id.to_string()];
+        let mut i = 0;
+
+        while i < ids.len() {
+            let children: Vec<String> = self.children(&ids[i]).map(|(c, _)| c.clone()).collect();
+            ids.extend(children);
+            i += 1;
+        }
+
+        ids
+    }
+}
+
+#[async_trait]
+impl RemoteBackend for LocalBackend {
+    async fn list(&self, path: &str, recursive: bool) -> Result<Vec<RemoteEntry>> {
+        let path = path.trim_matches('/');
+        self.check_failure(Operation::List, path)?;
+
+        let _lock = self.lock.lock().unwrap();
+        let index = self.read_index()?;
+
+        let folder_id = index.find(path)
+            .filter(|id| id == ROOT_ID || index.files[id].folder)
+            .with_context(|| format!("No folder '{}'", path))?;
+
+        let mut entries = Vec::new();
+        let mut pending = vec![(String::new(), folder_id)];
+
+        while let Some((rel_dir, id)) = pending.pop() {
+            for (child_id, file) in index.children(&id) {
+                let rel_path = join(&rel_dir, &file.name);
+
+                if recursive && file.folder {
+                    pending.push((rel_path.clone(), child_id.clone()));
+                }
+                entries.push(to_entry(&rel_path, child_id, file));
+            }
+        }
+
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(entries)
+    }
+
+    async fn is_folder(&self, path: &str) -> Result<bool> {
+        let path = path.trim_matches('/');
+        self.check_failure(Operation::List, path)?;
+
+        let _lock = self.lock.lock().unwrap();
+        let index = self.read_index()?;
+
+        match index.find(path) {
+            Some(id) => Ok(id == ROOT_ID || index.files[&id].folder),
+            None => bail!("No file '{}'", path),
+        }
+    }
+
+    async fn upload(&self, local: &Path, dir: &str, sent: &ByteCounter) -> Result<RemoteEntry> {
+        let name = gdrive::file_name(local)?;
+        let path = join(dir, name);
+        self.check_failure(Operation::Upload, &path)?;
+
+        let mime_type = self.mime_type.clone().unwrap_or_else(|| mimetype::detect(local));
+
+        let _lock = self.lock.lock().unwrap();
+        let mut index = self.read_index()?;
+        let parent = index.make_folders(dir)?;
+
+        // Uploading over a file keeps its id
+        let id = match index.child(&parent, name) {
+            Some((_, file)) if file.folder => bail!("'{}' is a folder", path),
+            Some((id, _)) => id.clone(),
+            None => index.new_id(),
+        };
+
+        let (size, md5) = copy_with_md5(local, &self.content_path(&id), None, sent)?;
+        let file = StoredFile {
+            name: name.to_string(),
+            parent,
+            folder: false,
+            mime_type: Some(mime_type.to_string()),
+            size: Some(size),
+            md5: Some(md5),
+            modified: Local::now(),
+        };
+
+        let entry = to_entry(&path, &id, &file);
+        index.files.insert(id, file);
+        self.write_index(&index)?;
+
+        Ok(entry)
+    }
+
+    async fn download(&self, path: &str, local: &Path) -> Result<u64> {
+        let path = path.trim_matches('/');
+        self.check_failure(Operation::Download, path)?;
+
+        let (id, file) = {
+            let _lock = self.lock.lock().unwrap();
+            let index = self.read_index()?;
+            let id = index.find(path)
+                .filter(|id| id != ROOT_ID)
+                .with_context(|| format!("No file '{}'", path))?;
+            let file = index.files[&id].clone();
+            (id, file)
+        };
+
+        if file.folder {
+            bail!("'{}' is a folder", path);
+        }
+
+        let (bytes, _) = copy_with_md5(
+            &self.content_path(&id), local, file.md5.as_deref(), &ByteCounter::default(),
+        )?;
+
+        Ok(bytes)
+    }
+
+    /// Deleting a folder deletes everything in it.
+    async fn delete(&self, path: &str) -> Result<()> {
+        let path = path.trim_matches('/');
+        self.check_failure(Operation::Delete, path)?;
+
+        let _lock = self.lock.lock().unwrap();
+        let mut index = self.read_index()?;
+        let id = index.find(path)
+            .filter(|id| id != ROOT_ID)
+            .with_context(|| format!("No file '{}'", path))?;
+
+        for id in index.subtree(&id) {
+            if let Some(file) = index.files.remove(&id) {
+                if !file.folder {
+                    fs::remove_file(self.content_path(&id)).ok();
+                }
+            }
+        }
+
+        self.write_index(&index)
+    }
+}
+
+/// Copy a file by way of a temporary file, returning its size and md5.
+/// If `expected_md5` is given `dest` is only replaced when the copy
+/// matches it.
+fn copy_with_md5(
+    src: &Path,
+    dest: &Path,
+    expected_md5: Option<&str>,
+    copied: &ByteCounter,
+) -> Result<(u64, String)> {
+    let mut reader = fs::File::open(src)
+        .map(|file| copied.reader(file))
+        .with_context(|| format!("Failed to open {}", src.display()))?;
+
+    let tmp_path = temp_path(dest);
+    let writer = fs::File::create(&tmp_path)
+        .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+
+    let mut writer = HashingWriter { inner: writer, hasher: Md5::new() };
+    let copied = io::copy(&mut reader, &mut writer)
+        .with_context(|| format!("Failed to copy {}", src.display()))
+        .and_then(|bytes| {
+            let md5 = format!("{:x}", writer.hasher.finalize_reset());
+
+            if let Some(expected) = expected_md5.filter(|e| *e != md5) {
+                bail!("Checksum mismatch for {}: expected {}, got {}", dest.display(), expected, md5);
+            }
+
+            fs::rename(&tmp_path, dest)
+                .with_context(|| format!("Failed to write {}", dest.display()))
+                .map(|_| (bytes, md5))
+        });
+
+    if copied.is_err() {
+        fs::remove_file(&tmp_path).ok();
+    }
+    copied
+}
+
+/// Writer that hashes what passes through it.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Md5,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn to_entry(path: &str, id: &str, file: &StoredFile) -> RemoteEntry {
+    RemoteEntry {
+        path: PathBuf::from(path),
+        id: id.to_string(),
+        folder: file.folder,
+        mime_type: file.mime_type.clone(),
+        size: file.size,
+        modified: Some(file.modified),
+        md5: file.md5.clone(),
+    }
+}
@@ -0,0 +1,572 @@
+//! Remote storage that files can be backed up to
+//!
+//! Every remote is reached through a [`RemoteBackend`], addressed by
+//! `/`-separated paths relative to the remote's root: its Drive folder,
+//! bucket prefix, WebDAV collection, SFTP directory or local directory.
+
+mod gdrive;
+mod http;
+mod local;
+mod s3;
+mod sftp;
+mod webdav;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+
+use crate::config::{Config, Remote, RemoteKind};
+use crate::gdrive::{self as drive, UploadOptions};
+use crate::output::{format_bytes, Event, Phase, Reporter, Status};
+use crate::progress::ByteCounter;
+use crate::remote::RemoteEntry;
+
+pub use local::LocalConfig;
+pub use s3::S3Config;
+pub use sftp::SftpConfig;
+pub use webdav::WebdavConfig;
+
+/// Storage that files can be listed on, uploaded to, downloaded from
+/// and deleted from.
+#[async_trait]
+pub trait RemoteBackend: Send + Sync {
+    /// Files and folders in the folder at `path`, with paths relative
+    /// to it. With `recursive` the contents of subfolders are included.
+    async fn list(&self, path: &str, recursive: bool) -> Result<Vec<RemoteEntry>>;
+
+    /// Whether `path` is a folder rather than a file, failing if there's
+    /// nothing there.
+    async fn is_folder(&self, path: &str) -> Result<bool>;
+
+    /// Upload a local file into the folder at `dir`, replacing a file of
+    /// the same name, and describe the uploaded file. Bytes are counted
+    /// with `sent` as they go.
+    async fn upload(&self, local: &Path, dir: &str, sent: &ByteCounter) -> Result<RemoteEntry>;
+
+    /// Download the file at `path` to `local`, returning its size.
+    async fn download(&self, path: &str, local: &Path) -> Result<u64>;
+
+    /// Delete the file at `path`.
+    async fn delete(&self, path: &str) -> Result<()>;
+}
+
+/// How to connect to a remote, besides its own settings.
+pub struct Connection<'a> {
+    pub cfg: &'a Config,
+
+    /// Google Drive API client secrets file instead of the configured one
+    pub secrets_file: Option<String>,
+    pub token_cache: &'a Path,
+    pub upload_opts: UploadOptions,
+    pub out: Reporter,
+}
+
+/// Connect to a configured remote.
+pub async fn open(remote: &Remote, conn: Connection<'_>) -> Result<Box<dyn RemoteBackend>> {
+    if conn.upload_opts.convert && !matches!(remote.kind, RemoteKind::Gdrive { .. }) {
+        bail!("Only Google Drive remotes can convert files, `{}` can't", remote.name);
+    }
+    if conn.upload_opts.mime_type.is_some() && matches!(remote.kind, RemoteKind::Sftp(_)) {
+        bail!("SFTP remotes don't store MIME types, `{}` can't take --mime", remote.name);
+    }
+
+    let backend: Box<dyn RemoteBackend> = match &remote.kind {
+        RemoteKind::Gdrive { folder_id, secrets_file } => {
+            let secrets_file = conn.secrets_file.or_else(|| secrets_file.clone());
+            let hub = drive::get_drivehub(
+                secrets_file, conn.cfg.gd_auth, conn.token_cache, conn.cfg.gd_retry, &conn.out,
+            ).await?;
+
+            Box::new(gdrive::GdriveBackend::new(hub, folder_id, conn.upload_opts, conn.out))
+        }
+        RemoteKind::S3(s3_cfg) => Box::new(s3::S3Backend::new(s3_cfg, conn.upload_opts)?),
+        RemoteKind::Webdav(dav_cfg) => {
+            Box::new(webdav::WebdavBackend::new(dav_cfg, conn.upload_opts)?)
+        }
+        RemoteKind::Sftp(sftp_cfg) => Box::new(sftp::SftpBackend::connect(sftp_cfg).await?),
+        RemoteKind::Local(local_cfg) => {
+            Box::new(local::LocalBackend::new(local_cfg, conn.upload_opts)?)
+        }
+    };
+
+    Ok(backend)
+}
+
+/// Upload files, and directory trees as folders of the same name, into
+/// the folder at `dir` on a remote, up to `jobs` files at a time.
+pub async fn upload(
+    backend: &dyn RemoteBackend,
+    locals: &[PathBuf],
+    dir: &str,
+    jobs: usize,
+    out: &Reporter,
+) -> Result<()> {
+    // Every file to upload, with the folder it goes into
+    let mut files = Vec::new();
+
+    for local in locals {
+        if !local.is_dir() {
+            files.push((local.clone(), dir.to_string()));
+            continue;
+        }
+
+        let dir_name = drive::file_name(&local.canonicalize()?)?.to_string();
+        let root = join(dir, &dir_name);
+        let (_, rel_paths) = drive::walk_tree(local)?;
+
+        for rel_path in rel_paths {
+            let rel_dir = rel_path.parent().unwrap_or(Path::new(""));
+            files.push((local.join(&rel_path), join(&root, &to_remote_path(rel_dir)?)));
+        }
+    }
+
+    if files.is_empty() {
+        out.say("Nothing to upload");
+        return Ok(());
+    }
+
+    let total = files.len();
+    let done = AtomicUsize::new(0);
+    let bytes = files.iter().filter_map(|(local, _)| fs::metadata(local).ok()).map(|m| m.len()).sum();
+    let progress = out.progress("Uploading", total, bytes);
+
+    let mut results: Vec<(PathBuf, Result<RemoteEntry>)> = stream::iter(files)
+        .map(|(local, remote_dir)| {
+            let (done, progress) = (&done, &progress);
+            async move {
+                progress.working_on(&local.to_string_lossy());
+                let result = backend.upload(&local, &remote_dir, &progress.counter()).await;
+                let count = done.fetch_add(1, Ordering::SeqCst) + 1;
+
+                match &result {
+                    Ok(entry) => {
+                        report_upload(&local, entry, count, total, out);
+                    }
+                    Err(e) => {
+                        out.say(format!("[{}/{}] Failed '{}'", count, total, local.display()));
+                        out.emit(upload_event(&local, None, Status::Error));
+                        out.error(None, Some(Phase::Upload), format!("{:#}", e));
+                    }
+                }
+                progress.file_done(None);
+
+                (local, result)
+            }
+        })
+        .buffer_unordered(jobs.max(1))
+        .collect()
+        .await;
+    drop(progress);
+
+    results.sort_by(|a, b| a.0.cmp(&b.0));
+    let bytes: u64 = results.iter()
+        .filter_map(|(_, r)| r.as_ref().ok())
+        .map(|entry| entry.size.unwrap_or_default())
+        .sum();
+    let failed: Vec<&PathBuf> = results.iter()
+        .filter(|(_, r)| r.is_err())
+        .map(|(local, _)| local)
+        .collect();
+
+    out.say(format!(
+        "Uploaded {} of {} files ({})",
+        total - failed.len(), total, format_bytes(bytes),
+    ));
+    if !failed.is_empty() {
+        out.say("Failed to upload:");
+        for local in failed.iter() {
+            out.say(format!("  {}", local.display()));
+        }
+    }
+    out.emit(Event::UploadSummary {
+        uploaded: total - failed.len(),
+        failed: failed.len(),
+        bytes,
+    });
+
+    if !failed.is_empty() {
+        bail!("{} uploads failed", failed.len());
+    }
+
+    Ok(())
+}
+
+fn report_upload(local: &Path, entry: &RemoteEntry, count: usize, total: usize, out: &Reporter) {
+    let name = entry.path.file_name().unwrap_or_default().to_string_lossy();
+    let mime_type = entry.mime_type.as_deref().unwrap_or_default();
+
+    out.say(format!(
+        "[{}/{}] Uploaded '{}' ({}) with ID: {}",
+        count, total, name, mime_type, entry.id,
+    ));
+    out.emit(upload_event(local, Some(entry), Status::Ok));
+}
+
+fn upload_event(local: &Path, entry: Option<&RemoteEntry>, status: Status) -> Event {
+    let name = match entry {
+        Some(entry) => entry.path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+        None => local.file_name().unwrap_or_default().to_string_lossy().to_string(),
+    };
+
+    Event::Upload {
+        phase: Phase::Upload,
+        path: local.to_path_buf(),
+        name,
+        id: entry.map(|e| e.id.clone()).unwrap_or_default(),
+        mime_type: entry.and_then(|e| e.mime_type.clone()).unwrap_or_default(),
+        bytes: entry.and_then(|e| e.size).unwrap_or_default(),
+        status,
+    }
+}
+
+/// Download a file, or a folder and everything in it, from a remote to
+/// `dest`. If `dest` is an existing directory the download goes inside it.
+pub async fn download(
+    backend: &dyn RemoteBackend,
+    path: &str,
+    dest: &Path,
+    out: &Reporter,
+) -> Result<()> {
+    let path = path.trim_matches('/');
+    let name = path.rsplit('/').next().filter(|n| !n.is_empty());
+
+    let root = match name {
+        Some(name) if dest.is_dir() => dest.join(name),
+        _ => dest.to_path_buf(),
+    };
+
+    if !path.is_empty() && !backend.is_folder(path).await? {
+        download_file(backend, path, &root, out).await?;
+        return Ok(());
+    }
+
+    let entries = backend.list(path, true).await?;
+
+    fs::create_dir_all(&root)
+        .with_context(|| format!("Failed to create {}", root.display()))?;
+
+    let (mut files, mut failed) = (0, 0);
+    for entry in entries {
+        let local = root.join(&entry.path);
+
+        if entry.folder {
+            fs::create_dir_all(&local)
+                .with_context(|| format!("Failed to create {}", local.display()))?;
+            continue;
+        }
+
+        files += 1;
+        let remote_path = join(path, &to_remote_path(&entry.path)?);
+        if let Err(e) = download_file(backend, &remote_path, &local, out).await {
+            out.error(None, Some(Phase::Download), format!("{:#}", e));
+            out.emit(download_event(&local, &entry.id, 0, Status::Error));
+            failed += 1;
+        }
+    }
+
+    out.say(format!("Downloaded {} of {} files to `{}`", files - failed, files, dest.display()));
+
+    if failed > 0 {
+        bail!("{} downloads failed", failed);
+    }
+
+    Ok(())
+}
+
+async fn download_file(
+    backend: &dyn RemoteBackend,
+    path: &str,
+    local: &Path,
+    out: &Reporter,
+) -> Result<()> {
+    if let Some(dir) = local.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+
+    let bytes = backend.download(path, local).await?;
+
+    out.say(format!("Downloaded '{}' ({})", local.display(), format_bytes(bytes)));
+    out.emit(download_event(local, path, bytes, Status::Ok));
+
+    Ok(())
+}
+
+fn download_event(local: &Path, id: &str, bytes: u64, status: Status) -> Event {
+    Event::Download {
+        phase: Phase::Download,
+        path: local.to_path_buf(),
+        id: id.to_string(),
+        bytes,
+        status,
+    }
+}
+
+/// Join remote paths, either of which may be empty.
+pub fn join(dir: &str, name: &str) -> String {
+    match (dir.trim_matches('/'), name.trim_matches('/')) {
+        ("", name) => name.to_string(),
+        (dir, "") => dir.to_string(),
+        (dir, name) => format!("{}/{}", dir, name),
+    }
+}
+
+/// Split a remote path into its folder and name.
+pub fn split(path: &str) -> (&str, &str) {
+    let path = path.trim_matches('/');
+
+    match path.rsplit_once('/') {
+        Some((dir, name)) => (dir, name),
+        None => ("", path),
+    }
+}
+
+/// Remote path of a relative local path.
+fn to_remote_path(rel_path: &Path) -> Result<String> {
+    let parts: Option<Vec<&str>> = rel_path.iter().map(|p| p.to_str()).collect();
+
+    match parts {
+        Some(parts) => Ok(parts.join("/")),
+        None => bail!("Invalid file name {}", rel_path.display()),
+    }
+}
+
+/// Entry for a remote file at `path` relative to a listed folder and
+/// `full_path` relative to the remote's root.
+fn entry(path: &str, full_path: &str) -> RemoteEntry {
+    RemoteEntry {
+        path: PathBuf::from(path),
+        id: full_path.to_string(),
+        folder: false,
+        mime_type: None,
+        size: None,
+        modified: None,
+        md5: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use local::{LocalBackend, Operation, SimulatedFailures};
+    use crate::output::OutputMode;
+
+    /// A local remote in a temporary directory, with a tree to upload:
+    /// `src/a.txt`, `src/docs/b.md` and `src/docs/deep/c.txt`
+    struct Fixture {
+        tmp: TempDir,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let tmp = TempDir::new().unwrap();
+            let src = tmp.path().join("src");
+            fs::create_dir_all(src.join("docs/deep")).unwrap();
+            fs::write(src.join("a.txt"), "a").unwrap();
+            fs::write(src.join("docs/b.md"), "bb").unwrap();
+            fs::write(src.join("docs/deep/c.txt"), "ccc").unwrap();
+
+            Self { tmp }
+        }
+
+        fn path(&self, rel_path: &str) -> PathBuf {
+            self.tmp.path().join(rel_path)
+        }
+
+        fn backend(&self, fail: SimulatedFailures) -> LocalBackend {
+            let cfg = LocalConfig {
+                path: self.path("remote").to_string_lossy().into_owned(),
+                fail,
+            };
+            LocalBackend::new(&cfg, UploadOptions::default()).unwrap()
+        }
+
+        async fn files(&self, backend: &LocalBackend, path: &str) -> Vec<(String, String)> {
+            backend.list(path, true)
+                .await
+                .unwrap()
+                .into_iter()
+                .filter(|e| !e.folder)
+                .map(|e| (e.path.to_string_lossy().into_owned(), e.id))
+                .collect()
+        }
+    }
+
+    fn quiet() -> Reporter {
+        Reporter::new(OutputMode::Human).buffered()
+    }
+
+    fn paths(files: &[(String, String)]) -> Vec<&str> {
+        files.iter().map(|(path, _)| path.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn uploads_trees_and_downloads_them_back() {
+        let fixture = Fixture::new();
+        let backend = fixture.backend(SimulatedFailures::default());
+        let locals = [fixture.path("src"), fixture.path("src/a.txt")];
+
+        upload(&backend, &locals, "backup", 2, &quiet()).await.unwrap();
+
+        let files = fixture.files(&backend, "backup").await;
+        assert_eq!(paths(&files), ["a.txt", "src/a.txt", "src/docs/b.md", "src/docs/deep/c.txt"]);
+
+        let dest = fixture.path("restored");
+        fs::create_dir(&dest).unwrap();
+        download(&backend, "backup/src", &dest, &quiet()).await.unwrap();
+
+        assert_eq!(fs::read_to_string(dest.join("src/a.txt")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(dest.join("src/docs/deep/c.txt")).unwrap(), "ccc");
+    }
+
+    #[tokio::test]
+    async fn failed_uploads_can_be_retried_without_duplicates() {
+        let fixture = Fixture::new();
+        let failing = fixture.backend(SimulatedFailures {
+            paths: vec!["backup/src/docs".to_string()],
+            ..Default::default()
+        });
+
+        let err = upload(&failing, &[fixture.path("src")], "backup", 4, &quiet()).await.unwrap_err();
+        assert_eq!(err.to_string(), "2 uploads failed");
+        let before = fixture.files(&failing, "backup").await;
+        assert_eq!(paths(&before), ["src/a.txt"]);
+
+        let backend = fixture.backend(SimulatedFailures::default());
+        upload(&backend, &[fixture.path("src")], "backup", 4, &quiet()).await.unwrap();
+
+        let after = fixture.files(&backend, "backup").await;
+        assert_eq!(paths(&after), ["src/a.txt", "src/docs/b.md", "src/docs/deep/c.txt"]);
+        assert_eq!(after[0], before[0]);
+    }
+
+    #[tokio::test]
+    async fn summarises_uploads_of_several_files() {
+        let fixture = Fixture::new();
+        let backend = fixture.backend(SimulatedFailures {
+            paths: vec!["docs/b.md".to_string()],
+            ..Default::default()
+        });
+        let locals = [fixture.path("src/a.txt"), fixture.path("src/docs")];
+
+        let out = quiet();
+        upload(&backend, &locals, "", 1, &out).await.unwrap_err();
+
+        let held = out.held();
+        assert_eq!(held[held.len() - 3..], [
+            "out: Uploaded 2 of 3 files (4 B)",
+            "out: Failed to upload:",
+            format!("out:   {}", fixture.path("src/docs/b.md").display()).as_str(),
+        ]);
+
+        let out = Reporter::new(OutputMode::Json).buffered();
+        upload(&backend, &locals, "", 4, &out).await.unwrap_err();
+
+        assert_eq!(
+            out.held().last().unwrap(),
+            r#"out: {"event":"upload-summary","uploaded":2,"failed":1,"bytes":4}"#,
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_every_nth_operation_of_the_given_kinds() {
+        let fixture = Fixture::new();
+        let backend = fixture.backend(SimulatedFailures {
+            every: Some(2),
+            ops: vec![Operation::Upload],
+            ..Default::default()
+        });
+
+        let err = upload(&backend, &[fixture.path("src")], "", 1, &quiet()).await.unwrap_err();
+        assert_eq!(err.to_string(), "1 uploads failed");
+
+        // Listing doesn't count towards the failures
+        assert_eq!(fixture.files(&backend, "").await.len(), 2);
+        assert_eq!(fixture.files(&backend, "").await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_downloads_leave_the_rest_in_place() {
+        let fixture = Fixture::new();
+        let backend = fixture.backend(SimulatedFailures {
+            paths: vec!["src/docs/b.md".to_string()],
+            ops: vec![Operation::Download],
+            ..Default::default()
+        });
+        upload(&backend, &[fixture.path("src")], "", 4, &quiet()).await.unwrap();
+
+        let dest = fixture.path("restored");
+        let err = download(&backend, "src", &dest, &quiet()).await.unwrap_err();
+        assert_eq!(err.to_string(), "1 downloads failed");
+
+        assert_eq!(fs::read_to_string(dest.join("a.txt")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(dest.join("docs/deep/c.txt")).unwrap(), "ccc");
+        assert!(!dest.join("docs/b.md").exists());
+    }
+
+    #[tokio::test]
+    async fn mismatching_downloads_leave_the_existing_file_alone() {
+        let fixture = Fixture::new();
+        let backend = fixture.backend(SimulatedFailures::default());
+        upload(&backend, &[fixture.path("src/a.txt")], "", 1, &quiet()).await.unwrap();
+
+        // Corrupt the stored content behind the index's back
+        let files = fixture.files(&backend, "").await;
+        fs::write(fixture.path("remote/content").join(&files[0].1), "corrupt").unwrap();
+
+        let dest = fixture.path("restored");
+        fs::create_dir(&dest).unwrap();
+        fs::write(dest.join("a.txt"), "good").unwrap();
+
+        let err = backend.download("a.txt", &dest.join("a.txt")).await.unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"), "{}", err);
+        assert_eq!(fs::read_to_string(dest.join("a.txt")).unwrap(), "good");
+        assert_eq!(fs::read_dir(&dest).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn downloads_empty_folders_and_reports_listing_failures() {
+        let fixture = Fixture::new();
+        let backend = fixture.backend(SimulatedFailures::default());
+        upload(&backend, &[fixture.path("src/a.txt")], "notes", 1, &quiet()).await.unwrap();
+
+        // Deleting the only file leaves an empty folder behind
+        backend.delete("notes/a.txt").await.unwrap();
+        assert!(backend.is_folder("notes").await.unwrap());
+
+        let dest = fixture.path("restored");
+        download(&backend, "notes", &dest, &quiet()).await.unwrap();
+        assert!(dest.is_dir() && fs::read_dir(&dest).unwrap().next().is_none());
+
+        let err = download(&backend, "missing", &fixture.path("missing"), &quiet()).await.unwrap_err();
+        assert_eq!(err.to_string(), "No file 'missing'");
+
+        let failing = fixture.backend(SimulatedFailures {
+            paths: vec!["notes".to_string()],
+            ops: vec![Operation::List],
+            ..Default::default()
+        });
+        let err = download(&failing, "notes", &fixture.path("failed"), &quiet()).await.unwrap_err();
+        assert_eq!(err.to_string(), "Simulated failure to list 'notes'");
+        assert!(!fixture.path("failed").exists());
+    }
+
+    #[tokio::test]
+    async fn downloads_single_files_into_existing_directories() {
+        let fixture = Fixture::new();
+        let backend = fixture.backend(SimulatedFailures::default());
+        upload(&backend, &[fixture.path("src/docs/b.md")], "notes", 1, &quiet()).await.unwrap();
+
+        let dest = fixture.path("restored");
+        fs::create_dir(&dest).unwrap();
+        download(&backend, "notes/b.md", &dest, &quiet()).await.unwrap();
+        download(&backend, "notes/b.md", &dest.join("renamed.md"), &quiet()).await.unwrap();
+
+        assert_eq!(fs::read_to_string(dest.join("b.md")).unwrap(), "bb");
+        assert_eq!(fs::read_to_string(dest.join("renamed.md")).unwrap(), "bb");
+    }
+}
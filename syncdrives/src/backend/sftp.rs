@@ -0,0 +1,278 @@
+//! Directories on SSH servers, over SFTP
+
+use std::fs;
+use std::io;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use serde::Deserialize;
+use ssh2::{CheckResult, KnownHostFileKind, Session, Sftp};
+
+use super::{entry, join, RemoteBackend};
+use crate::gdrive;
+use crate::progress::ByteCounter;
+use crate::remote::RemoteEntry;
+use crate::sync::temp_path;
+
+/// A directory on an SSH server.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SftpConfig {
+    pub host: String,
+
+    #[serde(default = "default_port")]
+    pub port: u16,
+
+    pub username: String,
+
+    /// Private key to log in with. Without it or a password the SSH
+    /// agent is used.
+    pub key_file: Option<String>,
+    pub password: Option<String>,
+
+    /// Directory to use as the remote's root
+    #[serde(default)]
+    pub path: String,
+}
+
+fn default_port() -> u16 {
+    22
+}
+
+pub struct SftpBackend {
+    sftp: Arc<Mutex<Sftp>>,
+    root: PathBuf,
+}
+
+impl SftpBackend {
+    /// Log in to the server, which must be in `~/.ssh/known_hosts`.
+    pub async fn connect(cfg: &SftpConfig) -> Result<Self> {
+        let cfg = cfg.clone();
+        let root = PathBuf::from(if cfg.path.is_empty() { "." } else { &cfg.path });
+
+        let sftp = tokio::task::spawn_blocking(move || {
+            let what = format!("Failed to connect to {}:{}", cfg.host, cfg.port);
+            let tcp = TcpStream::connect((cfg.host.as_str(), cfg.port)).context(what.clone())?;
+
+            let mut session = Session::new().context(what.clone())?;
+            session.set_tcp_stream(tcp);
+            session.handshake().context(what.clone())?;
+
+            check_host_key(&session, &cfg.host, cfg.port)?;
+
+            let login = match (&cfg.key_file, &cfg.password) {
+                (Some(key_file), _) => {
+                    session.userauth_pubkey_file(&cfg.username, None, Path::new(key_file), None)
+                }
+                (None, Some(password)) => session.userauth_password(&cfg.username, password),
+                (None, None) => session.userauth_agent(&cfg.username),
+            };
+            login.with_context(|| format!("Failed to log in to {} as {}", cfg.host, cfg.username))?;
+
+            session.sftp().context(what)
+        })
+        .await??;
+
+        Ok(Self { sftp: Arc::new(Mutex::new(sftp)), root })
+    }
+
+    /// Run blocking SFTP calls off the async runtime.
+    async fn blocking<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Sftp) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let sftp = Arc::clone(&self.sftp);
+
+        tokio::task::spawn_blocking(move || f(&sftp.lock().unwrap())).await?
+    }
+
+    fn full_path(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_matches('/'))
+    }
+}
+
+#[async_trait]
+impl RemoteBackend for SftpBackend {
+    async fn list(&self, path: &str, recursive: bool) -> Result<Vec<RemoteEntry>> {
+        let root = self.full_path(path);
+        let path = path.trim_matches('/').to_string();
+
+        self.blocking(move |sftp| {
+            let mut entries = Vec::new();
+            let mut pending = vec![String::new()];
+
+            while let Some(rel_dir) = pending.pop() {
+                let dir = root.join(&rel_dir);
+                let listing = sftp.readdir(&dir)
+                    .with_context(|| format!("Failed to list {}", dir.display()))?;
+
+                for (full_path, stat) in listing {
+                    let Some(name) = full_path.file_name().and_then(|n| n.to_str()) else {
+                        continue;
+                    };
+                    let rel_path = join(&rel_dir, name);
+
+                    if recursive && stat.is_dir() {
+                        pending.push(rel_path.clone());
+                    }
+
+                    entries.push(RemoteEntry {
+                        folder: stat.is_dir(),
+                        size: stat.size.filter(|_| stat.is_file()),
+                        modified: stat.mtime
+                            .and_then(|t| DateTime::from_timestamp(t as i64, 0))
+                            .map(|t| t.with_timezone(&Local)),
+                        ..entry(&rel_path, &join(&path, &rel_path))
+                    });
+                }
+            }
+
+            entries.sort_by(|a, b| a.path.cmp(&b.path));
+            Ok(entries)
+        })
+        .await
+    }
+
+    async fn is_folder(&self, path: &str) -> Result<bool> {
+        let remote_path = self.full_path(path);
+
+        self.blocking(move |sftp| {
+            let stat = sftp.stat(&remote_path)
+                .with_context(|| format!("Failed to read {}", remote_path.display()))?;
+            Ok(stat.is_dir())
+        })
+        .await
+    }
+
+    /// Files go up under a temporary name and replace the old one only
+    /// once complete, so a failed upload leaves it as it was.
+    async fn upload(&self, local: &Path, dir: &str, sent: &ByteCounter) -> Result<RemoteEntry> {
+        let path = join(dir, gdrive::file_name(local)?);
+        let (remote_dir, remote_path) = (self.full_path(dir), self.full_path(&path));
+        let (local, sent) = (local.to_path_buf(), sent.clone());
+
+        let size = self.blocking(move |sftp| {
+            make_dirs(sftp, &remote_dir)?;
+
+            let mut src = fs::File::open(&local)
+                .map(|file| sent.reader(file))
+                .with_context(|| format!("Failed to open {}", local.display()))?;
+
+            let tmp_path = temp_path(&remote_path);
+            let copied = sftp.create(&tmp_path)
+                .with_context(|| format!("Failed to create {}", tmp_path.display()))
+                .and_then(|mut dest| {
+                    io::copy(&mut src, &mut dest)
+                        .with_context(|| format!("Failed to upload {}", local.display()))
+                })
+                .and_then(|size| replace(sftp, &tmp_path, &remote_path).map(|_| size));
+
+            if copied.is_err() {
+                sftp.unlink(&tmp_path).ok();
+            }
+            copied
+        })
+        .await?;
+
+        Ok(RemoteEntry {
+            size: Some(size),
+            modified: Some(Local::now()),
+            ..entry(&path, &path)
+        })
+    }
+
+    async fn download(&self, path: &str, local: &Path) -> Result<u64> {
+        let remote_path = self.full_path(path);
+        let local = local.to_path_buf();
+
+        self.blocking(move |sftp| {
+            let mut src = sftp.open(&remote_path)
+                .with_context(|| format!("Failed to open {}", remote_path.display()))?;
+
+            let tmp_path = temp_path(&local);
+            let mut dest = fs::File::create(&tmp_path)
+                .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+
+            let copied = io::copy(&mut src, &mut dest)
+                .with_context(|| format!("Failed to download {}", remote_path.display()))
+                .and_then(|bytes| {
+                    fs::rename(&tmp_path, &local)
+                        .with_context(|| format!("Failed to write {}", local.display()))
+                        .map(|_| bytes)
+                });
+
+            if copied.is_err() {
+                fs::remove_file(&tmp_path).ok();
+            }
+            copied
+        })
+        .await
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        let remote_path = self.full_path(path);
+
+        self.blocking(move |sftp| {
+            sftp.unlink(&remote_path)
+                .with_context(|| format!("Failed to delete {}", remote_path.display()))
+        })
+        .await
+    }
+}
+
+/// Refuse servers whose host key isn't already known.
+fn check_host_key(session: &Session, host: &str, port: u16) -> Result<()> {
+    let path = dirs::home_dir()
+        .context("No home directory to find known_hosts in")?
+        .join(".ssh")
+        .join("known_hosts");
+
+    let mut known_hosts = session.known_hosts()?;
+    known_hosts.read_file(&path, KnownHostFileKind::OpenSSH)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let (key, _) = session.host_key().context("Server sent no host key")?;
+
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound => {
+            bail!("{} isn't in {}, connect once with ssh to add it", host, path.display())
+        }
+        CheckResult::Mismatch => bail!("Host key of {} doesn't match {}", host, path.display()),
+        CheckResult::Failure => bail!("Failed to check the host key of {}", host),
+    }
+}
+
+/// Rename `from` over `to`. SFTP version 3 servers such as OpenSSH's
+/// refuse to rename over an existing file, so it's removed first.
+fn replace(sftp: &Sftp, from: &Path, to: &Path) -> Result<()> {
+    if sftp.rename(from, to, None).is_ok() {
+        return Ok(());
+    }
+
+    if sftp.stat(to).is_ok() {
+        sftp.unlink(to)
+            .with_context(|| format!("Failed to replace {}", to.display()))?;
+    }
+    sftp.rename(from, to, None)
+        .with_context(|| format!("Failed to write {}", to.display()))
+}
+
+/// Create a directory and its parents unless they exist.
+fn make_dirs(sftp: &Sftp, dir: &Path) -> Result<()> {
+    let mut path = PathBuf::new();
+
+    for part in dir.iter() {
+        path.push(part);
+
+        if sftp.stat(&path).is_err() {
+            sftp.mkdir(&path, 0o755)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,414 @@
+//! WebDAV servers such as Nextcloud or Apache's mod_dav
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Mutex;
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::{DateTime, Local};
+use google_drive3::hyper::{Body, Method, Request, StatusCode};
+use mime::Mime;
+use percent_encoding::{percent_decode_str, utf8_percent_encode};
+use quick_xml::events::Event as XmlEvent;
+use quick_xml::Reader;
+use serde::Deserialize;
+
+use super::http::{self, Client, RequestBuilder, PATH_SEGMENT};
+use super::{entry, join, split, RemoteBackend};
+use crate::download::write_body;
+use crate::gdrive::{self, UploadOptions};
+use crate::mimetype;
+use crate::progress::ByteCounter;
+use crate::remote::RemoteEntry;
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:">
+  <d:prop>
+    <d:resourcetype/>
+    <d:getcontentlength/>
+    <d:getlastmodified/>
+    <d:getcontenttype/>
+  </d:prop>
+</d:propfind>"#;
+
+/// A collection on a WebDAV server.
+#[derive(Clone, Debug, Deserialize)]
+pub struct WebdavConfig {
+    /// URL of the collection to use as the remote's root
+    pub url: String,
+
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+pub struct WebdavBackend {
+    client: Client,
+
+    /// Root collection's URL and path, without a trailing slash
+    base_url: String,
+    base_path: String,
+
+    authorization: Option<String>,
+    mime_type: Option<Mime>,
+
+    /// Collections known to exist
+    collections: Mutex<HashSet<String>>,
+}
+
+/// One resource of a PROPFIND response
+#[derive(Debug, Default)]
+struct Resource {
+    href: String,
+    collection: bool,
+    size: Option<u64>,
+    modified: Option<DateTime<Local>>,
+    content_type: Option<String>,
+}
+
+impl WebdavBackend {
+    pub fn new(cfg: &WebdavConfig, opts: UploadOptions) -> Result<Self> {
+        let base_url = cfg.url.trim_end_matches('/').to_string();
+        let uri: google_drive3::hyper::Uri = base_url.parse()
+            .with_context(|| format!("Invalid WebDAV URL {}", cfg.url))?;
+
+        let authorization = cfg.username.as_ref().map(|user| {
+            let password = cfg.password.as_deref().unwrap_or_default();
+            format!("Basic {}", BASE64.encode(format!("{}:{}", user, password)))
+        });
+
+        Ok(Self {
+            client: gdrive::https_client()?,
+            base_path: decode(uri.path().trim_end_matches('/')),
+            base_url,
+            authorization,
+            mime_type: opts.mime_type,
+            collections: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        let path = path.trim_matches('/');
+        if path.is_empty() {
+            return self.base_url.clone();
+        }
+
+        let segments: Vec<String> = path.split('/')
+            .map(|s| utf8_percent_encode(s, PATH_SEGMENT).to_string())
+            .collect();
+        format!("{}/{}", self.base_url, segments.join("/"))
+    }
+
+    fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        let mut builder = Request::builder().method(method).uri(url);
+        if let Some(authorization) = &self.authorization {
+            builder = builder.header("Authorization", authorization);
+        }
+        builder
+    }
+
+    /// The resource at `path`, with those directly in it at depth 1.
+    async fn propfind(&self, path: &str, depth: u8) -> Result<Vec<Resource>> {
+        // Collections are addressed with a trailing slash, files without
+        let url = match depth {
+            0 => self.url(path),
+            _ => format!("{}/", self.url(path)),
+        };
+        let request = self.request(Method::from_bytes(b"PROPFIND")?, &url)
+            .header("Depth", depth.to_string())
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(Body::from(PROPFIND_BODY))?;
+
+        let what = format!("Failed to list {}", self.url(path));
+        let response = self.client.request(request).await.with_context(|| what.clone())?;
+        let xml = http::text(http::check(response, &what).await?).await?;
+
+        parse_multistatus(&xml).with_context(|| what.clone())
+    }
+
+    /// Create the collection at `dir` and its parents unless they exist.
+    async fn make_collections(&self, dir: &str) -> Result<()> {
+        let mut path = String::new();
+
+        for segment in dir.split('/').filter(|s| !s.is_empty()) {
+            path = join(&path, segment);
+            if self.collections.lock().unwrap().contains(&path) {
+                continue;
+            }
+
+            let request = self.request(Method::from_bytes(b"MKCOL")?, &format!("{}/", self.url(&path)))
+                .body(Body::empty())?;
+            let what = format!("Failed to create collection {}", self.url(&path));
+            let response = self.client.request(request).await.with_context(|| what.clone())?;
+
+            // Method Not Allowed means the collection is there already
+            if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+                http::check(response, &what).await?;
+            }
+            self.collections.lock().unwrap().insert(path.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Path of an href relative to the root collection.
+    fn relative_path(&self, href: &str) -> String {
+        // Servers may answer with full URLs rather than paths
+        let href = match href.find("://") {
+            Some(i) => href[i + 3..].find('/').map_or("", |j| &href[i + 3 + j..]),
+            None => href,
+        };
+
+        // The root's path must be a whole-component prefix, so that
+        // `/dav/u` doesn't swallow part of `/dav/user2`
+        let path = decode(href);
+        let path = match path.strip_prefix(&self.base_path) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+            _ => &path,
+        };
+
+        path.trim_matches('/').to_string()
+    }
+}
+
+#[async_trait]
+impl RemoteBackend for WebdavBackend {
+    async fn list(&self, path: &str, recursive: bool) -> Result<Vec<RemoteEntry>> {
+        let root = path.trim_matches('/').to_string();
+        let mut entries = Vec::new();
+        let mut pending = vec![root.clone()];
+
+        while let Some(dir) = pending.pop() {
+            for resource in self.propfind(&dir, 1).await? {
+                let full_path = self.relative_path(&resource.href);
+
+                // The listed collection is part of the response too
+                if full_path == dir {
+                    continue;
+                }
+
+                let rel_path = full_path.strip_prefix(&root)
+                    .unwrap_or(&full_path)
+                    .trim_start_matches('/');
+
+                if recursive && resource.collection {
+                    pending.push(full_path.clone());
+                }
+
+                entries.push(RemoteEntry {
+                    folder: resource.collection,
+                    mime_type: resource.content_type,
+                    size: resource.size.filter(|_| !resource.collection),
+                    modified: resource.modified,
+                    ..entry(rel_path, &full_path)
+                });
+            }
+        }
+
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(entries)
+    }
+
+    async fn is_folder(&self, path: &str) -> Result<bool> {
+        let resources = self.propfind(path, 0).await?;
+        let resource = resources.first()
+            .with_context(|| format!("No resource in the listing of {}", self.url(path)))?;
+
+        Ok(resource.collection)
+    }
+
+    async fn upload(&self, local: &Path, dir: &str, sent: &ByteCounter) -> Result<RemoteEntry> {
+        let path = join(dir, gdrive::file_name(local)?);
+        let mime_type = self.mime_type.clone().unwrap_or_else(|| mimetype::detect(local));
+
+        self.make_collections(split(&path).0).await?;
+
+        let (body, size) = http::file_body(local, sent).await?;
+        let request = self.request(Method::PUT, &self.url(&path))
+            .header("Content-Type", mime_type.as_ref())
+            .header("Content-Length", size)
+            .body(body)?;
+
+        let what = format!("Failed to upload {} to {}", local.display(), self.url(&path));
+        let response = self.client.request(request).await.with_context(|| what.clone())?;
+        http::check(response, &what).await?;
+
+        Ok(RemoteEntry {
+            mime_type: Some(mime_type.to_string()),
+            size: Some(size),
+            modified: Some(Local::now()),
+            ..entry(&path, &path)
+        })
+    }
+
+    async fn download(&self, path: &str, local: &Path) -> Result<u64> {
+        let request = self.request(Method::GET, &self.url(path)).body(Body::empty())?;
+
+        let what = format!("Failed to download {}", self.url(path));
+        let response = self.client.request(request).await.with_context(|| what.clone())?;
+        let response = http::check(response, &what).await?;
+        write_body(response.into_body(), local, None).await
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        if path.trim_matches('/').is_empty() {
+            bail!("Won't delete the root collection {}", self.base_url);
+        }
+
+        let request = self.request(Method::DELETE, &self.url(path)).body(Body::empty())?;
+
+        let what = format!("Failed to delete {}", self.url(path));
+        let response = self.client.request(request).await.with_context(|| what.clone())?;
+        http::check(response, &what).await?;
+
+        Ok(())
+    }
+}
+
+/// The resources in a PROPFIND multistatus response, whatever prefix
+/// the server uses for the DAV namespace.
+fn parse_multistatus(xml: &str) -> Result<Vec<Resource>> {
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+
+    let mut resources = Vec::new();
+    let mut current: Option<Resource> = None;
+    let mut element = Vec::new();
+
+    loop {
+        match reader.read_event()? {
+            XmlEvent::Start(e) => {
+                element = e.local_name().as_ref().to_vec();
+                match element.as_slice() {
+                    b"response" => current = Some(Resource::default()),
+                    b"collection" => set_collection(&mut current),
+                    _ => {}
+                }
+            }
+            XmlEvent::Empty(e) if e.local_name().as_ref() == b"collection" => {
+                set_collection(&mut current);
+            }
+            XmlEvent::Text(text) => {
+                let (Some(resource), text) = (current.as_mut(), text.unescape()?) else {
+                    continue;
+                };
+
+                match element.as_slice() {
+                    b"href" => resource.href = text.into_owned(),
+                    b"getcontentlength" => resource.size = text.parse().ok(),
+                    b"getlastmodified" => {
+                        resource.modified = DateTime::parse_from_rfc2822(&text)
+                            .ok()
+                            .map(|t| t.with_timezone(&Local));
+                    }
+                    b"getcontenttype" => resource.content_type = Some(text.into_owned()),
+                    _ => {}
+                }
+            }
+            XmlEvent::End(e) => {
+                if e.local_name().as_ref() == b"response" {
+                    resources.extend(current.take());
+                }
+                element.clear();
+            }
+            XmlEvent::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(resources)
+}
+
+fn set_collection(current: &mut Option<Resource>) {
+    if let Some(resource) = current {
+        resource.collection = true;
+    }
+}
+
+fn decode(path: &str) -> String {
+    percent_decode_str(path).decode_utf8_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backend(url: &str) -> WebdavBackend {
+        let cfg = WebdavConfig { url: url.to_string(), username: None, password: None };
+        WebdavBackend::new(&cfg, UploadOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn parses_multistatus_whatever_the_dav_prefix() {
+        let prefixed = r#"<?xml version="1.0"?>
+            <d:multistatus xmlns:d="DAV:">
+              <d:response>
+                <d:href>/dav/files/u/</d:href>
+                <d:propstat><d:prop>
+                  <d:resourcetype><d:collection/></d:resourcetype>
+                </d:prop></d:propstat>
+              </d:response>
+              <d:response>
+                <d:href>/dav/files/u/a%20b.txt</d:href>
+                <d:propstat><d:prop>
+                  <d:resourcetype/>
+                  <d:getcontentlength>11</d:getcontentlength>
+                  <d:getlastmodified>Tue, 02 Jan 2024 03:04:05 GMT</d:getlastmodified>
+                  <d:getcontenttype>text/plain</d:getcontenttype>
+                </d:prop></d:propstat>
+              </d:response>
+            </d:multistatus>"#;
+        let unprefixed = r#"<?xml version="1.0"?>
+            <multistatus xmlns="DAV:">
+              <response>
+                <href>/dav/files/u/photos/</href>
+                <propstat><prop><resourcetype><collection></collection></resourcetype></prop></propstat>
+              </response>
+            </multistatus>"#;
+
+        let resources = parse_multistatus(prefixed).unwrap();
+        assert_eq!(resources.len(), 2);
+        assert!(resources[0].collection);
+        assert_eq!(resources[1].href, "/dav/files/u/a%20b.txt");
+        assert!(!resources[1].collection);
+        assert_eq!(resources[1].size, Some(11));
+        assert_eq!(resources[1].content_type.as_deref(), Some("text/plain"));
+        assert_eq!(
+            resources[1].modified.map(|t| t.timestamp()),
+            Some(DateTime::parse_from_rfc3339("2024-01-02T03:04:05Z").unwrap().timestamp()),
+        );
+
+        let resources = parse_multistatus(unprefixed).unwrap();
+        assert_eq!(resources.len(), 1);
+        assert_eq!(resources[0].href, "/dav/files/u/photos/");
+        assert!(resources[0].collection);
+    }
+
+    #[test]
+    fn finds_paths_relative_to_the_root_collection() {
+        let dav = backend("https://cloud.example.com/dav/files/u/");
+
+        assert_eq!(dav.relative_path("/dav/files/u/"), "");
+        assert_eq!(dav.relative_path("/dav/files/u/docs/a%20b.txt"), "docs/a b.txt");
+        assert_eq!(dav.relative_path("https://cloud.example.com/dav/files/u/docs/"), "docs");
+        assert_eq!(dav.relative_path("https://cloud.example.com"), "");
+
+        // Another user's files only share a string prefix with the root
+        assert_eq!(dav.relative_path("/dav/files/user2/a.txt"), "dav/files/user2/a.txt");
+
+        let encoded = backend("https://cloud.example.com/dav/My%20Files");
+        assert_eq!(encoded.relative_path("/dav/My%20Files/a.txt"), "a.txt");
+    }
+
+    #[test]
+    fn encodes_urls_segment_by_segment() {
+        let dav = backend("https://cloud.example.com/dav/");
+
+        assert_eq!(dav.url(""), "https://cloud.example.com/dav");
+        assert_eq!(dav.url("/docs/"), "https://cloud.example.com/dav/docs");
+        assert_eq!(dav.url("my docs/a#1?.txt"), "https://cloud.example.com/dav/my%20docs/a%231%3F.txt");
+        assert_eq!(dav.url("ünï/ç.txt"), "https://cloud.example.com/dav/%C3%BCn%C3%AF/%C3%A7.txt");
+    }
+}
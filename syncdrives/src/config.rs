@@ -1,27 +1,512 @@
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
-use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Deserializer};
 
+use crate::backend::{LocalConfig, S3Config, SftpConfig, WebdavConfig};
+use crate::filter::FilterRules;
+use crate::gdrive::AuthMethod;
+use crate::manifest::STATE_DIR;
+use crate::retry::RetryPolicy;
+use crate::twoway::ConflictPolicy;
+
+/// Stands in for the user name when destinations are checked on
+/// loading the config, before the user is known
+const ANY_USER: &str = "user";
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
-    pub subdirs: Vec<String>,
+    #[serde(deserialize_with = "deserialize_subdirs")]
+    pub subdirs: Vec<Subdir>,
     pub hidden_files: Option<Vec<String>>,
     pub drives: Vec<Drive>,
     pub gd_folder_id: Option<String>,
+
+    /// How to sign in to Google Drive
+    #[serde(default)]
+    pub gd_auth: AuthMethod,
+
+    /// Where to cache Google Drive sign-ins instead of the data directory
+    pub gd_token_cache: Option<String>,
+
+    /// How Google Drive calls that fail for a while are retried
+    #[serde(default)]
+    pub gd_retry: RetryPolicy,
+
+    /// Remote storage to back up to. A `gd_folder_id` adds a Google
+    /// Drive remote named `gdrive`.
+    pub remotes: Option<Vec<Remote>>,
+
+    /// Directories kept in sync between drives. Without any, drives
+    /// share a `synced` directory as a union.
+    pub groups: Option<Vec<SyncGroup>>,
+
+    /// Store md5 hashes in drive manifests
+    #[serde(default)]
+    pub manifest_hashes: bool,
+
+    /// Also copy changes made on the drives back to local subdirectories
+    #[serde(default)]
+    pub two_way: bool,
+
+    /// How to settle files changed both locally and on a drive
+    #[serde(default)]
+    pub conflict_policy: ConflictPolicy,
+
+    /// What to sync in every subdirectory and synced directory
+    #[serde(flatten)]
+    pub filter: FilterRules,
+}
+
+/// Local directory synced to drives, given as just its name under the
+/// user's home or as a table with options of its own.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Subdir {
+    pub name: String,
+
+    /// Local directory to sync, `<name>` under the user's home if not
+    /// given. Relative paths are taken from the home directory.
+    pub source: Option<String>,
+
+    /// Where the directory goes under each drive's base directory, with
+    /// `{user}`, `{name}` and `{drive}` filled in. `wsl/{user}/{name}`
+    /// if not given.
+    pub dest: Option<String>,
+
+    /// What happens to drive files that are gone locally, in one-way
+    /// syncs
+    #[serde(default)]
+    pub delete: DeletePolicy,
+
+    /// Compare files of the same size by md5 rather than modification
+    /// time, in one-way syncs
+    #[serde(default)]
+    pub checksum: bool,
+
+    /// Nicknames of the drives to sync to, all drives if not given
+    pub drives: Option<Vec<String>>,
+
+    #[serde(flatten)]
+    pub filter: FilterRules,
 }
 
+/// Subdirectory as written in the config file, where a bare string is
+/// shorthand for a name with default options.
 #[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RawSubdir {
+    Name(String),
+    Table(Subdir),
+}
+
+/// What happens to files on a drive that are gone from the source.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeletePolicy {
+    /// Delete them, unless they changed on the drive since the last sync
+    #[default]
+    Safe,
+
+    /// Delete them even if they changed on the drive
+    Always,
+
+    /// Keep them
+    Never,
+}
+
+impl Subdir {
+    /// Local directory to sync, given the user's home directory.
+    pub fn get_source(&self, home_dir: &str) -> String {
+        match self.source.as_deref() {
+            Some(path) if path.starts_with('/') => trim_dir(path),
+            Some(path) => {
+                let path = path.strip_prefix('~').unwrap_or(path);
+                format!("{}/{}", home_dir, path.trim_matches('/'))
+            }
+            None => format!("{}/{}", home_dir, self.name),
+        }
+    }
+
+    /// Directory on a drive to sync to.
+    pub fn get_dest(&self, base_dir: &str, user: &str, drive_nickname: &str) -> Result<String> {
+        let dest = self.dest_path(user, drive_nickname)?;
+        Ok(format!("{}/{}", base_dir, dest))
+    }
+
+    /// Where the directory goes under a drive's base directory, which it
+    /// mustn't climb out of.
+    fn dest_path(&self, user: &str, drive_nickname: &str) -> Result<String> {
+        let template = self.dest.as_deref().unwrap_or("wsl/{user}/{name}");
+        let dest = template
+            .replace("{user}", user)
+            .replace("{name}", &self.name)
+            .replace("{drive}", drive_nickname);
+        let dest = dest.trim_matches('/');
+
+        if dest.split('/').any(|c| matches!(c, "" | "." | "..")) {
+            bail!(
+                "Destination `{}` of subdirectory `{}` has an empty, `.` or `..` component",
+                dest, self.name,
+            );
+        }
+
+        Ok(dest.to_string())
+    }
+
+    /// Whether the directory gets synced to this drive.
+    pub fn goes_to(&self, drive_nickname: &str) -> bool {
+        match &self.drives {
+            Some(nicknames) => nicknames.iter().any(|n| n == drive_nickname),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "RawDrive")]
 pub struct Drive {
-    /// Drive letter
-    #[serde(deserialize_with = "deserialize_drive_letter")]
-    pub letter: String,
+    /// How the drive gets mounted
+    pub mount: MountBackend,
 
     /// Drive's nickname
     pub nickname: Option<String>,
 
     /// Custom base directory
     pub base_dir: Option<String>,
+
+    /// Leave the drive mounted after syncing
+    pub keep_mounted: bool,
+
+    /// What to sync onto this drive
+    pub filter: FilterRules,
+}
+
+/// Directory kept in sync between several drives.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SyncGroup {
+    pub name: String,
+
+    /// Directory under each drive's base directory, the group's name
+    /// if not given
+    pub dir: Option<String>,
+
+    /// Nicknames of the member drives, all drives if not given
+    pub members: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub mode: GroupMode,
+
+    /// Nickname of the drive the others are synced from
+    pub primary: Option<String>,
+
+    /// Whether this is the shared `synced` directory used when no
+    /// groups are configured, which drives needn't have
+    #[serde(skip)]
+    pub implicit: bool,
+}
+
+/// How the drives in a sync group are synced with each other.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GroupMode {
+    /// Every member ends up with every file any member has, without
+    /// overwriting existing ones
+    #[default]
+    Union,
+
+    /// Members are made exact copies of the primary
+    Mirror,
+
+    /// The primary's new and changed files are copied to the other
+    /// members, which may keep files of their own
+    Hub,
+}
+
+impl fmt::Display for GroupMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = match self {
+            GroupMode::Union => "union",
+            GroupMode::Mirror => "mirror",
+            GroupMode::Hub => "hub",
+        };
+        f.pad(mode)
+    }
+}
+
+impl SyncGroup {
+    /// The shared `synced` directory used when no groups are configured.
+    pub fn default_synced() -> Self {
+        Self {
+            name: "synced".to_string(),
+            dir: None,
+            members: None,
+            mode: GroupMode::Union,
+            primary: None,
+            implicit: true,
+        }
+    }
+
+    /// Whether a drive is one of the group's members.
+    pub fn has_member(&self, drive_nickname: &str) -> bool {
+        match &self.members {
+            Some(nicknames) => nicknames.iter().any(|n| n == drive_nickname),
+            None => true,
+        }
+    }
+
+    /// Directory under each drive's base directory, which it mustn't
+    /// climb out of.
+    pub fn get_dir(&self) -> Result<String> {
+        let dir = self.dir.as_deref().unwrap_or(&self.name).trim_matches('/');
+
+        if dir.split('/').any(|c| matches!(c, "" | "." | "..")) {
+            bail!(
+                "Directory `{}` of group `{}` has an empty, `.` or `..` component",
+                dir, self.name,
+            );
+        }
+
+        Ok(dir.to_string())
+    }
+}
+
+/// Named remote storage.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Remote {
+    pub name: String,
+
+    #[serde(flatten)]
+    pub kind: RemoteKind,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum RemoteKind {
+    /// A Google Drive folder
+    Gdrive {
+        folder_id: String,
+
+        /// Google Drive API client secrets file
+        secrets_file: Option<String>,
+    },
+    S3(S3Config),
+    Webdav(WebdavConfig),
+    Sftp(SftpConfig),
+
+    /// A local directory standing in for cloud storage, for testing
+    Local(LocalConfig),
+}
+
+impl Config {
+    pub fn get_groups(&self) -> Vec<SyncGroup> {
+        match &self.groups {
+            Some(groups) => groups.clone(),
+            None => vec![SyncGroup::default_synced()],
+        }
+    }
+
+    /// Configured remotes, starting with the `gdrive` one for
+    /// `gd_folder_id` unless a remote has that name already.
+    pub fn get_remotes(&self) -> Vec<Remote> {
+        let mut remotes = self.remotes.clone().unwrap_or_default();
+
+        if let Some(folder_id) = &self.gd_folder_id {
+            if !remotes.iter().any(|r| r.name == "gdrive") {
+                remotes.insert(0, Remote {
+                    name: "gdrive".to_string(),
+                    kind: RemoteKind::Gdrive {
+                        folder_id: folder_id.clone(),
+                        secrets_file: None,
+                    },
+                });
+            }
+        }
+
+        remotes
+    }
+
+    /// The remote of this name, or the first one.
+    pub fn get_remote(&self, name: Option<&str>) -> Result<Remote> {
+        let remotes = self.get_remotes();
+
+        match name {
+            Some(name) => match remotes.into_iter().find(|r| r.name == name) {
+                Some(remote) => Ok(remote),
+                None => bail!("No remote named '{}' in config", name),
+            },
+            None => match remotes.into_iter().next() {
+                Some(remote) => Ok(remote),
+                None => bail!("No remotes or gd_folder_id specified in config"),
+            },
+        }
+    }
+
+    /// Directories syncing writes to under a drive's base directory,
+    /// each with a description of what goes there.
+    pub fn drive_dirs(&self, drive_nickname: &str, user: &str) -> Result<Vec<(String, String)>> {
+        let mut dirs = Vec::new();
+
+        for subdir in self.subdirs.iter().filter(|s| s.goes_to(drive_nickname)) {
+            let dest = subdir.dest_path(user, drive_nickname)?;
+            dirs.push((dest, format!("subdirectory `{}`", subdir.name)));
+        }
+        if self.hidden_files.as_ref().is_some_and(|f| !f.is_empty()) {
+            dirs.push((format!("wsl/{}", user), "hidden files".to_string()));
+        }
+        for group in self.get_groups().iter().filter(|g| g.has_member(drive_nickname)) {
+            dirs.push((group.get_dir()?, format!("group `{}`", group.name)));
+        }
+
+        Ok(dirs)
+    }
+
+    /// Check that every subdirectory and sync group gets a directory of
+    /// its own on a drive, apart from the others, `user`'s hidden files
+    /// and the drive's state.
+    pub fn check_dests(&self, drive_nickname: &str, user: &str) -> Result<()> {
+        let mut taken = vec![(STATE_DIR.to_string(), format!("the `{}` directory", STATE_DIR))];
+        for group in self.get_groups().iter().filter(|g| g.has_member(drive_nickname)) {
+            let dir = group.get_dir()?;
+
+            if let Some((_, other)) = taken.iter().find(|(taken, _)| overlaps(taken, &dir)) {
+                bail!(
+                    "Directory `{}` of group `{}` on {} overlaps {}",
+                    dir, group.name, drive_nickname, other,
+                );
+            }
+            taken.push((dir, format!("the directory of group `{}`", group.name)));
+        }
+
+        // Subdirectories may go beneath the hidden files' directory, just
+        // not take it over
+        let hidden_dir = format!("wsl/{}", user);
+        let has_hidden = self.hidden_files.as_ref().is_some_and(|f| !f.is_empty());
+
+        for subdir in self.subdirs.iter().filter(|s| s.goes_to(drive_nickname)) {
+            let dest = subdir.dest_path(user, drive_nickname)?;
+
+            if has_hidden && Path::new(&hidden_dir).starts_with(&dest) {
+                bail!(
+                    "Destination `{}` of subdirectory `{}` on {} overlaps the hidden files in `{}`",
+                    dest, subdir.name, drive_nickname, hidden_dir,
+                );
+            }
+            if let Some((_, other)) = taken.iter().find(|(dir, _)| overlaps(dir, &dest)) {
+                bail!(
+                    "Destination `{}` of subdirectory `{}` on {} overlaps {}",
+                    dest, subdir.name, drive_nickname, other,
+                );
+            }
+            taken.push((dest, format!("subdirectory `{}`", subdir.name)));
+        }
+
+        Ok(())
+    }
+}
+
+/// Drive as written in the config file, where a bare `letter` is
+/// shorthand for a drvfs mount.
+#[derive(Debug, Deserialize)]
+struct RawDrive {
+    #[serde(default, deserialize_with = "deserialize_opt_drive_letter")]
+    letter: Option<String>,
+    mount: Option<MountBackend>,
+    nickname: Option<String>,
+    base_dir: Option<String>,
+    #[serde(default)]
+    keep_mounted: bool,
+    #[serde(flatten)]
+    filter: FilterRules,
+}
+
+impl TryFrom<RawDrive> for Drive {
+    type Error = String;
+
+    fn try_from(raw: RawDrive) -> Result<Self, Self::Error> {
+        let mount = match (raw.mount, raw.letter) {
+            (Some(mount), None) => mount,
+            (None, Some(letter)) => MountBackend::Drvfs { letter },
+            (Some(_), Some(_)) => {
+                return Err("drive can't have both `letter` and `mount`".to_string());
+            }
+            (None, None) => {
+                return Err("drive needs either `letter` or `mount`".to_string());
+            }
+        };
+
+        Ok(Self {
+            mount,
+            nickname: raw.nickname,
+            base_dir: raw.base_dir,
+            keep_mounted: raw.keep_mounted,
+            filter: raw.filter,
+        })
+    }
+}
+
+/// Ways of getting a drive's contents mounted.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum MountBackend {
+    /// Windows drive mounted under WSL with `mount -t drvfs`
+    Drvfs {
+        /// Drive letter
+        #[serde(deserialize_with = "deserialize_drive_letter")]
+        letter: String,
+    },
+
+    /// Block device, given as a path or as `UUID=`, `LABEL=` or
+    /// `PARTUUID=` spec
+    Block {
+        device: String,
+
+        /// Filesystem type passed to `mount -t`
+        fstype: Option<String>,
+
+        /// Mount options passed to `mount -o`
+        options: Option<String>,
+
+        /// Custom mountpoint
+        mountpoint: Option<String>,
+    },
+
+    /// Filesystem that is already mounted at `path`
+    Mounted {
+        path: String,
+    },
+}
+
+impl MountBackend {
+    /// Short description of what gets mounted, for messages.
+    pub fn source(&self) -> String {
+        match self {
+            MountBackend::Drvfs { letter } => format_drive_letter(letter),
+            MountBackend::Block { device, .. } => device.to_string(),
+            MountBackend::Mounted { path } => path.to_string(),
+        }
+    }
+
+    /// Path of the device node for a block device spec.
+    pub fn device_path(&self) -> Option<PathBuf> {
+        let MountBackend::Block { device, .. } = self else {
+            return None;
+        };
+
+        let by = [
+            ("UUID=", "by-uuid"),
+            ("LABEL=", "by-label"),
+            ("PARTUUID=", "by-partuuid"),
+        ];
+
+        for (prefix, dir) in by.iter() {
+            if let Some(id) = device.strip_prefix(prefix) {
+                return Some(["/dev/disk", dir, id].iter().collect());
+            }
+        }
+
+        Some(PathBuf::from(device))
+    }
 }
 
 fn deserialize_drive_letter<'a, D>(deserializer: D) -> Result<String, D::Error>
@@ -32,6 +517,25 @@ where
     Ok(deformat_drive_letter(&letter))
 }
 
+fn deserialize_opt_drive_letter<'a, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'a>
+{
+    let letter = Option::<String>::deserialize(deserializer)?;
+    Ok(letter.map(|l| deformat_drive_letter(&l)))
+}
+
+fn deserialize_subdirs<'a, D>(deserializer: D) -> Result<Vec<Subdir>, D::Error>
+where
+    D: Deserializer<'a>
+{
+    let subdirs = Vec::<RawSubdir>::deserialize(deserializer)?;
+    Ok(subdirs.into_iter().map(|raw| match raw {
+        RawSubdir::Name(name) => Subdir { name, ..Subdir::default() },
+        RawSubdir::Table(subdir) => subdir,
+    }).collect())
+}
+
 impl Drive {
     pub fn new(
         letter: String,
@@ -39,16 +543,14 @@ impl Drive {
         base_dir: Option<String>,
     ) -> Self {
         Self {
-            letter: deformat_drive_letter(&letter),
+            mount: MountBackend::Drvfs { letter: deformat_drive_letter(&letter) },
             nickname,
             base_dir,
+            keep_mounted: false,
+            filter: FilterRules::default(),
         }
     }
 
-    pub fn get_letter(&self) -> String {
-        format_drive_letter(&self.letter)
-    }
-
     pub fn get_nickname(&self) -> String {
         if let Some(name) = &self.nickname {
             name.to_string()
@@ -59,14 +561,27 @@ impl Drive {
 
     pub fn get_base_dir(&self) -> String {
         if let Some(dir) = &self.base_dir {
-            format!("/mnt/{}/{}", self.letter, dir.trim_end_matches('/'))
+            let mountpoint = self.get_mountpoint();
+            format!(
+                "{}/{}",
+                mountpoint.trim_end_matches('/'),
+                dir.trim_end_matches('/'),
+            )
         } else {
             self.get_mountpoint()
         }
     }
 
     pub fn get_mountpoint(&self) -> String {
-        format!("/mnt/{}", self.letter)
+        match &self.mount {
+            MountBackend::Drvfs { letter } => format!("/mnt/{}", letter),
+            MountBackend::Block { mountpoint: Some(path), .. } => trim_dir(path),
+            MountBackend::Block { device, .. } => {
+                let name = device.rsplit(['/', '=']).next().unwrap_or(device);
+                format!("/mnt/{}", name)
+            }
+            MountBackend::Mounted { path } => trim_dir(path),
+        }
     }
 }
 
@@ -85,14 +600,168 @@ pub fn get_config(config_file: Option<String>) -> Result<Config> {
 
     let config: Config = toml::from_str(&cfg_str)
         .context("Failed to parse config")?;
+    for drive in config.drives.iter() {
+        config.check_dests(&drive.get_nickname(), ANY_USER)?;
+    }
 
     Ok(config)
 }
 
-fn format_drive_letter(letter: &String) -> String {
+/// Whether one relative path is the other or lies beneath it.
+fn overlaps(a: &str, b: &str) -> bool {
+    Path::new(a).starts_with(b) || Path::new(b).starts_with(a)
+}
+
+/// Strip trailing slashes from a directory path, leaving `/` alone.
+fn trim_dir(path: &str) -> String {
+    match path.trim_end_matches('/') {
+        "" => "/".to_string(),
+        dir => dir.to_string(),
+    }
+}
+
+fn format_drive_letter(letter: &str) -> String {
     format!("{}:", letter.to_uppercase().trim_end_matches(':'))
 }
 
-fn deformat_drive_letter(letter: &String) -> String {
+fn deformat_drive_letter(letter: &str) -> String {
     letter.to_lowercase().trim_end_matches(':').to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(toml: &str) -> Result<()> {
+        let drives = r#"
+            [[drives]]
+            letter = "e"
+            nickname = "USB"
+        "#;
+        toml::from_str::<Config>(&format!("{}\n{}", toml, drives)).unwrap().check_dests("USB", ANY_USER)
+    }
+
+    fn drive(mount: &str, base_dir: Option<&str>) -> Drive {
+        let base_dir = base_dir.map(|d| format!("base_dir = \"{}\"", d)).unwrap_or_default();
+        toml::from_str(&format!("mount = {}\n{}", mount, base_dir)).unwrap()
+    }
+
+    #[test]
+    fn works_out_mountpoints_and_base_directories() {
+        let letter: Drive = toml::from_str(r#"letter = "E:""#).unwrap();
+        assert_eq!(letter.get_mountpoint(), "/mnt/e");
+        assert_eq!(letter.mount.source(), "E:");
+
+        let block = drive(r#"{ type = "block", device = "LABEL=backup" }"#, Some("sync/"));
+        assert_eq!(block.get_mountpoint(), "/mnt/backup");
+        assert_eq!(block.get_base_dir(), "/mnt/backup/sync");
+
+        let block = drive(r#"{ type = "block", device = "/dev/sdb1", mountpoint = "/media/usb/" }"#, None);
+        assert_eq!(block.get_mountpoint(), "/media/usb");
+
+        let mounted = drive(r#"{ type = "mounted", path = "/" }"#, Some("backup"));
+        assert_eq!(mounted.get_base_dir(), "/backup");
+    }
+
+    #[test]
+    fn finds_device_nodes_for_block_device_specs() {
+        let device = |spec: &str| {
+            drive(&format!(r#"{{ type = "block", device = "{}" }}"#, spec), None).mount.device_path()
+        };
+
+        assert_eq!(device("UUID=1234-abcd"), Some(PathBuf::from("/dev/disk/by-uuid/1234-abcd")));
+        assert_eq!(device("PARTUUID=42"), Some(PathBuf::from("/dev/disk/by-partuuid/42")));
+        assert_eq!(device("/dev/sdb1"), Some(PathBuf::from("/dev/sdb1")));
+        assert_eq!(drive(r#"{ type = "mounted", path = "/" }"#, None).mount.device_path(), None);
+    }
+
+    #[test]
+    fn substitutes_destination_templates() {
+        let subdir = Subdir {
+            name: "docs".to_string(),
+            dest: Some("/backup/{drive}/{user}/{name}/".to_string()),
+            ..Subdir::default()
+        };
+
+        assert_eq!(subdir.get_dest("/mnt/e", "ann", "USB").unwrap(), "/mnt/e/backup/USB/ann/docs");
+        assert!(subdir.get_dest("/mnt/e", "..", "USB").is_err());
+    }
+
+    #[test]
+    fn destinations_stay_beneath_the_base_directory() {
+        assert!(check(r#"subdirs = ["docs", { name = "music", dest = "media/{name}" }]"#).is_ok());
+
+        for dest in ["..", "../{name}", "a/./{name}", "a//{name}", "/"] {
+            let err = check(&format!(r#"subdirs = [{{ name = "docs", dest = "{}" }}]"#, dest));
+            assert!(err.unwrap_err().to_string().contains("component"), "{}", dest);
+        }
+    }
+
+    #[test]
+    fn destinations_get_directories_of_their_own() {
+        let overlaps = [
+            r#"subdirs = [{ name = "a", dest = "x" }, { name = "b", dest = "x/b" }]"#,
+            r#"subdirs = [{ name = "a", dest = "x/a" }, { name = "b", dest = "x" }]"#,
+            r#"subdirs = [{ name = "a", dest = "synced/a" }]"#,
+            r#"subdirs = [{ name = "a", dest = ".syncdrives" }]"#,
+            r#"subdirs = [{ name = "a", dest = "photos" }]
+               groups = [{ name = "photos" }]"#,
+        ];
+        for toml in overlaps {
+            assert!(check(toml).unwrap_err().to_string().contains("overlaps"), "{}", toml);
+        }
+
+        // Subdirectories beside the hidden files are fine, just not over them
+        let hidden = r#"hidden_files = [".bashrc"]"#;
+        assert!(check(&format!("{}\nsubdirs = [\"docs\"]", hidden)).is_ok());
+        for dest in ["wsl/{user}", "wsl"] {
+            let toml = format!(r#"{}
+                subdirs = [{{ name = "docs", dest = "{}" }}]"#, hidden, dest);
+            assert!(check(&toml).unwrap_err().to_string().contains("hidden files"), "{}", dest);
+        }
+        assert!(check(r#"subdirs = [{ name = "docs", dest = "wsl/{user}" }]"#).is_ok());
+
+        // The hidden files go to the real user's directory
+        let toml = format!(r#"{}
+            subdirs = [{{ name = "docs", dest = "wsl/ann" }}]"#, hidden);
+        let cfg = toml::from_str::<Config>(&format!("{}\ndrives = []", toml)).unwrap();
+        assert!(cfg.check_dests("USB", ANY_USER).is_ok());
+        assert!(cfg.check_dests("USB", "ann").unwrap_err().to_string().contains("hidden files"));
+
+        // Siblings sharing a prefix, and subdirectories going to other drives
+        assert!(check(r#"subdirs = [{ name = "a", dest = "x/a" }, { name = "b", dest = "x/ab" }]"#).is_ok());
+        assert!(check(r#"subdirs = [{ name = "a", dest = "x", drives = ["Other"] }, { name = "b", dest = "x" }]"#).is_ok());
+    }
+
+    #[test]
+    fn group_directories_stay_beneath_the_base_directory() {
+        assert!(check(r#"subdirs = []
+            groups = [{ name = "photos" }, { name = "music", dir = "/media/music/" }]"#).is_ok());
+
+        for dir in ["", "/", ".", "..", "../x", "a/./b", "a//b"] {
+            let toml = format!(r#"subdirs = []
+                groups = [{{ name = "g", dir = "{}", mode = "mirror" }}]"#, dir);
+            assert!(check(&toml).unwrap_err().to_string().contains("component"), "{}", dir);
+        }
+    }
+
+    #[test]
+    fn group_directories_get_directories_of_their_own() {
+        let overlaps = [
+            r#"groups = [{ name = "g", dir = ".syncdrives" }]"#,
+            r#"groups = [{ name = "g", dir = ".syncdrives/x" }]"#,
+            r#"groups = [{ name = "a", dir = "x" }, { name = "b", dir = "x" }]"#,
+            r#"groups = [{ name = "a", dir = "x" }, { name = "b", dir = "x/b" }]"#,
+            r#"groups = [{ name = "x" }, { name = "b", dir = "x/b" }]"#,
+        ];
+        for groups in overlaps {
+            let toml = format!("subdirs = []
+{}", groups);
+            assert!(check(&toml).unwrap_err().to_string().contains("overlaps"), "{}", groups);
+        }
+
+        // Groups only clash on drives that are members of both
+        assert!(check(r#"subdirs = []
+            groups = [{ name = "a", dir = "x", members = ["Other"] }, { name = "b", dir = "x" }]"#).is_ok());
+    }
+}
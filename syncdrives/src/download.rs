@@ -0,0 +1,339 @@
+//! Downloading files and folders back from Google Drive
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use google_drive3::api::File;
+use google_drive3::hyper::body::{Body, HttpBody};
+use md5::{Digest, Md5};
+
+use crate::gdrive::{self, Hub};
+use crate::manifest::file_md5;
+use crate::output::{format_bytes, Event, Phase, Reporter, Status};
+use crate::sync::temp_path;
+
+const FILE_FIELDS: &str = "id, name, mimeType, md5Checksum, size";
+
+/// What to download.
+pub enum Target {
+    Id(String),
+
+    /// A file or folder of this name directly in the given folder
+    Name(String, String),
+}
+
+/// Download a file, or a folder and everything in it, to `dest`. If
+/// `dest` is an existing directory the download goes inside it.
+///
+/// Files whose local copy already has the same md5 are left alone, and
+/// every downloaded file's md5 is checked against Drive's.
+pub async fn download(hub: &Hub, target: Target, dest: &Path, out: &Reporter) -> Result<()> {
+    let root = match target {
+        Target::Id(id) => get_file(hub, &id).await?,
+        Target::Name(name, folder_id) => find_by_name(hub, &name, &folder_id).await?,
+    };
+
+    let root_name = root.name.clone().context("Drive file has no name")?;
+    let root_path = if dest.is_dir() {
+        dest.join(safe_name(&root_name)?)
+    } else {
+        dest.to_path_buf()
+    };
+
+    let (mut files, mut failed) = (0, 0);
+    let mut pending = vec![(root, root_path)];
+
+    while let Some((file, path)) = pending.pop() {
+        if gdrive::is_folder(&file) {
+            fs::create_dir_all(&path)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+
+            let id = file.id.as_deref().context("Drive folder has no id")?;
+            for (child, child_path) in child_paths(&path, gdrive::list_folder(hub, id).await?) {
+                match child_path {
+                    Ok(child_path) => pending.push((child, child_path)),
+                    Err(e) => {
+                        out.error(None, Some(Phase::Download), format!("{:#}", e));
+                        out.emit(download_event(&child, &path, 0, Status::Error));
+                        files += 1;
+                        failed += 1;
+                    }
+                }
+            }
+            continue;
+        }
+
+        files += 1;
+        if let Err(e) = download_file(hub, &file, &path, out).await {
+            out.error(None, Some(Phase::Download), format!("{:#}", e));
+            out.emit(download_event(&file, &path, 0, Status::Error));
+            failed += 1;
+        }
+    }
+
+    out.say(format!("Downloaded {} of {} files to `{}`", files - failed, files, dest.display()));
+
+    if failed > 0 {
+        bail!("{} downloads failed", failed);
+    }
+
+    Ok(())
+}
+
+async fn download_file(hub: &Hub, file: &File, path: &Path, out: &Reporter) -> Result<()> {
+    let mime_type = file.mime_type.as_deref().unwrap_or_default();
+
+    // Google Docs files have no content of their own and get exported
+    if mime_type.starts_with("application/vnd.google-apps.") {
+        return export_file(hub, file, path, out).await;
+    }
+
+    if let Some(md5) = &file.md5_checksum {
+        if path.is_file() && file_md5(path)? == *md5 {
+            out.say(format!("Unchanged '{}'", path.display()));
+            out.emit(download_event(file, path, 0, Status::Skipped));
+            return Ok(());
+        }
+    }
+
+    let bytes = fetch_file(hub, file, path).await?;
+
+    out.say(format!("Downloaded '{}' ({})", path.display(), format_bytes(bytes)));
+    out.emit(download_event(file, path, bytes, Status::Ok));
+
+    Ok(())
+}
+
+/// Download a file's content to `path`, check it against Drive's md5
+/// and return its size.
+pub async fn fetch_file(hub: &Hub, file: &File, path: &Path) -> Result<u64> {
+    let id = file.id.as_deref().context("Drive file has no id")?;
+
+    let (response, _) = hub.files()
+        .get(id)
+        .param("alt", "media")
+        .delegate(&mut hub.retrier(format!("download of {}", id)))
+        .doit()
+        .await
+        .with_context(|| format!("Failed to download {}", id))?;
+
+    write_body(response.into_body(), path, file.md5_checksum.as_deref()).await
+}
+
+/// Export a Google Docs file in the matching office format.
+async fn export_file(hub: &Hub, file: &File, path: &Path, out: &Reporter) -> Result<()> {
+    let id = file.id.as_deref().context("Drive file has no id")?;
+    let mime_type = file.mime_type.as_deref().unwrap_or_default();
+
+    let Some((export_type, ext)) = export_format(mime_type) else {
+        out.say(format!("Skipping '{}' ({} can't be downloaded)", path.display(), mime_type));
+        out.emit(download_event(file, path, 0, Status::Skipped));
+        return Ok(());
+    };
+
+    let path = path.with_extension(ext);
+    let response = hub.files()
+        .export(id, export_type)
+        .delegate(&mut hub.retrier(format!("export of {}", id)))
+        .doit()
+        .await
+        .with_context(|| format!("Failed to export {}", id))?;
+
+    let bytes = write_body(response.into_body(), &path, None).await?;
+
+    out.say(format!("Exported '{}' ({})", path.display(), format_bytes(bytes)));
+    out.emit(download_event(file, &path, bytes, Status::Ok));
+
+    Ok(())
+}
+
+/// Stream a response body into a file by way of a temporary file,
+/// returning the number of bytes written. If `expected_md5` is given
+/// the file is only replaced when the body matches it.
+pub async fn write_body(mut body: Body, path: &Path, expected_md5: Option<&str>) -> Result<u64> {
+    let tmp_path = temp_path(path);
+    let mut tmp_file = fs::File::create(&tmp_path)
+        .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+
+    let mut hasher = Md5::new();
+    let mut bytes = 0;
+
+    let written = async {
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.with_context(|| format!("Failed to download {}", path.display()))?;
+
+            tmp_file.write_all(&chunk)
+                .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+            hasher.update(&chunk);
+            bytes += chunk.len() as u64;
+        }
+
+        if let Some(expected) = expected_md5 {
+            let md5 = format!("{:x}", hasher.finalize_reset());
+            if md5 != expected {
+                bail!("Checksum mismatch for {}: expected {}, got {}", path.display(), expected, md5);
+            }
+        }
+
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }.await;
+
+    if let Err(e) = written {
+        fs::remove_file(&tmp_path).ok();
+        return Err(e);
+    }
+
+    Ok(bytes)
+}
+
+async fn get_file(hub: &Hub, id: &str) -> Result<File> {
+    let (_, file) = hub.files()
+        .get(id)
+        .param("fields", FILE_FIELDS)
+        .delegate(&mut hub.retrier(format!("lookup of {}", id)))
+        .doit()
+        .await
+        .with_context(|| format!("Failed to look up {}", id))?;
+
+    Ok(file)
+}
+
+async fn find_by_name(hub: &Hub, name: &str, folder_id: &str) -> Result<File> {
+    let mut matches: Vec<File> = gdrive::list_folder(hub, folder_id)
+        .await?
+        .into_iter()
+        .filter(|f| f.name.as_deref() == Some(name))
+        .collect();
+
+    match matches.len() {
+        0 => bail!("No file named '{}' in folder {}", name, folder_id),
+        1 => Ok(matches.remove(0)),
+        n => bail!("{} files are named '{}', pick one with --id", n, name),
+    }
+}
+
+/// Office format to export a Google Docs file as, with its extension.
+fn export_format(mime_type: &str) -> Option<(&'static str, &'static str)> {
+    match mime_type {
+        "application/vnd.google-apps.document" => Some((
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+            "docx",
+        )),
+        "application/vnd.google-apps.spreadsheet" => Some((
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+            "xlsx",
+        )),
+        "application/vnd.google-apps.presentation" => Some((
+            "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+            "pptx",
+        )),
+        _ => None,
+    }
+}
+
+/// Local paths for the files in a folder. Drive allows several files
+/// with the same name, which can't all be saved.
+fn child_paths(dir: &Path, children: Vec<File>) -> Vec<(File, Result<PathBuf>)> {
+    let mut taken = HashSet::new();
+
+    children.into_iter()
+        .map(|child| {
+            let path = child.name.as_deref()
+                .context("Drive file has no name")
+                .and_then(safe_name)
+                .and_then(|name| {
+                    if !taken.insert(name.clone()) {
+                        bail!("Several files would be saved as {}", dir.join(&name).display());
+                    }
+                    Ok(dir.join(name))
+                });
+            (child, path)
+        })
+        .collect()
+}
+
+/// Drive names may contain anything, but must make a single local
+/// path component.
+fn safe_name(name: &str) -> Result<String> {
+    if name.is_empty() || name == "." || name == ".." {
+        bail!("Can't save Drive file named '{}'", name);
+    }
+
+    Ok(name.replace('/', "_"))
+}
+
+fn download_event(file: &File, path: &Path, bytes: u64, status: Status) -> Event {
+    Event::Download {
+        phase: Phase::Download,
+        path: path.to_path_buf(),
+        id: file.id.clone().unwrap_or_default(),
+        bytes,
+        status,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn named(name: &str) -> File {
+        File { name: Some(name.to_string()), ..Default::default() }
+    }
+
+    #[test]
+    fn files_with_clashing_names_are_reported() {
+        let dir = Path::new("restore");
+        let children = vec![named("a.txt"), named("b/c"), named("a.txt"), named("b_c"), named("..")];
+
+        let paths: Vec<Result<PathBuf>> = child_paths(dir, children).into_iter().map(|(_, p)| p).collect();
+
+        assert_eq!(paths[0].as_ref().unwrap(), &dir.join("a.txt"));
+        assert_eq!(paths[1].as_ref().unwrap(), &dir.join("b_c"));
+        assert!(paths[2].as_ref().unwrap_err().to_string().contains("Several files"));
+        assert!(paths[3].is_err());
+        assert!(paths[4].is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_downloads_leave_no_temporary_file() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("a.txt");
+        let (mut sender, body) = Body::channel();
+
+        sender.send_data("partial".into()).await.unwrap();
+        sender.abort();
+
+        assert!(write_body(body, &path, None).await.is_err());
+        assert!(fs::read_dir(tmp.path()).unwrap().next().is_none());
+    }
+
+    #[tokio::test]
+    async fn mismatching_downloads_leave_the_existing_file_alone() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("a.txt");
+        fs::write(&path, "old").unwrap();
+
+        let err = write_body(Body::from("corrupt"), &path, Some("0123456789abcdef0123456789abcdef"))
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("Checksum mismatch"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn matching_downloads_replace_the_existing_file() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("a.txt");
+        fs::write(&path, "old").unwrap();
+
+        let md5 = format!("{:x}", Md5::digest(b"new"));
+        assert_eq!(write_body(Body::from("new"), &path, Some(&md5)).await.unwrap(), 3);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+    }
+}
@@ -0,0 +1,203 @@
+//! Include and exclude rules for what gets synced
+//!
+//! Rules are gitignore patterns, matched against paths relative to the
+//! directory being synced. They come from the config, at the global,
+//! subdirectory and drive level, and from `.syncignore` files found in
+//! the source tree, which take precedence the deeper they are.
+
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use serde::Deserialize;
+
+/// Name of the files holding exclude patterns for their directory
+pub const IGNORE_FILE: &str = ".syncignore";
+
+/// Patterns as written in the config.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct FilterRules {
+    /// Only sync files matching these, if any are given
+    #[serde(default)]
+    pub include: Vec<String>,
+
+    /// Skip paths matching these. A `!` pattern brings back paths that
+    /// an earlier one skipped.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+/// Rules deciding which paths a sync covers.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    include: Option<Gitignore>,
+    exclude: Option<Gitignore>,
+
+    /// Rules from `.syncignore` files, by the directory they're in
+    ignore_files: Vec<(PathBuf, Gitignore)>,
+}
+
+impl Filter {
+    /// Combine rules from the config, later ones taking precedence.
+    pub fn new(rules: &[&FilterRules]) -> Result<Self> {
+        let include = rules.iter().flat_map(|r| r.include.iter());
+        let exclude = rules.iter().flat_map(|r| r.exclude.iter());
+
+        Ok(Self {
+            include: build_patterns(include)?,
+            exclude: build_patterns(exclude)?,
+            ignore_files: Vec::new(),
+        })
+    }
+
+    /// Pick up the `.syncignore` file in a directory of the tree at
+    /// `root`, if it has one.
+    pub fn read_ignore_file(&mut self, root: &Path, rel_dir: &Path) -> Result<()> {
+        let path = root.join(rel_dir).join(IGNORE_FILE);
+        if !path.is_file() {
+            return Ok(());
+        }
+
+        let mut builder = GitignoreBuilder::new(rel_dir);
+        if let Some(e) = builder.add(&path) {
+            return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+        }
+
+        let patterns = builder.build()
+            .with_context(|| format!("Invalid pattern in {}", path.display()))?;
+        self.ignore_files.push((rel_dir.to_path_buf(), patterns));
+
+        Ok(())
+    }
+
+    /// Whether a path is left out of syncing.
+    ///
+    /// Directories are never left out by `include` patterns, since
+    /// included files may be anywhere beneath them.
+    pub fn is_excluded(&self, rel_path: &Path, is_dir: bool) -> bool {
+        if !is_dir && !self.includes_all(rel_path, false) {
+            return true;
+        }
+
+        // The deepest `.syncignore` with an opinion wins
+        for (dir, patterns) in self.ignore_files.iter().rev() {
+            if !rel_path.starts_with(dir) || rel_path == dir {
+                continue;
+            }
+
+            match patterns.matched_path_or_any_parents(rel_path, is_dir) {
+                Match::None => (),
+                m => return m.is_ignore(),
+            }
+        }
+
+        self.exclude.as_ref().is_some_and(|patterns| {
+            patterns.matched_path_or_any_parents(rel_path, is_dir).is_ignore()
+        })
+    }
+
+    /// Whether everything at or beneath a path is included, rather than
+    /// only the files picked out by `include` patterns.
+    pub fn includes_all(&self, rel_path: &Path, is_dir: bool) -> bool {
+        match &self.include {
+            Some(patterns) => patterns.matched_path_or_any_parents(rel_path, is_dir).is_ignore(),
+            None => true,
+        }
+    }
+}
+
+/// Matcher for gitignore patterns, or `None` without any.
+fn build_patterns<'a>(lines: impl Iterator<Item = &'a String>) -> Result<Option<Gitignore>> {
+    let mut builder = GitignoreBuilder::new("");
+    let mut any = false;
+
+    for line in lines {
+        builder.add_line(None, line)
+            .with_context(|| format!("Invalid pattern `{}`", line))?;
+        any = true;
+    }
+
+    if !any {
+        return Ok(None);
+    }
+
+    Ok(Some(builder.build().context("Invalid include or exclude patterns")?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn rules(include: &[&str], exclude: &[&str]) -> FilterRules {
+        FilterRules {
+            include: include.iter().map(|p| p.to_string()).collect(),
+            exclude: exclude.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    fn excluded(filter: &Filter, rel_path: &str) -> bool {
+        filter.is_excluded(Path::new(rel_path), false)
+    }
+
+    #[test]
+    fn negated_patterns_bring_back_what_was_excluded() {
+        let filter = Filter::new(&[&rules(&[], &["*.log", "!keep.log"])]).unwrap();
+
+        assert!(excluded(&filter, "debug.log"));
+        assert!(!excluded(&filter, "keep.log"));
+        assert!(!excluded(&filter, "logs/keep.log"));
+    }
+
+    #[test]
+    fn drive_rules_beat_subdir_rules_which_beat_global_ones() {
+        let global = rules(&[], &["*.log", "*.tmp"]);
+        let subdir = rules(&[], &["!*.log", "!*.tmp"]);
+        let drive = rules(&[], &["*.tmp"]);
+        let filter = Filter::new(&[&global, &subdir, &drive]).unwrap();
+
+        assert!(!excluded(&filter, "app.log"));
+        assert!(excluded(&filter, "scratch.tmp"));
+    }
+
+    #[test]
+    fn deeper_ignore_files_override_shallower_ones_and_the_config() {
+        let tmp = TempDir::new().unwrap();
+        fs::create_dir_all(tmp.path().join("build/keep")).unwrap();
+        fs::write(tmp.path().join(IGNORE_FILE), "*.o\n").unwrap();
+        fs::write(tmp.path().join("build").join(IGNORE_FILE), "!*.bak\n").unwrap();
+        fs::write(tmp.path().join("build/keep").join(IGNORE_FILE), "!*.o\n").unwrap();
+
+        // Directories are read on the way down, as the sync walks them
+        let mut filter = Filter::new(&[&rules(&[], &["*.bak"])]).unwrap();
+        for dir in ["", "build", "build/keep"] {
+            filter.read_ignore_file(tmp.path(), Path::new(dir)).unwrap();
+        }
+
+        assert!(excluded(&filter, "main.o"));
+        assert!(excluded(&filter, "build/main.o"));
+        assert!(!excluded(&filter, "build/keep/main.o"));
+        assert!(excluded(&filter, "notes.bak"));
+        assert!(!excluded(&filter, "build/notes.bak"));
+    }
+
+    #[test]
+    fn includes_never_leave_out_directories() {
+        let filter = Filter::new(&[&rules(&["*.md"], &[])]).unwrap();
+
+        assert!(!filter.is_excluded(Path::new("docs"), true));
+        assert!(!filter.is_excluded(Path::new("docs/deep"), true));
+        assert!(!excluded(&filter, "docs/deep/guide.md"));
+        assert!(excluded(&filter, "docs/deep/guide.txt"));
+    }
+
+    #[test]
+    fn directory_patterns_only_match_directories() {
+        let filter = Filter::new(&[&rules(&[], &["node_modules/"])]).unwrap();
+
+        assert!(filter.is_excluded(Path::new("app/node_modules"), true));
+        assert!(excluded(&filter, "app/node_modules/left-pad/index.js"));
+        assert!(!excluded(&filter, "app/node_modules"));
+    }
+}
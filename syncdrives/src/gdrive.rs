@@ -1,107 +1,350 @@
-//! Connect with and upload file to Google Drive using their API
+//! Connect with and upload files to Google Drive using their API
 
+use std::error::Error as StdError;
 use std::fs;
+use std::future::Future;
 use std::io::Cursor;
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
-use anyhow::{Context, Result};
+use std::pin::Pin;
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
 use google_drive3::{DriveHub, api::File, hyper, hyper_rustls, oauth2};
+use google_drive3::client::GetToken;
 use hyper::client::HttpConnector;
 use hyper_rustls::HttpsConnector;
-use oauth2::{InstalledFlowAuthenticator, InstalledFlowReturnMethod, ApplicationSecret};
+use mime::Mime;
+use oauth2::authenticator::Authenticator;
+use oauth2::authenticator_delegate::{DeviceAuthResponse, DeviceFlowDelegate, InstalledFlowDelegate};
+use oauth2::{
+    ApplicationSecret, DeviceFlowAuthenticator, InstalledFlowAuthenticator,
+    InstalledFlowReturnMethod, ServiceAccountAuthenticator,
+};
 use serde::{Deserialize, Serialize};
 
+use crate::auth;
+use crate::manifest::file_md5;
+use crate::mimetype;
+use crate::output::{format_bytes, Event, Phase, Reporter};
+use crate::progress::ByteCounter;
+use crate::resumable::{DriveTarget, UploadSession, RESUMABLE_THRESHOLD};
+use crate::retry::{Retrier, RetryPolicy};
+use crate::sync::ActionKind;
+
+/// Google Drive API client, along with how failed calls are retried.
+#[derive(Clone)]
+pub struct Hub {
+    api: DriveHub<HttpsConnector<HttpConnector>>,
+    retry: RetryPolicy,
+    out: Reporter,
+}
+
+impl Hub {
+    /// Delegate for retrying a call, described by `what` when retried.
+    pub fn retrier(&self, what: impl Into<String>) -> Retrier {
+        Retrier::new(self.retry, self.out.clone(), what)
+    }
+
+    /// Delegate for retrying a call that creates a file, only when it
+    /// certainly wasn't carried out.
+    pub fn create_retrier(&self, what: impl Into<String>) -> Retrier {
+        Retrier::for_create(self.retry, self.out.clone(), what)
+    }
+}
+
+impl Deref for Hub {
+    type Target = DriveHub<HttpsConnector<HttpConnector>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.api
+    }
+}
+
+type Auth = Authenticator<HttpsConnector<HttpConnector>>;
+
+const FOLDER_MIME_TYPE: &str = "application/vnd.google-apps.folder";
+
+/// The only Drive scope Google grants through the device flow
+const FILE_SCOPE: &str = "https://www.googleapis.com/auth/drive.file";
+
+/// How to sign in to Google Drive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthMethod {
+    /// Open a browser on this machine, with `installed` or `web`
+    /// client secrets
+    #[default]
+    Browser,
+
+    /// Enter a code shown in the terminal on another device. Google
+    /// limits this to files syncdrives created itself.
+    Device,
+
+    /// Use a service account's JSON key as the secrets file
+    ServiceAccount,
+}
+
+/// Client secrets as downloaded from the Google Cloud console, for
+/// either a desktop or a web application.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct GDApiConfig {
-    installed: InstalledApp,
+    installed: Option<ClientApp>,
+    web: Option<ClientApp>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
-pub struct InstalledApp {
+pub struct ClientApp {
     pub client_id: String,
-    pub project_id: String,
+    pub project_id: Option<String>,
     pub auth_uri: String,
     pub token_uri: String,
-    pub auth_provider_x509_cert_url: String,
+    pub auth_provider_x509_cert_url: Option<String>,
     pub client_secret: String,
+    #[serde(default)]
     pub redirect_uris: Vec<String>,
 }
 
-impl From<InstalledApp> for ApplicationSecret {
-    fn from(app: InstalledApp) -> Self {
+impl From<ClientApp> for ApplicationSecret {
+    fn from(app: ClientApp) -> Self {
         Self {
             client_id: app.client_id,
             client_secret: app.client_secret,
             token_uri: app.token_uri,
             auth_uri: app.auth_uri,
             redirect_uris: app.redirect_uris,
-            project_id: Some(app.project_id),
+            project_id: app.project_id,
             client_email: None,
-            auth_provider_x509_cert_url: Some(app.auth_provider_x509_cert_url),
+            auth_provider_x509_cert_url: app.auth_provider_x509_cert_url,
             client_x509_cert_url: None,
         }
     }
 }
 
-/// Read and parse GD API credentials from JSON file.
-fn get_gdapi_config(secrets_file: Option<String>) -> Result<GDApiConfig> {
-    let path: PathBuf = if let Some(f) = secrets_file {
+/// Path of the secrets file, by default next to the manifest.
+pub fn secrets_path(secrets_file: Option<String>) -> PathBuf {
+    if let Some(f) = secrets_file {
         PathBuf::from(f)
     } else {
         [env!("CARGO_MANIFEST_DIR"), "client_secrets.json"].iter().collect()
-    };
+    }
+}
 
-    let cfg_content = fs::read_to_string(&path)
+/// Read and parse GD API credentials from JSON file.
+fn get_gdapi_config(path: &Path) -> Result<GDApiConfig> {
+    let cfg_content = fs::read_to_string(path)
         .with_context(|| {
             format!("Failed to read GDApi config file {}", path.display())
         })?;
 
-    let config = serde_json::from_str(&cfg_content)?;
+    let config = serde_json::from_str(&cfg_content)
+        .with_context(|| format!("Failed to parse GDApi config file {}", path.display()))?;
 
     Ok(config)
 }
 
 /// Connect to Google Drive and return hub for accessing it.
+///
+/// User sign-ins are cached in `token_cache`, which is kept private to
+/// the current user. Calls that fail for a while are retried under
+/// `retry`.
 pub async fn get_drivehub(
     secrets_file: Option<String>,
-) -> Result<DriveHub<HttpsConnector<HttpConnector>>> {
-    let config: GDApiConfig = get_gdapi_config(secrets_file)?;
-    let secret: ApplicationSecret = config.installed.into();
-
-    let auth = InstalledFlowAuthenticator::builder(
-        secret,
-        InstalledFlowReturnMethod::HTTPRedirect,
-    )
-    .persist_tokens_to_disk("tokencache.json")
-    .build()
-    .await?;
+    method: AuthMethod,
+    token_cache: &Path,
+    retry: RetryPolicy,
+    out: &Reporter,
+) -> Result<Hub> {
+    let path = secrets_path(secrets_file);
+    let client = https_client()?;
+
+    if method != AuthMethod::ServiceAccount {
+        auth::secure_token_cache(token_cache)?;
+    }
+
+    let api = match method {
+        AuthMethod::Browser => DriveHub::new(client, browser_auth(&path, token_cache).await?),
+        AuthMethod::Device => {
+            DriveHub::new(client, FileScoped(device_auth(&path, token_cache).await?))
+        }
+        AuthMethod::ServiceAccount => {
+            let key = oauth2::read_service_account_key(&path)
+                .await
+                .with_context(|| {
+                    format!("Failed to read service account key {}", path.display())
+                })?;
 
+            let auth = ServiceAccountAuthenticator::builder(key).build().await?;
+            DriveHub::new(client, auth)
+        }
+    };
+
+    Ok(Hub { api, retry, out: out.clone() })
+}
+
+/// HTTP client for talking to Google.
+pub fn https_client() -> Result<hyper::Client<HttpsConnector<HttpConnector>>> {
     let connector = hyper_rustls::HttpsConnectorBuilder::new()
         .with_native_roots()?
         .https_or_http()
         .enable_http1()
         .build();
 
-    let client = hyper::Client::builder().build(connector);
+    Ok(hyper::Client::builder().build(connector))
+}
+
+async fn browser_auth(path: &Path, token_cache: &Path) -> Result<Auth> {
+    let config = get_gdapi_config(path)?;
 
-    Ok(DriveHub::new(client, auth))
+    let builder = match (config.installed, config.web) {
+        (Some(app), _) => InstalledFlowAuthenticator::builder(
+            app.into(),
+            InstalledFlowReturnMethod::HTTPRedirect,
+        ),
+        (None, Some(app)) => {
+            // Web clients only accept the redirect URIs registered for
+            // them, so listen on the port of a localhost one
+            let Some((uri, port)) = app.redirect_uris.iter().find_map(|u| localhost_port(u)) else {
+                bail!("Web client secrets in {} need a http://localhost:<port> redirect URI", path.display());
+            };
+
+            InstalledFlowAuthenticator::builder(
+                app.into(),
+                InstalledFlowReturnMethod::HTTPPortRedirect(port),
+            )
+            .flow_delegate(Box::new(WebRedirect(uri)))
+        }
+        (None, None) => bail!("No `installed` or `web` client in {}", path.display()),
+    };
+
+    let auth = builder
+        .persist_tokens_to_disk(token_cache)
+        .build()
+        .await?;
+
+    Ok(auth)
 }
 
-/// Upload a single file to Google Drive.
-pub async fn upload_file_to_drive(
-    hub: &DriveHub<HttpsConnector<HttpConnector>>,
-    file_path: &str,
-    parent_folder_id: Option<&str>,
-) -> Result<()> {
-    let path = Path::new(file_path);
-    let file_name = path.file_name()
-        .and_then(|n| n.to_str())
-        .ok_or("Invalid file name")
-        .unwrap();
+async fn device_auth(path: &Path, token_cache: &Path) -> Result<Auth> {
+    let auth = DeviceFlowAuthenticator::builder(client_app(path)?.into())
+        .flow_delegate(Box::new(DeviceCodePrompt))
+        .persist_tokens_to_disk(token_cache)
+        .build()
+        .await?;
+
+    Ok(auth)
+}
+
+/// The desktop or, failing that, web client in a secrets file.
+pub fn client_app(path: &Path) -> Result<ClientApp> {
+    let config = get_gdapi_config(path)?;
+
+    match config.installed.or(config.web) {
+        Some(app) => Ok(app),
+        None => bail!("No `installed` or `web` client in {}", path.display()),
+    }
+}
+
+/// A `http://localhost:<port>` redirect URI and its port.
+fn localhost_port(uri: &str) -> Option<(String, u16)> {
+    let host_port = uri.strip_prefix("http://")?.split('/').next()?;
+    let (host, port) = host_port.split_once(':')?;
+
+    if host != "localhost" && host != "127.0.0.1" {
+        return None;
+    }
+
+    Some((uri.to_string(), port.parse().ok()?))
+}
 
-    let file_content = fs::read(file_path)?;
+/// Redirect to a web client's registered URI.
+struct WebRedirect(String);
+
+impl InstalledFlowDelegate for WebRedirect {
+    fn redirect_uri(&self) -> Option<&str> {
+        Some(&self.0)
+    }
+}
+
+/// Show the device code on stderr, keeping stdout for output.
+struct DeviceCodePrompt;
+
+impl DeviceFlowDelegate for DeviceCodePrompt {
+    fn present_user_code<'a>(
+        &'a self,
+        resp: &'a DeviceAuthResponse,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(async move {
+            eprintln!("Go to {} and enter the code {}", resp.verification_uri, resp.user_code);
+            eprintln!("Waiting for access to be granted...");
+        })
+    }
+}
+
+/// Authenticator that asks for the `drive.file` scope whatever the
+/// call wants, as Google refuses full Drive access to device logins.
+#[derive(Clone)]
+struct FileScoped(Auth);
+
+impl GetToken for FileScoped {
+    fn get_token<'a>(
+        &'a self,
+        _scopes: &'a [&str],
+    ) -> Pin<Box<dyn Future<Output = Result<Option<String>, Box<dyn StdError + Send + Sync>>> + Send + 'a>> {
+        Box::pin(async move {
+            let token = self.0.token(&[FILE_SCOPE]).await?;
+            Ok(token.token().map(str::to_string))
+        })
+    }
+}
+
+/// How files are sent to Google Drive.
+#[derive(Clone, Debug, Default)]
+pub struct UploadOptions {
+    /// MIME type to send instead of detecting one
+    pub mime_type: Option<Mime>,
+
+    /// Have Drive convert office and CSV files to Google Docs formats
+    pub convert: bool,
+}
+
+impl UploadOptions {
+    /// MIME type to send a file's content as.
+    pub fn mime_type(&self, path: &Path) -> Mime {
+        self.mime_type.clone().unwrap_or_else(|| mimetype::detect(path))
+    }
+}
+
+/// Name and Google Docs type, if it's converted, a file gets on Drive.
+/// Converted files don't keep their extension.
+pub fn drive_name(path: &Path, opts: &UploadOptions) -> Result<(String, Option<&'static str>)> {
+    let name = file_name(path)?;
+
+    match mimetype::google_type(&opts.mime_type(path)).filter(|_| opts.convert) {
+        Some(google_type) => {
+            let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or(name);
+            Ok((stem.to_string(), Some(google_type)))
+        }
+        None => Ok((name.to_string(), None)),
+    }
+}
+
+/// Upload a file as a new Drive file, returning Drive's description of
+/// it and its size.
+pub async fn create_file(
+    hub: &Hub,
+    path: &Path,
+    parent_folder_id: Option<&str>,
+    opts: &UploadOptions,
+    sent: &ByteCounter,
+    out: &Reporter,
+) -> Result<(File, u64)> {
+    let (mut file, meta) = open_file(path)?;
+    let mime_type = opts.mime_type(path);
+    let (name, google_type) = drive_name(path, opts)?;
 
     let mut file_metadata = File {
-        name: Some(file_name.to_string()),
+        name: Some(name),
+        mime_type: google_type.map(str::to_string),
         ..Default::default()
     };
 
@@ -109,18 +352,558 @@ pub async fn upload_file_to_drive(
         file_metadata.parents = Some(vec![folder_id.to_string()]);
     }
 
-    let result = hub.files()
-        .create(file_metadata)
-        .upload(
-            Cursor::new(file_content),
-            "application/octet-stream".parse()?,
-        )
-        .await?;
+    let what = format!("upload of {}", path.display());
+
+    let result = if meta.len() <= RESUMABLE_THRESHOLD {
+        hub.files()
+            .create(file_metadata)
+            .delegate(&mut hub.create_retrier(what))
+            .upload(sent.reader(&file), mime_type)
+            .await
+            .map(|(_, done)| done)
+            .map_err(anyhow::Error::from)
+    } else {
+        let target = parent_folder_id.unwrap_or("root");
+        let session = UploadSession::new(target, path, &meta);
+        let mut drive = DriveTarget::create(hub, file_metadata, mime_type, meta.len(), what);
+
+        session.upload(&mut drive, &mut file, sent, out).await
+    };
+
+    let file = result.with_context(|| format!("Failed to upload {}", path.display()))?;
+
+    Ok((file, meta.len()))
+}
+
+/// Replace the content of an existing Drive file, returning its size.
+/// Drive converts the content again if the file is a Google Docs one.
+pub async fn update_file(
+    hub: &Hub,
+    path: &Path,
+    file_id: &str,
+    opts: &UploadOptions,
+    sent: &ByteCounter,
+    out: &Reporter,
+) -> Result<u64> {
+    let (mut file, meta) = open_file(path)?;
+    let mime_type = opts.mime_type(path);
 
-    let file_id = result.1.id
-        .ok_or("No file id returned")
-        .unwrap();
-    println!("Uploaded '{}' with ID: {}", file_name, file_id);
+    let what = format!("update of {}", path.display());
+
+    let result = if meta.len() <= RESUMABLE_THRESHOLD {
+        hub.files()
+            .update(File::default(), file_id)
+            .delegate(&mut hub.retrier(what))
+            .upload(sent.reader(&file), mime_type)
+            .await
+            .map(|(_, done)| done)
+            .map_err(anyhow::Error::from)
+    } else {
+        let session = UploadSession::new(file_id, path, &meta);
+        let mut drive = DriveTarget::update(
+            hub, file_id, File::default(), mime_type, meta.len(), what,
+        );
+
+        session.upload(&mut drive, &mut file, sent, out).await
+    };
+
+    result.with_context(|| format!("Failed to update {}", path.display()))?;
+
+    Ok(meta.len())
+}
+
+fn open_file(path: &Path) -> Result<(fs::File, fs::Metadata)> {
+    let file = fs::File::open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let meta = file.metadata()
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+
+    Ok((file, meta))
+}
+
+pub async fn trash_file(hub: &Hub, file_id: &str) -> Result<()> {
+    let trashed = File {
+        trashed: Some(true),
+        ..Default::default()
+    };
+
+    hub.files()
+        .update(trashed, file_id)
+        .delegate(&mut hub.retrier(format!("trashing {}", file_id)))
+        .doit_without_upload()
+        .await
+        .with_context(|| format!("Failed to trash {}", file_id))?;
 
     Ok(())
 }
+
+/// What syncing a directory to Google Drive did, or would do.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DriveSyncStats {
+    pub uploaded: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub trashed: usize,
+    pub bytes: u64,
+}
+
+/// Sync a directory tree to a folder of the same name under
+/// `parent_folder_id`.
+///
+/// Files are compared by size and md5: new ones are uploaded, changed
+/// ones updated in place and unchanged ones skipped. With `delete`,
+/// Drive files and folders missing locally are moved to the trash.
+/// Google Docs files are left alone.
+pub async fn sync_dir_to_drive(
+    hub: &Hub,
+    dir: &Path,
+    parent_folder_id: &str,
+    delete: bool,
+    dry_run: bool,
+    out: &Reporter,
+) -> Result<DriveSyncStats> {
+    if !dir.is_dir() {
+        bail!("`{}` is not a directory", dir.display());
+    }
+
+    let dir_name = file_name(&dir.canonicalize()?)?.to_string();
+    let mut stats = DriveSyncStats::default();
+
+    let existing = find_folder(hub, &dir_name, parent_folder_id).await?;
+    let root_id = ensure_folder(
+        hub, dir, &dir_name, parent_folder_id, existing, dry_run, out,
+    ).await?;
+
+    // Folders still to sync, by path relative to `dir`. A folder has no
+    // id when it would only be created by a real run.
+    let mut pending: Vec<(PathBuf, Option<String>)> = vec![(PathBuf::new(), root_id)];
+
+    while let Some((rel_dir, folder_id)) = pending.pop() {
+        let mut remote = match &folder_id {
+            Some(id) => list_folder(hub, id).await?,
+            None => Vec::new(),
+        };
+        remote.retain(|f| !is_google_doc(f));
+
+        let full_dir = dir.join(&rel_dir);
+        let mut entries = fs::read_dir(&full_dir)
+            .with_context(|| format!("Failed to read {}", full_dir.display()))?
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|e| e.file_name());
+
+        for entry in entries {
+            let rel_path = rel_dir.join(entry.file_name());
+            let path = dir.join(&rel_path);
+            let name = file_name(&rel_path)?;
+            let file_type = entry.file_type()?;
+
+            if !file_type.is_dir() && !file_type.is_file() {
+                continue;
+            }
+
+            // Drive allows several files with the same name; the first
+            // one is synced and any others count as missing locally
+            let found = remote.iter().position(|f| {
+                f.name.as_deref() == Some(name) && is_folder(f) == file_type.is_dir()
+            });
+            let found = found.map(|i| remote.remove(i));
+
+            if file_type.is_dir() {
+                let existing = found.and_then(|f| f.id);
+                let parent_id = folder_id.as_deref().unwrap_or_default();
+                let id = ensure_folder(
+                    hub, &path, name, parent_id, existing, dry_run, out,
+                ).await?;
+                pending.push((rel_path, id));
+                continue;
+            }
+
+            let size = entry.metadata()?.len();
+
+            let (action, id) = match found {
+                None => {
+                    let id = if dry_run {
+                        None
+                    } else {
+                        let opts = UploadOptions::default();
+                        let (file, _) = create_file(
+                            hub, &path, folder_id.as_deref(), &opts, &ByteCounter::default(), out,
+                        ).await?;
+                        file.id
+                    };
+                    stats.uploaded += 1;
+                    (ActionKind::Create, id)
+                }
+                Some(remote_file) => {
+                    let id = remote_file.id.clone().context("Drive file has no id")?;
+
+                    if is_unchanged(&path, size, &remote_file)? {
+                        stats.unchanged += 1;
+                        continue;
+                    }
+
+                    if !dry_run {
+                        let opts = UploadOptions::default();
+                        update_file(hub, &path, &id, &opts, &ByteCounter::default(), out).await?;
+                    }
+                    stats.updated += 1;
+                    (ActionKind::Update, Some(id))
+                }
+            };
+
+            stats.bytes += size;
+            report_remote_file(&rel_path, id, action, size, dry_run, out);
+        }
+
+        if delete {
+            for remote_file in remote {
+                let name = remote_file.name.unwrap_or_default();
+                let id = remote_file.id.context("Drive file has no id")?;
+
+                if !dry_run {
+                    trash_file(hub, &id).await?;
+                }
+                stats.trashed += 1;
+                report_remote_file(&rel_dir.join(name), Some(id), ActionKind::Delete, 0, dry_run, out);
+            }
+        }
+    }
+
+    out.say(format!(
+        "{} {}, {} {}, {} unchanged, {} {} ({} {})",
+        stats.uploaded, if dry_run { "to upload" } else { "uploaded" },
+        stats.updated, if dry_run { "to update" } else { "updated" },
+        stats.unchanged,
+        stats.trashed, if dry_run { "to trash" } else { "trashed" },
+        format_bytes(stats.bytes), if dry_run { "to send" } else { "sent" },
+    ));
+    out.emit(Event::DriveSync {
+        path: dir.to_path_buf(),
+        uploaded: stats.uploaded,
+        updated: stats.updated,
+        unchanged: stats.unchanged,
+        trashed: stats.trashed,
+        bytes: stats.bytes,
+        dry_run,
+    });
+
+    Ok(stats)
+}
+
+fn report_remote_file(
+    rel_path: &Path,
+    id: Option<String>,
+    action: ActionKind,
+    bytes: u64,
+    dry_run: bool,
+    out: &Reporter,
+) {
+    let verb = match action {
+        ActionKind::Create => "upload",
+        ActionKind::Update => "update",
+        ActionKind::Delete => "trash",
+        ActionKind::Skip => "skip",
+    };
+    out.say(format!("{:<6} {}", verb, rel_path.display()));
+    out.emit(Event::RemoteFile {
+        phase: Phase::DriveSync,
+        path: rel_path.to_path_buf(),
+        id,
+        action,
+        bytes,
+        dry_run,
+    });
+}
+
+/// Whether a Drive file has the same size and md5 as a local file.
+fn is_unchanged(path: &Path, size: u64, remote_file: &File) -> Result<bool> {
+    if remote_file.size != Some(size as i64) {
+        return Ok(false);
+    }
+
+    match &remote_file.md5_checksum {
+        Some(md5) => Ok(*md5 == file_md5(path)?),
+        None => Ok(false),
+    }
+}
+
+pub fn is_folder(file: &File) -> bool {
+    file.mime_type.as_deref() == Some(FOLDER_MIME_TYPE)
+}
+
+/// Whether a file is a Google Docs one, without a size or md5.
+fn is_google_doc(file: &File) -> bool {
+    let mime_type = file.mime_type.as_deref().unwrap_or_default();
+    mime_type.starts_with("application/vnd.google-apps.") && !is_folder(file)
+}
+
+/// Create a folder unless it exists already or this is a dry-run,
+/// returning its id if there is one.
+async fn ensure_folder(
+    hub: &Hub,
+    path: &Path,
+    name: &str,
+    parent_folder_id: &str,
+    existing: Option<String>,
+    dry_run: bool,
+    out: &Reporter,
+) -> Result<Option<String>> {
+    if existing.is_some() {
+        return Ok(existing);
+    }
+
+    if dry_run {
+        out.say(format!("Would create folder '{}'", name));
+        return Ok(None);
+    }
+
+    let id = create_folder(hub, name, parent_folder_id).await?;
+
+    out.say(format!("Created folder '{}' with ID: {}", name, id));
+    out.emit(Event::Folder {
+        phase: Phase::DriveSync,
+        path: path.to_path_buf(),
+        name: name.to_string(),
+        id: id.clone(),
+        created: true,
+    });
+
+    Ok(Some(id))
+}
+
+pub async fn find_folder(hub: &Hub, name: &str, parent_folder_id: &str) -> Result<Option<String>> {
+    let query = format!(
+        "name = '{}' and '{}' in parents and mimeType = '{}' and trashed = false",
+        escape_query(name), escape_query(parent_folder_id), FOLDER_MIME_TYPE,
+    );
+
+    let (_, list) = hub.files()
+        .list()
+        .q(&query)
+        .param("fields", "files(id, name)")
+        .delegate(&mut hub.retrier(format!("lookup of folder '{}'", name)))
+        .doit()
+        .await
+        .with_context(|| format!("Failed to look up folder '{}'", name))?;
+
+    Ok(list.files
+        .and_then(|files| files.into_iter().next())
+        .and_then(|f| f.id))
+}
+
+pub async fn create_folder(hub: &Hub, name: &str, parent_folder_id: &str) -> Result<String> {
+    let folder = File {
+        name: Some(name.to_string()),
+        mime_type: Some(FOLDER_MIME_TYPE.to_string()),
+        parents: Some(vec![parent_folder_id.to_string()]),
+        ..Default::default()
+    };
+
+    let result = hub.files()
+        .create(folder)
+        .delegate(&mut hub.create_retrier(format!("creation of folder '{}'", name)))
+        .upload(Cursor::new(Vec::new()), FOLDER_MIME_TYPE.parse()?)
+        .await
+        .with_context(|| format!("Failed to create folder '{}'", name))?;
+
+    result.1.id.context("No folder id returned")
+}
+
+/// Every file and folder directly in a Drive folder, except trashed ones.
+pub async fn list_folder(hub: &Hub, folder_id: &str) -> Result<Vec<File>> {
+    let query = format!("'{}' in parents and trashed = false", escape_query(folder_id));
+    let mut files = Vec::new();
+    let mut page_token: Option<String> = None;
+
+    loop {
+        let mut call = hub.files()
+            .list()
+            .q(&query)
+            .page_size(1000)
+            .order_by("folder, name")
+            .param(
+                "fields",
+                "nextPageToken, files(id, name, mimeType, md5Checksum, size, modifiedTime)",
+            );
+        if let Some(token) = &page_token {
+            call = call.page_token(token);
+        }
+
+        let mut retrier = hub.retrier(format!("listing of folder {}", folder_id));
+        let (_, list) = call.delegate(&mut retrier)
+            .doit()
+            .await
+            .with_context(|| format!("Failed to list folder {}", folder_id))?;
+
+        files.extend(list.files.unwrap_or_default());
+
+        match list.next_page_token {
+            Some(token) => page_token = Some(token),
+            None => break,
+        }
+    }
+
+    Ok(files)
+}
+
+/// Subdirectories and files under `dir`, relative to it and in path
+/// order. Symlinks and other special files are left out.
+pub fn walk_tree(dir: &Path) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut subdirs = Vec::new();
+    let mut files = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+
+    while let Some(rel_dir) = pending.pop() {
+        let full_dir = dir.join(&rel_dir);
+        let entries = fs::read_dir(&full_dir)
+            .with_context(|| format!("Failed to read {}", full_dir.display()))?;
+
+        for entry in entries {
+            let entry = entry?;
+            let rel_path = rel_dir.join(entry.file_name());
+            let file_type = entry.file_type()?;
+
+            if file_type.is_dir() {
+                subdirs.push(rel_path.clone());
+                pending.push(rel_path);
+            } else if file_type.is_file() {
+                files.push(rel_path);
+            }
+        }
+    }
+
+    subdirs.sort();
+    files.sort();
+
+    Ok((subdirs, files))
+}
+
+pub fn file_name(path: &Path) -> Result<&str> {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .with_context(|| format!("Invalid file name {}", path.display()))
+}
+
+/// Escape a value for use in a quoted Drive search query.
+fn escape_query(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn opts(mime_type: Option<&str>, convert: bool) -> UploadOptions {
+        UploadOptions { mime_type: mime_type.map(|m| m.parse().unwrap()), convert }
+    }
+
+    #[test]
+    fn converted_files_lose_their_extension_and_take_a_google_type() {
+        let path = Path::new("reports/q1.csv");
+
+        assert_eq!(drive_name(path, &opts(None, false)).unwrap(), ("q1.csv".to_string(), None));
+        assert_eq!(
+            drive_name(path, &opts(None, true)).unwrap(),
+            ("q1".to_string(), Some("application/vnd.google-apps.spreadsheet")),
+        );
+    }
+
+    #[test]
+    fn given_mime_types_decide_the_conversion() {
+        let path = Path::new("notes.txt");
+
+        assert_eq!(drive_name(path, &opts(None, true)).unwrap(), ("notes.txt".to_string(), None));
+        assert_eq!(
+            drive_name(path, &opts(Some("text/csv"), true)).unwrap(),
+            ("notes".to_string(), Some("application/vnd.google-apps.spreadsheet")),
+        );
+        assert_eq!(opts(Some("text/csv"), false).mime_type(path).essence_str(), "text/csv");
+    }
+
+    #[test]
+    fn walks_trees_in_path_order_without_symlinks() {
+        let tmp = TempDir::new().unwrap();
+        fs::create_dir_all(tmp.path().join("b/deep")).unwrap();
+        fs::create_dir(tmp.path().join("a")).unwrap();
+        fs::write(tmp.path().join("b/deep/c.txt"), "").unwrap();
+        fs::write(tmp.path().join("top.txt"), "").unwrap();
+        std::os::unix::fs::symlink(tmp.path().join("top.txt"), tmp.path().join("a/link.txt")).unwrap();
+
+        let (subdirs, files) = walk_tree(tmp.path()).unwrap();
+
+        assert_eq!(subdirs, [Path::new("a"), Path::new("b"), Path::new("b/deep")]);
+        assert_eq!(files, [Path::new("b/deep/c.txt"), Path::new("top.txt")]);
+    }
+
+    #[test]
+    fn unchanged_files_match_in_size_and_md5() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("a.txt");
+        fs::write(&path, "hello").unwrap();
+
+        let remote = |size: i64, md5: Option<&str>| File {
+            size: Some(size),
+            md5_checksum: md5.map(str::to_string),
+            ..Default::default()
+        };
+        let md5 = "5d41402abc4b2a76b9719d911017c592";
+
+        assert!(is_unchanged(&path, 5, &remote(5, Some(md5))).unwrap());
+        assert!(!is_unchanged(&path, 5, &remote(5, Some("0"))).unwrap());
+        assert!(!is_unchanged(&path, 5, &remote(4, Some(md5))).unwrap());
+        assert!(!is_unchanged(&path, 5, &remote(5, None)).unwrap());
+    }
+
+    #[test]
+    fn escapes_quotes_in_search_queries() {
+        assert_eq!(escape_query(r"Bob's \ notes"), r"Bob\'s \\ notes");
+    }
+
+    fn secrets(tmp: &TempDir, json: &str) -> PathBuf {
+        let path = tmp.path().join("client_secret.json");
+        fs::write(&path, json).unwrap();
+        path
+    }
+
+    const CLIENT: &str = r#"{
+        "client_id": "id", "client_secret": "secret",
+        "auth_uri": "https://auth", "token_uri": "https://token"
+    }"#;
+
+    #[test]
+    fn client_apps_come_from_installed_or_web_secrets() {
+        let tmp = TempDir::new().unwrap();
+
+        let installed = secrets(&tmp, &format!(r#"{{"installed": {}}}"#, CLIENT));
+        assert_eq!(client_app(&installed).unwrap().client_id, "id");
+
+        let web = secrets(&tmp, &format!(r#"{{"web": {}}}"#, CLIENT));
+        assert_eq!(client_app(&web).unwrap().client_secret, "secret");
+
+        let neither = secrets(&tmp, r#"{"type": "service_account"}"#);
+        let err = client_app(&neither).unwrap_err().to_string();
+        assert!(err.starts_with("No `installed` or `web` client in"), "{}", err);
+    }
+
+    #[test]
+    fn redirects_listen_on_local_ports_only() {
+        assert_eq!(
+            localhost_port("http://localhost:8080/callback"),
+            Some(("http://localhost:8080/callback".to_string(), 8080)),
+        );
+        assert_eq!(localhost_port("http://127.0.0.1:9000").map(|(_, p)| p), Some(9000));
+        assert_eq!(localhost_port("http://localhost/callback"), None);
+        assert_eq!(localhost_port("https://localhost:8080"), None);
+        assert_eq!(localhost_port("http://example.com:8080"), None);
+    }
+
+    #[test]
+    fn google_docs_are_told_apart_from_folders_and_plain_files() {
+        let file = |mime_type: &str| File { mime_type: Some(mime_type.to_string()), ..Default::default() };
+
+        assert!(is_google_doc(&file("application/vnd.google-apps.document")));
+        assert!(!is_google_doc(&file(FOLDER_MIME_TYPE)));
+        assert!(!is_google_doc(&file("text/plain")));
+    }
+}
@@ -0,0 +1,261 @@
+//! Syncing directories between drives according to sync groups
+
+use anyhow::{bail, Result};
+
+use crate::config::{Config, GroupMode, SyncGroup};
+use crate::manifest::Manifest;
+use crate::output::{Event, Phase, Reporter, Status};
+use crate::util::{self, DriveInfo};
+
+/// Sync a group's directory between its member drives, returning the
+/// number of failed syncs.
+///
+/// Union groups gather every member's files on the primary (or first
+/// member) and then spread them back out, rather than syncing every
+/// pair of drives.
+pub fn sync_group(
+    group: &SyncGroup,
+    cfg: &Config,
+    dests: &mut [DriveInfo],
+    dry_run: bool,
+    out: &Reporter,
+) -> usize {
+    let (members, hub) = match group_members(group, dests) {
+        Ok(members) => members,
+        Err(e) => {
+            out.error(None, Some(Phase::CrossSync), format!("{:#}", e));
+            return 1;
+        }
+    };
+
+    if let Some(e) = dests[hub].err {
+        out.say(format!(
+            "Skipping group {g} due to {d} {err} error",
+            g=group.name, d=dests[hub].nickname, err=e.kind(),
+        ));
+        for &i in members.iter() {
+            out.emit(drive_event(&dests[i].nickname, Status::Skipped));
+        }
+        return 0;
+    }
+
+    let spokes: Vec<usize> = members.iter().copied().filter(|&i| i != hub).collect();
+    let mut pairs: Vec<(usize, usize)> = Vec::new();
+
+    if group.mode == GroupMode::Union {
+        pairs.extend(spokes.iter().map(|&i| (i, hub)));
+    }
+    pairs.extend(spokes.iter().map(|&i| (hub, i)));
+
+    let mut errors = 0;
+
+    for (i, j) in pairs {
+        let (src, dest) = (&dests[i], &dests[j]);
+
+        if src.err.is_some() {
+            continue;
+        }
+
+        if let Some(e) = dest.err {
+            out.say(format!(
+                "Skipping {s} -> {d} sync due to {d} {err} error",
+                s=src.nickname, d=dest.nickname, err=e.kind(),
+            ));
+            out.emit(drive_event(&dest.nickname, Status::Skipped));
+            continue;
+        }
+
+        let result = Manifest::load(&dest.base_dir, cfg.manifest_hashes)
+            .and_then(|mut manifest| {
+                let synced = util::sync_dir(group, src, dest, &cfg.filter, dry_run, &mut manifest, out);
+
+                if !dry_run {
+                    manifest.save()?;
+                }
+
+                synced
+            });
+
+        match result {
+            Ok(stats) => dests[j].stats.add(&stats),
+            Err(e) => {
+                out.error(
+                    Some(&dests[j].nickname),
+                    Some(Phase::CrossSync),
+                    format!("{:#}", e),
+                );
+                errors += 1;
+            }
+        }
+    }
+
+    errors
+}
+
+/// Indices of a group's member drives and of the one the others are
+/// synced from.
+fn group_members(group: &SyncGroup, dests: &[DriveInfo]) -> Result<(Vec<usize>, usize)> {
+    let find = |nickname: &str| -> Result<usize> {
+        match dests.iter().position(|d| d.nickname == nickname) {
+            Some(i) => Ok(i),
+            None => bail!("Group {} has unknown drive `{}`", group.name, nickname),
+        }
+    };
+
+    let members = match &group.members {
+        Some(nicknames) => nicknames.iter()
+            .map(|n| find(n))
+            .collect::<Result<Vec<usize>>>()?,
+        None => (0..dests.len()).collect(),
+    };
+
+    let hub = match (&group.primary, group.mode) {
+        (Some(primary), _) => {
+            let hub = find(primary)?;
+            if !members.contains(&hub) {
+                bail!("Group {} primary `{}` is not a member", group.name, primary);
+            }
+            hub
+        }
+        (None, GroupMode::Union) => {
+            // Prefer a drive that is actually available
+            let healthy = members.iter().find(|&&i| dests[i].err.is_none());
+            match healthy.or(members.first()) {
+                Some(&i) => i,
+                None => bail!("Group {} has no drives", group.name),
+            }
+        }
+        (None, mode) => bail!("Group {} needs a primary in {} mode", group.name, mode),
+    };
+
+    Ok((members, hub))
+}
+
+fn drive_event(nickname: &str, status: Status) -> Event {
+    Event::Drive {
+        drive: nickname.to_string(),
+        phase: Phase::CrossSync,
+        status,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+    use crate::output::OutputMode;
+    use crate::util::DestError;
+
+    /// Drives `A`, `B` and `C` as directories in a temporary directory,
+    /// each with a `shared` directory holding the given files. Contents
+    /// differ in size between drives, e.g. `a.txt from BB`.
+    struct Drives {
+        tmp: TempDir,
+        dests: Vec<DriveInfo>,
+    }
+
+    impl Drives {
+        fn new(files: [&[&str]; 3]) -> Self {
+            let tmp = TempDir::new().unwrap();
+            let root = tmp.path().strip_prefix("/").unwrap().display().to_string();
+
+            let dests = ["A", "B", "C"].iter().zip(files).enumerate()
+                .map(|(i, (nickname, files))| {
+                    let dir = tmp.path().join(nickname).join("shared");
+                    fs::create_dir_all(&dir).unwrap();
+                    for name in files {
+                        fs::write(dir.join(name), format!("{} from {}", name, nickname.repeat(i + 1))).unwrap();
+                    }
+
+                    let drive = format!(
+                        "mount = {{ type = \"mounted\", path = \"/\" }}\nnickname = \"{}\"\nbase_dir = \"{}/{}\"",
+                        nickname, root, nickname,
+                    );
+                    DriveInfo::from_drive(&toml::from_str(&drive).unwrap())
+                })
+                .collect();
+
+            Self { tmp, dests }
+        }
+
+        fn sync(&mut self, group: &str) -> usize {
+            let group: SyncGroup = toml::from_str(group).unwrap();
+            let cfg: Config = toml::from_str("subdirs = []\ndrives = []").unwrap();
+            let quiet = Reporter::new(OutputMode::Human).buffered();
+
+            sync_group(&group, &cfg, &mut self.dests, false, &quiet)
+        }
+
+        fn files(&self, nickname: &str) -> Vec<String> {
+            let dir = self.tmp.path().join(nickname).join("shared");
+            let mut names: Vec<String> = fs::read_dir(dir).unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        }
+
+        fn read(&self, nickname: &str, name: &str) -> String {
+            fs::read_to_string(self.tmp.path().join(nickname).join("shared").join(name)).unwrap()
+        }
+    }
+
+    #[test]
+    fn union_groups_give_every_member_every_file() {
+        let mut drives = Drives::new([&["a.txt", "same.txt"], &["b.txt", "same.txt"], &["c.txt"]]);
+
+        assert_eq!(drives.sync(r#"name = "shared""#), 0);
+
+        for nickname in ["A", "B", "C"] {
+            assert_eq!(drives.files(nickname), ["a.txt", "b.txt", "c.txt", "same.txt"]);
+        }
+        // Files members have already aren't overwritten
+        assert_eq!(drives.read("B", "same.txt"), "same.txt from BB");
+    }
+
+    #[test]
+    fn mirror_groups_make_members_copies_of_the_primary() {
+        let mut drives = Drives::new([&["a.txt", "same.txt"], &["b.txt", "same.txt"], &[]]);
+
+        assert_eq!(drives.sync(r#"name = "shared"
+            mode = "mirror"
+            primary = "A""#), 0);
+
+        for nickname in ["A", "B", "C"] {
+            assert_eq!(drives.files(nickname), ["a.txt", "same.txt"]);
+        }
+        assert_eq!(drives.read("B", "same.txt"), "same.txt from A");
+    }
+
+    #[test]
+    fn hub_groups_spread_the_primarys_files_and_keep_the_members_own() {
+        let mut drives = Drives::new([&["a.txt"], &["b.txt"], &["c.txt"]]);
+
+        assert_eq!(drives.sync(r#"name = "shared"
+            mode = "hub"
+            primary = "A"
+            members = ["A", "B"]"#), 0);
+
+        assert_eq!(drives.files("A"), ["a.txt"]);
+        assert_eq!(drives.files("B"), ["a.txt", "b.txt"]);
+        assert_eq!(drives.files("C"), ["c.txt"]);
+    }
+
+    #[test]
+    fn groups_without_a_usable_primary_are_skipped() {
+        let mut drives = Drives::new([&["a.txt"], &["b.txt"], &[]]);
+
+        assert_eq!(drives.sync(r#"name = "shared"
+            mode = "mirror""#), 1);
+        assert_eq!(drives.sync(r#"name = "shared"
+            primary = "D""#), 1);
+
+        // A primary that failed to sync leaves the others alone
+        drives.dests[0].err = Some(DestError::MountError);
+        assert_eq!(drives.sync(r#"name = "shared"
+            mode = "mirror"
+            primary = "A""#), 0);
+        assert_eq!(drives.files("B"), ["b.txt"]);
+    }
+}
@@ -0,0 +1,107 @@
+//! Local log of past sync runs
+
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::output::Status;
+use crate::paths;
+use crate::sync::SyncStats;
+
+const HISTORY_FILE: &str = "history.jsonl";
+
+/// What happened to one drive during one sync run.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RunRecord {
+    pub started_at: DateTime<Local>,
+    pub finished_at: DateTime<Local>,
+    pub drive: String,
+    pub status: Status,
+
+    #[serde(flatten)]
+    pub stats: SyncStats,
+}
+
+fn history_path() -> PathBuf {
+    paths::data_dir().join(HISTORY_FILE)
+}
+
+/// Append records for a finished run to the history log.
+pub fn append(records: &[RunRecord]) -> Result<()> {
+    let path = history_path();
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+
+    for record in records.iter() {
+        writeln!(file, "{}", serde_json::to_string(record)?)?;
+    }
+
+    Ok(())
+}
+
+/// Read every recorded run, oldest first. Unreadable lines are skipped.
+pub fn read() -> Result<Vec<RunRecord>> {
+    let path = history_path();
+
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+        }
+    };
+
+    Ok(content.lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// When a drive last finished a sync without errors.
+pub fn last_synced(runs: &[RunRecord], drive: &str) -> Option<DateTime<Local>> {
+    runs.iter()
+        .filter(|r| r.drive == drive && r.status == Status::Ok)
+        .map(|r| r.finished_at)
+        .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn run(drive: &str, status: Status, days_ago: i64) -> RunRecord {
+        let at = Local::now() - Duration::days(days_ago);
+        RunRecord {
+            started_at: at,
+            finished_at: at,
+            drive: drive.to_string(),
+            status,
+            stats: SyncStats::default(),
+        }
+    }
+
+    #[test]
+    fn last_synced_is_the_latest_successful_run_of_the_drive() {
+        let runs = [
+            run("USB", Status::Ok, 3),
+            run("USB", Status::Ok, 2),
+            run("USB", Status::Error, 1),
+            run("Backup", Status::Ok, 0),
+        ];
+
+        assert_eq!(last_synced(&runs, "USB"), Some(runs[1].finished_at));
+        assert_eq!(last_synced(&runs, "Other"), None);
+    }
+}
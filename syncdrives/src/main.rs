@@ -1,13 +1,44 @@
 //! Drive Syncer
 
-use anyhow::{bail, Result};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use anyhow::{bail, Context, Result};
+use chrono::Local;
 use clap::{self, Parser, Subcommand};
+use mime::Mime;
+use tokio::sync::Semaphore;
 
+mod auth;
+mod backend;
 mod config;
+mod download;
+mod filter;
 mod gdrive;
+mod groups;
+mod history;
+mod manifest;
+mod mimetype;
+mod output;
+mod paths;
+mod progress;
+mod remote;
+mod resumable;
+mod retry;
+mod status;
+mod sync;
+mod twoway;
 mod util;
 
-use config::Config;
+use backend::Connection;
+use config::{Config, Remote, RemoteKind};
+use download::Target;
+use gdrive::{AuthMethod, UploadOptions};
+use history::RunRecord;
+use manifest::Manifest;
+use output::{format_bytes, Event, OutputMode, Phase, Reporter, Status};
+use status::DriveStatus;
+use twoway::{ConflictPolicy, Resolution};
 use util::{DestError, DriveInfo};
 
 #[derive(Parser)]
@@ -20,6 +51,18 @@ struct Cli {
     /// Path of TOML config file
     #[arg(short, long = "config", value_name = "FILE")]
     config_file: Option<String>,
+
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = OutputMode::Human)]
+    output: OutputMode,
+
+    /// How to sign in to Google Drive, instead of the configured way
+    #[arg(long, value_enum, value_name = "METHOD")]
+    auth: Option<AuthMethod>,
+
+    /// Where to cache Google Drive sign-ins, instead of the configured place
+    #[arg(long, value_name = "FILE")]
+    token_cache: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -42,13 +85,202 @@ enum Commands {
         /// Perform dry-run sync only
         #[arg(short, long)]
         dry_run: bool,
+
+        /// Leave drives mounted after syncing
+        #[arg(long)]
+        no_unmount: bool,
+
+        /// Also copy changes made on the drives back to local
+        #[arg(long)]
+        two_way: bool,
+
+        /// How to settle files changed both locally and on a drive
+        #[arg(long, value_enum, value_name = "POLICY")]
+        conflict_policy: Option<ConflictPolicy>,
+
+        /// Sync at most this many drives with local at once. A drive
+        /// that fails doesn't stop the others, whatever the number, but
+        /// fails the command once they're done.
+        #[arg(short, long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+        jobs: Option<u32>,
+    },
+
+    /// Show the health of each configured drive without syncing
+    Status {
+        /// System username
+        #[arg(short, long)]
+        user: String,
+    },
+
+    /// List past sync runs per drive
+    History {
+        /// Only show runs of the drive with this nickname
+        #[arg(short = 'n', long, value_name = "NICKNAME")]
+        drive_nickname: Option<String>,
+
+        /// Show at most this many of the latest runs per drive
+        #[arg(short, long, default_value_t = 10)]
+        limit: usize,
+    },
+
+    /// Download a file or folder from Google Drive
+    Download {
+        /// Google Drive ID of the file or folder
+        #[arg(long, conflicts_with = "name")]
+        id: Option<String>,
+
+        /// Name of a file or folder in the configured Drive folder, or
+        /// its path with `--remote`. Without `--id` or `--name` the whole
+        /// folder is downloaded.
+        #[arg(short, long)]
+        name: Option<String>,
+
+        /// Download from this configured remote
+        #[arg(short, long, value_name = "NAME", conflicts_with = "id")]
+        remote: Option<String>,
+
+        /// Local path to download to
+        #[arg(short, long, default_value = ".")]
+        dest: String,
+
+        /// Google Drive API client secrets file
+        #[arg(short, long, value_name = "FILE")]
+        secrets_file: Option<String>,
+    },
+
+    /// Work with files on Google Drive
+    Gdrive {
+        #[command(subcommand)]
+        command: GdriveCommands,
+    },
+
+    /// Manage the Google Drive sign-in
+    Auth {
+        #[command(subcommand)]
+        command: AuthCommands,
+    },
+
+    /// Inspect what's stored on remotes
+    Remote {
+        #[command(subcommand)]
+        command: RemoteCommands,
     },
 
-    /// Upload single file to Google Drive
+    /// Upload files or directories to a remote
     Upload {
-        /// Local path of file to upload
+        /// Files or directories to upload, or glob patterns matching them
+        #[arg(value_name = "PATH", required_unless_present_any = ["file", "dir"])]
+        paths: Vec<String>,
+
+        /// Local path of a file to upload
+        #[arg(short, long)]
+        file: Vec<String>,
+
+        /// Local directory to upload along with its folder structure
+        #[arg(short, long)]
+        dir: Vec<String>,
+
+        /// Configured remote to upload to, by default the first one
+        #[arg(short, long, value_name = "NAME")]
+        remote: Option<String>,
+
+        /// Folder on the remote to upload into
+        #[arg(long, value_name = "PATH")]
+        to: Option<String>,
+
+        /// MIME type to upload with instead of detecting it. SFTP
+        /// remotes don't store one.
+        #[arg(long = "mime", value_name = "TYPE")]
+        mime_type: Option<Mime>,
+
+        /// Convert office and CSV files to Google Docs formats
+        #[arg(long)]
+        convert: bool,
+
+        /// Upload at most this many files at once
+        #[arg(short, long, value_name = "N", default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+        jobs: u32,
+
+        /// Google Drive API client secrets file
+        #[arg(short, long, value_name = "FILE")]
+        secrets_file: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+#[command(rename_all = "kebab-case")]
+enum GdriveCommands {
+    /// Upload new and changed files in a directory, skipping unchanged ones
+    Sync {
+        /// Local directory to sync
         #[arg(short, long)]
-        file: String,
+        dir: String,
+
+        /// Trash files on Google Drive that no longer exist locally
+        #[arg(long)]
+        delete: bool,
+
+        /// Only show what would be uploaded, updated or trashed
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Google Drive API client secrets file
+        #[arg(short, long, value_name = "FILE")]
+        secrets_file: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+#[command(rename_all = "kebab-case")]
+enum AuthCommands {
+    /// Show which account Google Drive is signed in as
+    Status {
+        /// Google Drive API client secrets file
+        #[arg(short, long, value_name = "FILE")]
+        secrets_file: Option<String>,
+    },
+
+    /// Revoke and delete the cached sign-in
+    Logout,
+}
+
+#[derive(Subcommand)]
+#[command(rename_all = "kebab-case")]
+enum RemoteCommands {
+    /// List the contents of a folder on a remote
+    Ls {
+        /// Folder to list, by default the remote's root
+        path: Option<String>,
+
+        /// Configured remote to list, by default the first one
+        #[arg(long, value_name = "NAME")]
+        remote: Option<String>,
+
+        /// Google Drive folder to list instead of a configured remote
+        #[arg(long, value_name = "ID", conflicts_with_all = ["remote", "path"])]
+        folder_id: Option<String>,
+
+        /// Also list the contents of subfolders
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// Show size, modification time and md5 too
+        #[arg(short, long)]
+        long: bool,
+
+        /// Google Drive API client secrets file
+        #[arg(short, long, value_name = "FILE")]
+        secrets_file: Option<String>,
+    },
+
+    /// Delete a file from a remote. Google Drive files go to the trash.
+    Rm {
+        /// Path of the file on the remote
+        path: String,
+
+        /// Configured remote to delete from, by default the first one
+        #[arg(long, value_name = "NAME")]
+        remote: Option<String>,
 
         /// Google Drive API client secrets file
         #[arg(short, long, value_name = "FILE")]
@@ -59,126 +291,641 @@ enum Commands {
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
+    let out = Reporter::new(cli.output);
+
+    let result = run(cli.command, cli.config_file, cli.auth, cli.token_cache, &out).await;
+
+    if let Err(e) = &result {
+        if !out.is_human() {
+            out.error(None, None, format!("{:#}", e));
+        }
+    }
+
+    result
+}
+
+async fn run(
+    command: Commands,
+    config_file: Option<String>,
+    auth_method: Option<AuthMethod>,
+    token_cache: Option<String>,
+    out: &Reporter,
+) -> Result<()> {
+    // History is kept locally and doesn't need the config
+    if let Commands::History { drive_nickname, limit } = command {
+        return show_history(drive_nickname, limit, out);
+    }
 
     // Get info from config file
-    let cfg = config::get_config(cli.config_file)?;
+    let mut cfg = config::get_config(config_file)?;
+    if let Some(method) = auth_method {
+        cfg.gd_auth = method;
+    }
+    if token_cache.is_some() {
+        cfg.gd_token_cache = token_cache;
+    }
+    let token_cache = auth::token_cache_path(cfg.gd_token_cache.as_deref());
 
-    match cli.command {
+    match command {
         Commands::Sync {
             user,
             drive_letter,
             drive_nickname,
             dry_run,
+            no_unmount,
+            two_way,
+            conflict_policy,
+            jobs,
         } => {
-            sync_drives(&cfg, user, drive_letter, drive_nickname, dry_run)?;
+            cfg.two_way |= two_way;
+            if let Some(policy) = conflict_policy {
+                cfg.conflict_policy = policy;
+            }
+
+            let dests = destinations(&cfg, &user, drive_letter, drive_nickname)?;
+
+            // Two-way syncs write to the same local directories
+            let jobs = match jobs {
+                _ if cfg.two_way => 1,
+                Some(jobs) => jobs as usize,
+                None => dests.len(),
+            };
+
+            sync_drives(cfg, dests, user, dry_run, no_unmount, jobs, out).await?;
+        }
+        Commands::Status { user } => {
+            show_status(&cfg, user.as_str(), out);
         }
-        Commands::Upload { file, secrets_file } => {
-            if let Some(folder_id) = cfg.gd_folder_id {
-                let hub = gdrive::get_drivehub(secrets_file).await?;
+        Commands::History { .. } => unreachable!(),
+        Commands::Download { id, name, remote, dest, secrets_file } => {
+            if let Some(remote) = remote {
+                let remote = cfg.get_remote(Some(&remote))?;
+                let backend = backend::open(&remote, Connection {
+                    cfg: &cfg,
+                    secrets_file,
+                    token_cache: &token_cache,
+                    upload_opts: UploadOptions::default(),
+                    out: out.clone(),
+                }).await?;
 
-                gdrive::upload_file_to_drive(
+                let path = name.unwrap_or_default();
+                backend::download(backend.as_ref(), &path, Path::new(&dest), out).await?;
+                return Ok(());
+            }
+
+            let target = match (id, name, cfg.gd_folder_id.clone()) {
+                (Some(id), _, _) => Target::Id(id),
+                (None, Some(name), Some(folder_id)) => Target::Name(name, folder_id),
+                (None, None, Some(folder_id)) => Target::Id(folder_id),
+                (None, _, None) => bail!("No gd_folder_id specified in config"),
+            };
+            let hub = gdrive::get_drivehub(secrets_file, cfg.gd_auth, &token_cache, cfg.gd_retry, out).await?;
+
+            download::download(&hub, target, Path::new(&dest), out).await?;
+        }
+        Commands::Gdrive { command } => match command {
+            GdriveCommands::Sync { dir, delete, dry_run, secrets_file } => {
+                let Some(folder_id) = cfg.gd_folder_id else {
+                    bail!("No gd_folder_id specified in config");
+                };
+                let hub = gdrive::get_drivehub(secrets_file, cfg.gd_auth, &token_cache, cfg.gd_retry, out).await?;
+
+                gdrive::sync_dir_to_drive(
                     &hub,
-                    file.as_str(),
-                    Some(folder_id.as_str()),
+                    Path::new(&dir),
+                    folder_id.as_str(),
+                    delete,
+                    dry_run,
+                    out,
                 )
                 .await?;
-            } else {
-                bail!("No gd_folder_id specified in config");
             }
+        },
+        Commands::Auth { command } => match command {
+            AuthCommands::Status { secrets_file } => {
+                auth::status(secrets_file, cfg.gd_auth, &token_cache, cfg.gd_retry, out).await?;
+            }
+            AuthCommands::Logout => {
+                auth::logout(&token_cache, out).await?;
+            }
+        },
+        Commands::Remote { command } => match command {
+            RemoteCommands::Ls { path, remote, folder_id, recursive, long, secrets_file } => {
+                let remote = match folder_id {
+                    Some(folder_id) => Remote {
+                        name: "gdrive".to_string(),
+                        kind: RemoteKind::Gdrive { folder_id, secrets_file: None },
+                    },
+                    None => cfg.get_remote(remote.as_deref())?,
+                };
+                let backend = backend::open(&remote, Connection {
+                    cfg: &cfg,
+                    secrets_file,
+                    token_cache: &token_cache,
+                    upload_opts: UploadOptions::default(),
+                    out: out.clone(),
+                }).await?;
+
+                let entries = backend.list(path.as_deref().unwrap_or_default(), recursive).await?;
+                remote::show(entries, long, out);
+            }
+            RemoteCommands::Rm { path, remote, secrets_file } => {
+                let remote = cfg.get_remote(remote.as_deref())?;
+                let backend = backend::open(&remote, Connection {
+                    cfg: &cfg,
+                    secrets_file,
+                    token_cache: &token_cache,
+                    upload_opts: UploadOptions::default(),
+                    out: out.clone(),
+                }).await?;
+
+                backend.delete(&path).await?;
+                out.say(format!("Deleted '{}' from {}", path, remote.name));
+            }
+        },
+        Commands::Upload {
+            paths, file, dir, remote, to, mime_type, convert, jobs, secrets_file,
+        } => {
+            let locals = expand_globs(paths.iter().chain(file.iter()).chain(dir.iter()))?;
+
+            let remote = cfg.get_remote(remote.as_deref())?;
+            let backend = backend::open(&remote, Connection {
+                cfg: &cfg,
+                secrets_file,
+                token_cache: &token_cache,
+                upload_opts: UploadOptions { mime_type, convert },
+                out: out.clone(),
+            }).await?;
+
+            let to = to.unwrap_or_default();
+
+            backend::upload(backend.as_ref(), &locals, &to, jobs as usize, out).await?;
         }
     }
 
     Ok(())
 }
 
-/// Sync external drives with local and then sync between
-/// external drives if multiple specified.
-fn sync_drives(
+/// Paths matching glob patterns, in order. Paths without wildcards are
+/// kept as they are, whether or not they exist.
+fn expand_globs<'a>(patterns: impl Iterator<Item = &'a String>) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+
+    for pattern in patterns {
+        if !pattern.contains(['*', '?', '[']) {
+            paths.push(PathBuf::from(pattern));
+            continue;
+        }
+
+        let matches = glob::glob(pattern)
+            .with_context(|| format!("Invalid pattern '{}'", pattern))?
+            .collect::<Result<Vec<_>, _>>()?;
+        if matches.is_empty() {
+            bail!("Nothing matches '{}'", pattern);
+        }
+        paths.extend(matches);
+    }
+
+    Ok(paths)
+}
+
+/// The configured drives, plus the one given on the command line, with
+/// their destinations checked for `user`.
+fn destinations(
     cfg: &Config,
-    user: String,
+    user: &str,
     drive_letter: Option<String>,
     drive_nickname: Option<String>,
+) -> Result<Vec<DriveInfo>> {
+    let mut dests: Vec<DriveInfo> = cfg.drives.iter().map(DriveInfo::from_drive).collect();
+
+    if let Some(letter) = drive_letter {
+        // Add cli-specified drive to destinations
+        dests.push(DriveInfo::new(letter, drive_nickname));
+    }
+
+    // Loading the config checked destinations for a stand-in user
+    for dest in dests.iter() {
+        cfg.check_dests(&dest.nickname, user)?;
+    }
+
+    Ok(dests)
+}
+
+/// Sync external drives with local and then sync between
+/// external drives if multiple specified.
+async fn sync_drives(
+    cfg: Config,
+    dests: Vec<DriveInfo>,
+    user: String,
     dry_run: bool,
+    no_unmount: bool,
+    jobs: usize,
+    out: &Reporter,
 ) -> Result<()> {
+    for subdir in cfg.subdirs.iter() {
+        for nickname in subdir.drives.iter().flatten() {
+            if !dests.iter().any(|d| &d.nickname == nickname) {
+                bail!("Subdirectory {} has unknown drive `{}`", subdir.name, nickname);
+            }
+        }
+    }
+
     if dry_run {
-        println!("::: Dry-run sync :::");
+        out.say("::: Dry-run sync :::");
     }
 
-    let mut dests = Vec::new();
+    let cfg = Arc::new(cfg);
+    let started_at = Local::now();
+
+    out.say("::: Syncing drives with local :::");
+    let (mut dests, lost) = sync_all_with_local(&cfg, dests, &user, dry_run, jobs, out).await;
+    let cfg = cfg.as_ref();
 
-    for d in cfg.drives.iter() {
-        dests.push(DriveInfo::from_drive(&d));
+    // If multiple destinations specified, sync each group's directory
+    // between its member drives. Drives with errors sit these out.
+    let mut cross_errors = 0;
+    if dests.len() > 1 {
+        for group in cfg.get_groups().iter() {
+            out.say(format!(
+                "\n::: Syncing `{}` directories ({} group {}) :::",
+                group.get_dir()?, group.mode, group.name,
+            ));
+            cross_errors += groups::sync_group(group, cfg, &mut dests, dry_run, out);
+        }
     }
 
-    if let Some(letter) = drive_letter {
-        // Add cli-specified drive to destinations
-        dests.push(DriveInfo::new(letter, drive_nickname));
+    // Unmount whatever this run mounted, whether or not syncing succeeded
+    if !no_unmount {
+        for dest in dests.iter().filter(|d| d.needs_unmount()) {
+            if let Err(e) = util::unmount_drive(dest) {
+                out.error(Some(&dest.nickname), Some(Phase::Unmount), e);
+                out.emit(drive_event(&dest.nickname, Phase::Unmount, Status::Error));
+            } else {
+                out.say(format!("Unmounted {} from {}", dest.nickname, dest.mountpoint));
+                out.emit(drive_event(&dest.nickname, Phase::Unmount, Status::Ok));
+            }
+        }
     }
 
+    print_conflicts(&dests, out);
+
+    if !dry_run {
+        let finished_at = Local::now();
+        let mut records: Vec<RunRecord> = dests.iter()
+            .map(|d| RunRecord {
+                started_at,
+                finished_at,
+                drive: d.nickname.clone(),
+                status: if d.err.is_some() { Status::Error } else { Status::Ok },
+                stats: d.stats,
+            })
+            .collect();
+        records.extend(lost.iter().map(|nickname| RunRecord {
+            started_at,
+            finished_at,
+            drive: nickname.clone(),
+            status: Status::Error,
+            stats: Default::default(),
+        }));
+
+        if let Err(e) = history::append(&records) {
+            out.error(None, None, format!("{:#}", e));
+        }
+    }
+
+    let failed = dests.iter().filter(|d| d.err.is_some()).count() + lost.len();
+    let status = if failed == 0 && cross_errors == 0 {
+        Status::Ok
+    } else {
+        Status::Error
+    };
+
+    out.emit(Event::Finished {
+        status,
+        drives: dests.len() + lost.len(),
+        failed,
+        dry_run,
+    });
+
+    if failed > 0 || cross_errors > 0 {
+        bail!("{} drives and {} cross-drive syncs failed", failed, cross_errors);
+    }
+
+    Ok(())
+}
+
+/// Mount and sync each destination's drive with local, `jobs` at a time.
+/// Every drive gets synced even if others fail.
+async fn sync_all_with_local(
+    cfg: &Arc<Config>,
+    dests: Vec<DriveInfo>,
+    user: &str,
+    dry_run: bool,
+    jobs: usize,
+    out: &Reporter,
+) -> (Vec<DriveInfo>, Vec<String>) {
     let base_src_dir = format!("/home/{}", user);
-    let hidden_files: Vec<String> = cfg.hidden_files
-        .clone()
-        .unwrap_or(Vec::new());
-
-    // Iterate destinations and try to mount their drives and sync
-    // their directories with local ones
-    println!("::: Syncing drives with local :::");
-    for dest in dests.iter_mut() {
-        if let Err(e) = util::mount_drive(&dest) {
-            eprintln!("Error: {} - {}", dest.nickname, e);
+
+    // Drives synced side by side print their output once they're done,
+    // so that it doesn't get mixed up
+    let slots = Arc::new(Semaphore::new(jobs.max(1)));
+    let mut handles = Vec::new();
+
+    for mut dest in dests {
+        let nickname = dest.nickname.clone();
+        let (cfg, slots) = (Arc::clone(cfg), Arc::clone(&slots));
+        let (base_src_dir, user) = (base_src_dir.clone(), user.to_string());
+        let progress = out.clone();
+        let job_out = if jobs > 1 { out.buffered() } else { out.clone() };
+
+        handles.push((nickname, tokio::spawn(async move {
+            let _slot = slots.acquire_owned().await.ok();
+            if jobs > 1 {
+                progress.say(format!("Syncing {}...", dest.nickname));
+            }
+
+            let dest = tokio::task::spawn_blocking(move || {
+                let synced = panic::catch_unwind(AssertUnwindSafe(|| {
+                    sync_with_local(&mut dest, &cfg, &base_src_dir, &user, dry_run, &job_out)
+                }));
+
+                // The drive stays around to be unmounted and recorded
+                if synced.is_err() {
+                    dest.err = Some(DestError::SyncError);
+                    job_out.error(Some(&dest.nickname), Some(Phase::LocalSync), "Sync panicked");
+                    job_out.emit(drive_event(&dest.nickname, Phase::LocalSync, Status::Error));
+                }
+                job_out.flush();
+
+                dest
+            });
+
+            dest.await.expect("sync panics are caught")
+        })));
+    }
+
+    // A job lost outright takes its drive's info with it, but the other
+    // drives still get unmounted and recorded
+    let mut dests = Vec::new();
+    let mut lost = Vec::new();
+    for (nickname, handle) in handles {
+        match handle.await {
+            Ok(dest) => dests.push(dest),
+            Err(e) => {
+                out.error(Some(&nickname), Some(Phase::LocalSync), e);
+                out.emit(drive_event(&nickname, Phase::LocalSync, Status::Error));
+                lost.push(nickname);
+            }
+        }
+    }
+
+    (dests, lost)
+}
+
+/// Mount and sync a drive with local, recording any failure in its
+/// `err`.
+fn sync_with_local(
+    dest: &mut DriveInfo,
+    cfg: &Config,
+    base_src_dir: &str,
+    user: &str,
+    dry_run: bool,
+    out: &Reporter,
+) {
+    let nickname = dest.nickname.clone();
+
+    match util::mount_drive(dest) {
+        Ok(mounted) => dest.mounted = mounted,
+        Err(e) => {
+            out.error(Some(&nickname), Some(Phase::Mount), e);
+            out.emit(drive_event(&nickname, Phase::Mount, Status::Error));
             dest.err = Some(DestError::MountError);
-            continue;
+            return;
         }
+    }
+    out.emit(drive_event(&nickname, Phase::Mount, Status::Ok));
 
-        if let Err(e) = util::sync_dirs_with_local(
-            &dest,
-            base_src_dir.as_str(),
-            &cfg.subdirs,
-            &hidden_files,
-            user.as_str(),
-            dry_run,
-        ) {
+    let result = Manifest::load(&dest.base_dir, cfg.manifest_hashes)
+        .and_then(|mut manifest| {
+            let synced = util::sync_dirs_with_local(
+                dest,
+                cfg,
+                base_src_dir,
+                user,
+                dry_run,
+                &mut manifest,
+                out,
+            );
+
+            // Keep whatever was applied before any failure
+            if !dry_run {
+                if synced.is_ok() {
+                    manifest.synced_at = Some(Local::now());
+                }
+                manifest.save()?;
+            }
+
+            synced
+        });
+
+    match result {
+        Ok(stats) => dest.stats.add(&stats),
+        Err(e) => {
             dest.err = Some(DestError::SyncError);
-            eprintln!("Error: {} - {}", dest.nickname, e);
-            eprintln!("Aborting syncs with local...");
-            break;
+            out.error(Some(&nickname), Some(Phase::LocalSync), format!("{:#}", e));
+            out.emit(drive_event(&nickname, Phase::LocalSync, Status::Error));
+            return;
         }
     }
+    out.emit(drive_event(&nickname, Phase::LocalSync, Status::Ok));
+}
 
-    // If multiple destinations specified, iterate them again and
-    // try to sync their synced/ directories with each other
-    if dests.len() > 1 {
-        println!("\n::: Syncing between `synced` directories :::");
-        for src in dests.iter() {
-            if src.err.is_none() {
-                let src_sync_dir = format!("{}/synced/", src.base_dir);
-
-                for dest in dests.iter() {
-                    let dest_sync_dir = format!("{}/synced/", dest.base_dir);
-
-                    if dest_sync_dir != src_sync_dir {
-                        if let Some(e) = dest.err {
-                            println!(
-                                "Skipping {s} -> {d} sync due to {d} {err} error",
-                                s=src.nickname, d=dest.nickname, err=e.kind(),
-                            );
-                            continue;
-                        }
-
-                        if let Err(e) = util::sync_dir(
-                            src_sync_dir.as_str(),
-                            dest_sync_dir.as_str(),
-                            src.nickname.as_str(),
-                            dest.nickname.as_str(),
-                            dry_run,
-                        ) {
-                            eprintln!("Error: {}", e);
-                        }
-                    }
-                }
+/// List the conflicts found on each drive during two-way syncs.
+fn print_conflicts(dests: &[DriveInfo], out: &Reporter) {
+    for dest in dests.iter().filter(|d| !d.conflicts.is_empty()) {
+        out.say(format!("\n{} conflict(s) with {}:", dest.conflicts.len(), dest.nickname));
+
+        for conflict in dest.conflicts.iter() {
+            let kept = match conflict.resolution {
+                Resolution::Local => "local",
+                Resolution::Drive => "drive",
+                Resolution::Both => "both",
+            };
+            out.say(format!("  {:<6} {}", kept, conflict.path.display()));
+        }
+    }
+}
+
+fn drive_event(nickname: &str, phase: Phase, status: Status) -> Event {
+    Event::Drive {
+        drive: nickname.to_string(),
+        phase,
+        status,
+    }
+}
+
+/// Print the health of every configured drive.
+fn show_status(cfg: &Config, user: &str, out: &Reporter) {
+    let runs = history::read().unwrap_or_else(|e| {
+        out.error(None, None, format!("{:#}", e));
+        Vec::new()
+    });
+
+    for (i, d) in cfg.drives.iter().enumerate() {
+        let dest = DriveInfo::from_drive(d);
+        let last_synced = history::last_synced(&runs, &dest.nickname);
+        let status = match DriveStatus::check(&dest, cfg, user, last_synced) {
+            Ok(status) => status,
+            Err(e) => {
+                out.error(Some(&dest.nickname), None, format!("{:#}", e));
+                continue;
+            }
+        };
+
+        if out.is_human() {
+            if i > 0 {
+                println!();
             }
+            print_status(&status);
+        }
+        out.emit(Event::Status(status));
+    }
+}
+
+fn print_status(status: &DriveStatus) {
+    for line in status.lines() {
+        println!("{}", line);
+    }
+}
+
+/// Print the latest recorded sync runs of each drive.
+fn show_history(
+    drive_nickname: Option<String>,
+    limit: usize,
+    out: &Reporter,
+) -> Result<()> {
+    let mut runs = history::read()?;
+    if let Some(nickname) = &drive_nickname {
+        runs.retain(|r| &r.drive == nickname);
+    }
+
+    let mut drives: Vec<String> = runs.iter().map(|r| r.drive.clone()).collect();
+    drives.sort();
+    drives.dedup();
+
+    if drives.is_empty() {
+        out.say("No sync runs recorded");
+    }
+
+    for (i, drive) in drives.iter().enumerate() {
+        let (drive_runs, rest): (Vec<RunRecord>, Vec<RunRecord>) = runs
+            .into_iter()
+            .partition(|r| &r.drive == drive);
+        runs = rest;
+
+        if i > 0 {
+            out.say("");
+        }
+        out.say(drive);
+
+        let skip = drive_runs.len().saturating_sub(limit);
+        for run in drive_runs.into_iter().skip(skip) {
+            out.say(format!(
+                "  {}  {:<5}  {} created, {} updated, {} deleted, {} skipped ({})",
+                run.started_at.format("%Y-%m-%d %H:%M:%S"),
+                if run.status == Status::Ok { "ok" } else { "error" },
+                run.stats.created,
+                run.stats.updated,
+                run.stats.deleted,
+                run.stats.skipped,
+                format_bytes(run.stats.bytes),
+            ));
+            out.emit(Event::History(run));
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn expand(patterns: &[String]) -> Result<Vec<PathBuf>> {
+        expand_globs(patterns.iter())
+    }
+
+    #[test]
+    fn expands_globs_and_keeps_plain_paths() {
+        let tmp = TempDir::new().unwrap();
+        for name in ["a.txt", "b.txt", "c.md"] {
+            fs::write(tmp.path().join(name), "").unwrap();
+        }
+        let dir = tmp.path().display();
+
+        let paths = expand(&[format!("{}/*.txt", dir), format!("{}/missing.md", dir)]).unwrap();
+        assert_eq!(paths, [
+            tmp.path().join("a.txt"),
+            tmp.path().join("b.txt"),
+            tmp.path().join("missing.md"),
+        ]);
+
+        let paths = expand(&[format!("{}/[ac].*", dir)]).unwrap();
+        assert_eq!(paths, [tmp.path().join("a.txt"), tmp.path().join("c.md")]);
+    }
+
+    #[tokio::test]
+    async fn a_failed_sync_leaves_the_other_drives_going() {
+        for jobs in [1, 2] {
+            let tmp = TempDir::new().unwrap();
+            let (dests, lost) = sync_failing_first_drive(&tmp, jobs).await;
+
+            assert!(lost.is_empty());
+            assert_eq!(dests.len(), 2);
+            assert!(matches!(dests[0].err, Some(DestError::SyncError)), "jobs = {}", jobs);
+            assert!(dests[1].err.is_none(), "jobs = {}", jobs);
+            assert!(tmp.path().join("two/wsl/nobody/docs/a.txt").is_file(), "jobs = {}", jobs);
+        }
+    }
+
+    /// Sync drives `One`, which is missing a source directory, and `Two`.
+    async fn sync_failing_first_drive(tmp: &TempDir, jobs: usize) -> (Vec<DriveInfo>, Vec<String>) {
+        let root = tmp.path().strip_prefix("/").unwrap().display();
+        fs::create_dir(tmp.path().join("docs")).unwrap();
+        fs::write(tmp.path().join("docs/a.txt"), "a").unwrap();
+
+        // Drives are directories under `/`, which is always mounted
+        let cfg: Config = toml::from_str(&format!(r#"
+            subdirs = [
+                {{ name = "gone", source = "/{root}/gone", drives = ["One"] }},
+                {{ name = "docs", source = "/{root}/docs" }},
+            ]
+
+            [[drives]]
+            mount = {{ type = "mounted", path = "/" }}
+            nickname = "One"
+            base_dir = "{root}/one"
+
+            [[drives]]
+            mount = {{ type = "mounted", path = "/" }}
+            nickname = "Two"
+            base_dir = "{root}/two"
+        "#, root=root)).unwrap();
+        let dests = cfg.drives.iter().map(DriveInfo::from_drive).collect();
+        let quiet = Reporter::new(OutputMode::Human).buffered();
+
+        sync_all_with_local(&Arc::new(cfg), dests, "nobody", false, jobs, &quiet).await
+    }
+
+    #[test]
+    fn globs_must_match_something() {
+        let tmp = TempDir::new().unwrap();
+        let pattern = format!("{}/*.pdf", tmp.path().display());
+
+        let err = expand(std::slice::from_ref(&pattern)).unwrap_err();
+        assert_eq!(err.to_string(), format!("Nothing matches '{}'", pattern));
+    }
+}
@@ -0,0 +1,243 @@
+//! Per-drive manifest of the files Drive Syncer last left on a drive
+//!
+//! The manifest lives in the drive's base directory and records each
+//! synced file's size, mtime and (optionally) md5, keyed by its path
+//! relative to the base directory. It's the baseline for spotting files
+//! that were changed on the drive itself between syncs.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, Metadata};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+
+use crate::sync::{self, ActionKind, Reason, SyncPlan};
+
+/// Name of the directory in a drive's base directory where Drive Syncer
+/// keeps its own bookkeeping.
+pub const STATE_DIR: &str = ".syncdrives";
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FileRecord {
+    pub size: u64,
+
+    /// Modification time in whole seconds since the epoch
+    pub mtime: u64,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+}
+
+impl FileRecord {
+    pub fn from_metadata(meta: &Metadata) -> Self {
+        Self {
+            size: meta.len(),
+            mtime: sync::mtime_secs(meta),
+            md5: None,
+        }
+    }
+
+    /// Whether a file's current metadata still matches this record.
+    pub fn matches(&self, meta: &Metadata) -> bool {
+        self.size == meta.len() && self.mtime == sync::mtime_secs(meta)
+    }
+}
+
+/// How a file on the drive compares with the manifest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tracking {
+    /// Recorded and unchanged since
+    Unchanged,
+    /// Recorded but changed since
+    Modified,
+    /// Not recorded
+    Untracked,
+}
+
+/// Files on a drive that changed since the manifest was written.
+#[derive(Debug, Default)]
+pub struct ManifestDiff {
+    pub added: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+}
+
+impl ManifestDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.modified.is_empty() && self.removed.is_empty()
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Manifest {
+    /// When the drive was last synced successfully
+    pub synced_at: Option<DateTime<Local>>,
+
+    pub files: BTreeMap<PathBuf, FileRecord>,
+
+    #[serde(skip)]
+    base_dir: PathBuf,
+
+    #[serde(skip)]
+    with_hashes: bool,
+}
+
+impl Manifest {
+    /// Load the manifest from a drive's base directory, or start an
+    /// empty one if the drive has never been synced. New records get an
+    /// md5 if `with_hashes` is set.
+    pub fn load(base_dir: &str, with_hashes: bool) -> Result<Self> {
+        let path = manifest_path(base_dir);
+
+        let mut manifest: Manifest = match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).with_context(|| {
+                format!("Failed to parse manifest {}", path.display())
+            })?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Manifest::default(),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("Failed to read manifest {}", path.display())
+                });
+            }
+        };
+
+        manifest.base_dir = PathBuf::from(base_dir);
+        manifest.with_hashes = with_hashes;
+
+        Ok(manifest)
+    }
+
+    /// Write the manifest back to the drive.
+    pub fn save(&self) -> Result<()> {
+        let path = manifest_path(&self.base_dir.to_string_lossy());
+        let tmp_path = path.with_extension("json.tmp");
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?)
+            .and_then(|_| fs::rename(&tmp_path, &path))
+            .with_context(|| format!("Failed to write manifest {}", path.display()))
+    }
+
+    /// Whether there's a previous sync to compare against.
+    pub fn has_baseline(&self) -> bool {
+        self.synced_at.is_some()
+    }
+
+    /// Path of `dir` relative to the base directory.
+    pub fn relative(&self, dir: &Path) -> PathBuf {
+        dir.strip_prefix(&self.base_dir)
+            .map(|p| p.to_path_buf())
+            .unwrap_or_else(|_| dir.to_path_buf())
+    }
+
+    pub fn tracking(&self, rel_path: &Path, meta: &Metadata) -> Tracking {
+        match self.files.get(rel_path) {
+            Some(record) if record.matches(meta) => Tracking::Unchanged,
+            Some(_) => Tracking::Modified,
+            None => Tracking::Untracked,
+        }
+    }
+
+    /// Drop the records for a path and everything beneath it.
+    pub fn forget(&mut self, rel_path: &Path) {
+        self.files.retain(|p, _| !p.starts_with(rel_path));
+    }
+
+    /// Record a file's current state. An existing hash is reused when
+    /// the file hasn't changed.
+    pub fn record(&mut self, rel_path: PathBuf) -> Result<()> {
+        let path = self.base_dir.join(&rel_path);
+        let meta = fs::metadata(&path)?;
+        let mut record = FileRecord::from_metadata(&meta);
+
+        if self.with_hashes {
+            record.md5 = match self.files.get(&rel_path) {
+                Some(old) if old.matches(&meta) && old.md5.is_some() => old.md5.clone(),
+                _ => Some(file_md5(&path)?),
+            };
+        }
+
+        self.files.insert(rel_path, record);
+
+        Ok(())
+    }
+
+    /// Bring the records under a plan's destination up to date after the
+    /// plan has been applied.
+    ///
+    /// Files kept on the drive because they changed there are left as
+    /// they were, so they keep being protected from deletion.
+    pub fn update_from_plan(&mut self, plan: &SyncPlan) -> Result<()> {
+        let prefix = self.relative(&plan.dest_dir);
+
+        for action in plan.actions.iter() {
+            let rel_path = prefix.join(&action.path);
+
+            match (action.kind, action.reason) {
+                (ActionKind::Delete, _) => self.forget(&rel_path),
+                (_, Reason::NotRegular | Reason::NewerOnDest | Reason::ChangedOnDest) => (),
+                _ => {
+                    if self.base_dir.join(&rel_path).is_file() {
+                        self.record(rel_path)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compare the files under `dir` on the drive with the manifest.
+    pub fn diff(&self, dir: &Path) -> Result<ManifestDiff> {
+        let prefix = self.relative(dir);
+        let mut diff = ManifestDiff::default();
+        let mut seen = Vec::new();
+
+        if dir.is_dir() {
+            for (rel_path, meta) in sync::walk_files(dir)? {
+                let rel_path = prefix.join(rel_path);
+
+                match self.tracking(&rel_path, &meta) {
+                    Tracking::Unchanged => (),
+                    Tracking::Modified => diff.modified.push(rel_path.clone()),
+                    Tracking::Untracked => diff.added.push(rel_path.clone()),
+                }
+                seen.push(rel_path);
+            }
+        }
+
+        seen.sort();
+        for path in self.files.keys().filter(|p| p.starts_with(&prefix)) {
+            if seen.binary_search(path).is_err() {
+                diff.removed.push(path.clone());
+            }
+        }
+
+        Ok(diff)
+    }
+}
+
+fn manifest_path(base_dir: &str) -> PathBuf {
+    [base_dir, STATE_DIR, MANIFEST_FILE].iter().collect()
+}
+
+/// Hex md5 digest of a file's contents.
+pub fn file_md5(path: &Path) -> Result<String> {
+    let mut file = File::open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Md5::new();
+
+    io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -0,0 +1,51 @@
+//! MIME types of files uploaded to Google Drive
+
+use std::path::Path;
+use mime::Mime;
+
+const ZIP: &str = "application/zip";
+
+/// Detect a file's MIME type from its magic bytes and extension.
+///
+/// Magic bytes win, except that zip archives defer to the extension
+/// since office documents and the like are zip files underneath.
+pub fn detect(path: &Path) -> Mime {
+    let by_magic = infer::get_from_path(path)
+        .ok()
+        .flatten()
+        .and_then(|t| t.mime_type().parse::<Mime>().ok());
+    let by_extension = mime_guess::from_path(path).first();
+
+    match (by_magic, by_extension) {
+        (Some(magic), Some(ext)) if magic.essence_str() == ZIP => ext,
+        (Some(magic), _) => magic,
+        (None, Some(ext)) => ext,
+        (None, None) => mime::APPLICATION_OCTET_STREAM,
+    }
+}
+
+/// Google Docs format Drive can convert a file of this type to.
+pub fn google_type(mime_type: &Mime) -> Option<&'static str> {
+    let google_type = match mime_type.essence_str() {
+        "application/msword"
+        | "application/vnd.openxmlformats-officedocument.wordprocessingml.document"
+        | "application/vnd.oasis.opendocument.text"
+        | "application/rtf" => "application/vnd.google-apps.document",
+
+        "application/vnd.ms-excel"
+        | "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+        | "application/vnd.oasis.opendocument.spreadsheet"
+        | "text/csv"
+        | "text/tab-separated-values" => "application/vnd.google-apps.spreadsheet",
+
+        "application/vnd.ms-powerpoint"
+        | "application/vnd.openxmlformats-officedocument.presentationml.presentation"
+        | "application/vnd.oasis.opendocument.presentation" => {
+            "application/vnd.google-apps.presentation"
+        }
+
+        _ => return None,
+    };
+
+    Some(google_type)
+}
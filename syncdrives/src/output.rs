@@ -0,0 +1,436 @@
+//! Human-readable and machine-readable (NDJSON) output for Drive Syncer
+
+use std::fmt::Display;
+use std::io::{self, IsTerminal, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use clap::ValueEnum;
+use indicatif::MultiProgress;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::AuthStatus;
+use crate::history::RunRecord;
+use crate::progress::Progress;
+use crate::remote::RemoteEntry;
+use crate::status::DriveStatus;
+use crate::sync::{ActionKind, EntryKind, Reason, SyncAction, SyncPlan};
+use crate::twoway::Conflict;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputMode {
+    /// Plain text progress messages
+    #[default]
+    Human,
+
+    /// One JSON event per line
+    Json,
+}
+
+/// Stage of a command that an event belongs to.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    Mount,
+    LocalSync,
+    Pull,
+    CrossSync,
+    Unmount,
+    Upload,
+    DriveSync,
+    Download,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Error,
+    Skipped,
+}
+
+/// A single machine-readable progress event.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    /// A drive finished a phase
+    Drive {
+        drive: String,
+        phase: Phase,
+        status: Status,
+    },
+
+    /// A directory was synced from `source` to `drive`
+    Subdir {
+        drive: String,
+        source: String,
+        phase: Phase,
+        subdir: String,
+        src_dir: PathBuf,
+        dest_dir: PathBuf,
+        created: usize,
+        updated: usize,
+        deleted: usize,
+        skipped: usize,
+        bytes: u64,
+        dry_run: bool,
+        status: Status,
+    },
+
+    /// Files under `dir` changed on `drive` since its last sync
+    DriveChanges {
+        drive: String,
+        dir: PathBuf,
+        added: Vec<PathBuf>,
+        modified: Vec<PathBuf>,
+        removed: Vec<PathBuf>,
+    },
+
+    /// A file or directory was changed on `drive`
+    File {
+        drive: String,
+        phase: Phase,
+        path: PathBuf,
+        entry: EntryKind,
+        action: ActionKind,
+        reason: Reason,
+        bytes: u64,
+        dry_run: bool,
+    },
+
+    /// A file changed both locally and on `drive` in a two-way sync
+    Conflict {
+        drive: String,
+        dir: PathBuf,
+        #[serde(flatten)]
+        conflict: Conflict,
+        dry_run: bool,
+    },
+
+    /// A file was uploaded to a remote
+    Upload {
+        phase: Phase,
+        path: PathBuf,
+        name: String,
+        id: String,
+        mime_type: String,
+        bytes: u64,
+        status: Status,
+    },
+
+    /// Files were uploaded to a remote, some of which may have failed
+    UploadSummary {
+        uploaded: usize,
+        failed: usize,
+        bytes: u64,
+    },
+
+    /// A file was downloaded from Google Drive
+    Download {
+        phase: Phase,
+        path: PathBuf,
+        id: String,
+        bytes: u64,
+        status: Status,
+    },
+
+    /// A file on Google Drive was uploaded, updated or trashed by a sync
+    RemoteFile {
+        phase: Phase,
+        path: PathBuf,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        action: ActionKind,
+        bytes: u64,
+        dry_run: bool,
+    },
+
+    /// A directory was synced to Google Drive
+    DriveSync {
+        path: PathBuf,
+        uploaded: usize,
+        updated: usize,
+        unchanged: usize,
+        trashed: usize,
+        bytes: u64,
+        dry_run: bool,
+    },
+
+    /// A Google Drive folder was found or created for a local directory
+    Folder {
+        phase: Phase,
+        path: PathBuf,
+        name: String,
+        id: String,
+        created: bool,
+    },
+
+    /// A Google Drive call failed for a while and is about to be retried
+    Retry {
+        what: String,
+        attempt: u32,
+        max_attempts: u32,
+        delay_ms: u64,
+        reason: String,
+    },
+
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        drive: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        phase: Option<Phase>,
+        message: String,
+    },
+
+    /// Health of a drive
+    Status(DriveStatus),
+
+    /// A file or folder on Google Drive
+    RemoteEntry(RemoteEntry),
+
+    /// A past sync run of a drive
+    History(RunRecord),
+
+    /// Who Google Drive is signed in as
+    Auth(AuthStatus),
+
+    /// Cached Google Drive tokens were revoked and removed
+    Logout {
+        token_cache: PathBuf,
+        revoked: usize,
+        removed: bool,
+    },
+
+    /// The command finished
+    Finished {
+        status: Status,
+        drives: usize,
+        failed: usize,
+        dry_run: bool,
+    },
+}
+
+impl Event {
+    pub fn file(drive: &str, phase: Phase, action: &SyncAction, dry_run: bool) -> Self {
+        Event::File {
+            drive: drive.to_string(),
+            phase,
+            path: action.path.clone(),
+            entry: action.entry,
+            action: action.kind,
+            reason: action.reason,
+            bytes: action.size,
+            dry_run,
+        }
+    }
+
+    pub fn subdir(
+        drive: &str,
+        source: &str,
+        phase: Phase,
+        subdir: &str,
+        plan: &SyncPlan,
+        dry_run: bool,
+    ) -> Self {
+        Event::Subdir {
+            drive: drive.to_string(),
+            source: source.to_string(),
+            phase,
+            subdir: subdir.to_string(),
+            src_dir: plan.src_dir.clone(),
+            dest_dir: plan.dest_dir.clone(),
+            created: plan.count(ActionKind::Create),
+            updated: plan.count(ActionKind::Update),
+            deleted: plan.count(ActionKind::Delete),
+            skipped: plan.count(ActionKind::Skip),
+            bytes: plan.bytes_to_copy(),
+            dry_run,
+            status: Status::Ok,
+        }
+    }
+}
+
+/// Writes either human-readable messages or JSON events, depending on
+/// the selected output mode.
+#[derive(Clone, Debug)]
+pub struct Reporter {
+    mode: OutputMode,
+
+    /// Lines held back until `flush`, so that jobs running side by side
+    /// don't interleave their output
+    held: Option<Arc<Mutex<Vec<Line>>>>,
+
+    /// Where progress bars are drawn, if anywhere
+    bars: Option<MultiProgress>,
+}
+
+#[derive(Debug)]
+enum Line {
+    Out(String),
+    Err(String),
+}
+
+impl Reporter {
+    pub fn new(mode: OutputMode) -> Self {
+        // Progress bars would only get in the way of piped output
+        let bars = (mode == OutputMode::Human && io::stdout().is_terminal())
+            .then(MultiProgress::new);
+
+        Self { mode, held: None, bars }
+    }
+
+    /// Reporter in the same mode that holds back its output until
+    /// `flush` is called. Its progress bars are still drawn live.
+    pub fn buffered(&self) -> Self {
+        Self { held: Some(Arc::default()), ..self.clone() }
+    }
+
+    /// Progress bar for `files` files of `bytes` in all, hidden unless
+    /// output goes to a terminal.
+    pub fn progress(&self, prefix: impl Into<String>, files: usize, bytes: u64) -> Progress {
+        Progress::new(self.bars.as_ref(), prefix.into(), files, bytes)
+    }
+
+    pub fn is_human(&self) -> bool {
+        self.mode == OutputMode::Human
+    }
+
+    /// Print a human-readable message.
+    pub fn say(&self, msg: impl Display) {
+        if self.is_human() {
+            self.print(Line::Out(msg.to_string()));
+        }
+    }
+
+    /// Print a human-readable error, or emit it as an event.
+    pub fn error(&self, drive: Option<&str>, phase: Option<Phase>, err: impl Display) {
+        if self.is_human() {
+            match drive {
+                Some(d) => self.print(Line::Err(format!("Error: {} - {}", d, err))),
+                None => self.print(Line::Err(format!("Error: {}", err))),
+            }
+        } else {
+            self.emit(Event::Error {
+                drive: drive.map(|d| d.to_string()),
+                phase,
+                message: err.to_string(),
+            });
+        }
+    }
+
+    /// Emit a machine-readable event.
+    pub fn emit(&self, event: Event) {
+        if !self.is_human() {
+            match serde_json::to_string(&event) {
+                Ok(line) => self.print(Line::Out(line)),
+                Err(e) => self.print(Line::Err(format!("Failed to serialize event: {}", e))),
+            }
+        }
+    }
+
+    /// Print the lines held back so far, all in one go.
+    pub fn flush(&self) {
+        let Some(held) = &self.held else {
+            return;
+        };
+        let lines = std::mem::take(&mut *held.lock().unwrap());
+
+        self.suspend(|| {
+            // Hold both streams so that nothing gets printed in between
+            let (mut stdout, mut stderr) = (io::stdout().lock(), io::stderr().lock());
+            for line in lines {
+                let _ = match line {
+                    Line::Out(line) => writeln!(stdout, "{}", line),
+                    Line::Err(line) => writeln!(stderr, "{}", line),
+                };
+            }
+        });
+    }
+
+    fn print(&self, line: Line) {
+        match (&self.held, line) {
+            (Some(held), line) => held.lock().unwrap().push(line),
+            (None, Line::Out(line)) => self.suspend(|| println!("{}", line)),
+            (None, Line::Err(line)) => self.suspend(|| eprintln!("{}", line)),
+        }
+    }
+
+    /// Lines held back so far, prefixed with the stream they go to.
+    #[cfg(test)]
+    pub fn held(&self) -> Vec<String> {
+        self.held.as_ref().unwrap().lock().unwrap().iter()
+            .map(|line| match line {
+                Line::Out(line) => format!("out: {}", line),
+                Line::Err(line) => format!("err: {}", line),
+            })
+            .collect()
+    }
+
+    /// Run `f` with any progress bars out of the way, e.g. to print or
+    /// to ask something on the terminal.
+    pub fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        match &self.bars {
+            Some(bars) => bars.suspend(f),
+            None => f(),
+        }
+    }
+}
+
+/// Format a byte count with binary units, e.g. `1.5 GiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_mode_writes_one_event_per_line() {
+        let out = Reporter::new(OutputMode::Json).buffered();
+
+        out.say("Mounted USB");
+        out.emit(Event::Drive { drive: "USB".to_string(), phase: Phase::LocalSync, status: Status::Ok });
+        out.error(Some("USB"), Some(Phase::Unmount), "busy");
+
+        assert_eq!(out.held(), [
+            r#"out: {"event":"drive","drive":"USB","phase":"local-sync","status":"ok"}"#,
+            r#"out: {"event":"error","drive":"USB","phase":"unmount","message":"busy"}"#,
+        ]);
+    }
+
+    #[test]
+    fn human_mode_writes_messages_and_errors() {
+        let out = Reporter::new(OutputMode::Human).buffered();
+
+        out.say("Mounted USB");
+        out.emit(Event::Drive { drive: "USB".to_string(), phase: Phase::Mount, status: Status::Ok });
+        out.error(Some("USB"), None, "busy");
+        out.error(None, None, "no config");
+
+        assert_eq!(out.held(), [
+            "out: Mounted USB",
+            "err: Error: USB - busy",
+            "err: Error: no config",
+        ]);
+    }
+
+    #[test]
+    fn formats_bytes_with_binary_units() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+}
@@ -0,0 +1,11 @@
+//! Where Drive Syncer keeps its own files
+
+use std::path::PathBuf;
+
+/// Directory where Drive Syncer keeps local state such as the history,
+/// saved upload sessions and the Google Drive token cache.
+pub fn data_dir() -> PathBuf {
+    dirs::data_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("syncdrives")
+}
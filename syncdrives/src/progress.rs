@@ -0,0 +1,163 @@
+//! Live progress bars for copying and uploading files
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+const TEMPLATE: &str =
+    "{prefix:.bold} [{bar:25}] {msg} {bytes}/{total_bytes} {binary_bytes_per_sec} ETA {eta}";
+
+/// Progress of a set of files being copied or uploaded.
+pub struct Progress {
+    bar: ProgressBar,
+    files: usize,
+    done: AtomicUsize,
+}
+
+impl Progress {
+    /// Bar for `files` files of `bytes` in all, added to `bars` if given.
+    pub fn new(bars: Option<&MultiProgress>, prefix: String, files: usize, bytes: u64) -> Self {
+        let bar = match bars {
+            Some(bars) if files > 0 => {
+                let bar = bars.add(ProgressBar::new(bytes));
+                bar.set_style(ProgressStyle::with_template(TEMPLATE)
+                    .expect("valid progress template")
+                    .progress_chars("=> "));
+                bar.enable_steady_tick(Duration::from_millis(200));
+                bar
+            }
+            _ => ProgressBar::hidden(),
+        };
+        bar.set_prefix(prefix);
+
+        let progress = Self { bar, files, done: AtomicUsize::new(0) };
+        progress.show_files(None);
+        progress
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.bar.is_hidden()
+    }
+
+    /// Reader that counts what's read from it as done.
+    pub fn wrap_read<R: Read>(&self, reader: R) -> impl Read {
+        self.bar.wrap_read(reader)
+    }
+
+    /// Handle for counting bytes as they're sent.
+    pub fn counter(&self) -> ByteCounter {
+        ByteCounter(self.bar.clone())
+    }
+
+    /// Count a file as done, naming what's being worked on now if known.
+    pub fn file_done(&self, current: Option<&str>) {
+        self.done.fetch_add(1, Ordering::SeqCst);
+        self.show_files(current);
+    }
+
+    /// Name the file being worked on.
+    pub fn working_on(&self, current: &str) {
+        self.show_files(Some(current));
+    }
+
+    fn show_files(&self, current: Option<&str>) {
+        if self.is_hidden() {
+            return;
+        }
+
+        let counts = format!("{}/{} files", self.done.load(Ordering::SeqCst), self.files);
+        match current {
+            Some(name) => self.bar.set_message(format!("{} {}", counts, name)),
+            None => self.bar.set_message(counts),
+        }
+    }
+}
+
+/// Counts bytes towards a [`Progress`] as they're sent.
+#[derive(Clone)]
+pub struct ByteCounter(ProgressBar);
+
+impl Default for ByteCounter {
+    fn default() -> Self {
+        Self(ProgressBar::hidden())
+    }
+}
+
+impl ByteCounter {
+    pub fn add(&self, bytes: u64) {
+        self.0.inc(bytes);
+    }
+
+    /// Reader that counts what's read from it.
+    pub fn reader<R>(&self, inner: R) -> CountingReader<R> {
+        CountingReader { inner, counter: self.clone(), pos: 0, counted: 0 }
+    }
+}
+
+/// Reader counting the bytes read through it, minus any seeked back over.
+pub struct CountingReader<R> {
+    inner: R,
+    counter: ByteCounter,
+    pos: u64,
+
+    /// How far into the reader bytes have been counted
+    counted: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.pos += n as u64;
+
+        if self.pos > self.counted {
+            self.counter.add(self.pos - self.counted);
+            self.counted = self.pos;
+        }
+
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for CountingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.inner.seek(pos)?;
+
+        if self.pos < self.counted {
+            self.counter.0.dec(self.counted - self.pos);
+            self.counted = self.pos;
+        }
+
+        Ok(self.pos)
+    }
+}
+
+impl Drop for Progress {
+    /// Bars disappear once done, leaving the summary printed after them.
+    fn drop(&mut self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn counts_bytes_read_once_and_takes_back_rereads() {
+        let counter = ByteCounter::default();
+        let mut reader = counter.reader(Cursor::new(vec![0u8; 10]));
+        let mut buf = [0; 4];
+
+        reader.read_exact(&mut buf).unwrap();
+        reader.seek(SeekFrom::End(0)).unwrap();
+        assert_eq!(counter.0.position(), 4);
+
+        reader.seek(SeekFrom::Start(2)).unwrap();
+        assert_eq!(counter.0.position(), 2);
+
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        assert_eq!(counter.0.position(), 10);
+    }
+}
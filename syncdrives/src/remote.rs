@@ -0,0 +1,135 @@
+//! Listing what's stored on a remote
+
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use crate::output::{format_bytes, Event, Reporter};
+
+#[derive(Debug, Serialize)]
+pub struct RemoteEntry {
+    /// Path relative to the listed folder
+    pub path: PathBuf,
+
+    /// Drive file id, or the path on remotes addressed by path
+    pub id: String,
+    pub folder: bool,
+    pub mime_type: Option<String>,
+    pub size: Option<u64>,
+    pub modified: Option<DateTime<Local>>,
+    pub md5: Option<String>,
+}
+
+/// Print entries as a table, or emit them as events.
+pub fn show(entries: Vec<RemoteEntry>, long: bool, out: &Reporter) {
+    if out.is_human() {
+        for line in table(&entries, long) {
+            println!("{}", line);
+        }
+    }
+
+    for entry in entries {
+        out.emit(Event::RemoteEntry(entry));
+    }
+}
+
+fn table(entries: &[RemoteEntry], long: bool) -> Vec<String> {
+    if entries.is_empty() {
+        return vec!["No files".to_string()];
+    }
+
+    let rows: Vec<Vec<String>> = entries.iter()
+        .map(|e| {
+            let mut row = vec![display_name(&e.path, e.folder), e.id.clone()];
+
+            if long {
+                row.push(e.size.map(format_bytes).unwrap_or_else(|| "-".to_string()));
+                row.push(e.modified
+                    .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+                    .unwrap_or_else(|| "-".to_string()));
+                row.push(e.md5.clone().unwrap_or_else(|| "-".to_string()));
+            }
+            row
+        })
+        .collect();
+
+    let header: &[&str] = if long {
+        &["NAME", "ID", "SIZE", "MODIFIED", "MD5"]
+    } else {
+        &["NAME", "ID"]
+    };
+
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let format_row = |cells: Vec<&str>| {
+        let line: Vec<String> = cells.iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        line.join("  ").trim_end().to_string()
+    };
+
+    let mut lines = vec![format_row(header.to_vec())];
+    for row in rows.iter() {
+        lines.push(format_row(row.iter().map(|c| c.as_str()).collect()));
+    }
+    lines
+}
+
+fn display_name(path: &Path, folder: bool) -> String {
+    if folder {
+        format!("{}/", path.display())
+    } else {
+        path.display().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn entry(path: &str, folder: bool) -> RemoteEntry {
+        RemoteEntry {
+            path: PathBuf::from(path),
+            id: format!("id-{}", path.len()),
+            folder,
+            mime_type: None,
+            size: (!folder).then_some(2048),
+            modified: Some(Local.with_ymd_and_hms(2024, 5, 1, 12, 30, 0).unwrap()),
+            md5: (!folder).then(|| "abc".to_string()),
+        }
+    }
+
+    #[test]
+    fn short_tables_show_names_and_ids() {
+        let entries = [entry("photos", true), entry("photos/cat.jpg", false)];
+
+        assert_eq!(table(&entries, false), [
+            "NAME            ID",
+            "photos/         id-6",
+            "photos/cat.jpg  id-14",
+        ]);
+    }
+
+    #[test]
+    fn long_tables_add_size_time_and_md5() {
+        let entries = [entry("photos", true), entry("photos/cat.jpg", false)];
+
+        assert_eq!(table(&entries, true), [
+            "NAME            ID     SIZE     MODIFIED             MD5",
+            "photos/         id-6   -        2024-05-01 12:30:00  -",
+            "photos/cat.jpg  id-14  2.0 KiB  2024-05-01 12:30:00  abc",
+        ]);
+    }
+
+    #[test]
+    fn empty_listings_say_so() {
+        assert_eq!(table(&[], true), ["No files"]);
+    }
+}
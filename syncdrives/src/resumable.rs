@@ -0,0 +1,631 @@
+//! Resumable Google Drive uploads that carry on where they left off
+
+use std::collections::BTreeMap;
+use std::fs::{self, DirBuilder, Metadata, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Local};
+use google_drive3::api::{File, Scope};
+use google_drive3::client::{self, Delegate, Retry};
+use google_drive3::hyper::{self, header, Body, Method, Request, Response, StatusCode};
+use mime::Mime;
+use serde::{Deserialize, Serialize};
+
+use crate::gdrive::Hub;
+use crate::output::{format_bytes, Reporter};
+use crate::paths;
+use crate::progress::ByteCounter;
+use crate::sync;
+
+const SESSIONS_FILE: &str = "upload-sessions.json";
+const UPLOAD_URL: &str = "https://www.googleapis.com/upload/drive/v3/files";
+
+/// Files bigger than this are uploaded with the resumable protocol.
+pub const RESUMABLE_THRESHOLD: u64 = 5 * 1024 * 1024;
+
+/// Bytes sent per request. Drive wants a multiple of 256 KiB.
+const CHUNK_SIZE: usize = 32 * 256 * 1024;
+
+/// Drive forgets upload sessions after a week.
+const SESSION_LIFETIME_DAYS: i64 = 7;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct SavedSession {
+    url: String,
+
+    /// Size and mtime of the file when the session started
+    size: u64,
+    mtime: u64,
+
+    started_at: DateTime<Local>,
+}
+
+/// What Drive says about an upload session.
+#[derive(Debug)]
+pub enum Reply {
+    /// Drive holds this many bytes of the file and wants the rest
+    Incomplete(u64),
+
+    /// The upload is done, leaving this file
+    Complete(Box<File>),
+
+    /// Drive no longer knows the session
+    Expired,
+}
+
+/// Where a resumable upload goes.
+#[async_trait]
+pub trait UploadTarget: Send {
+    /// Start a session, returning its URI.
+    async fn start(&mut self) -> Result<String>;
+
+    /// Ask how much of a `size` byte upload Drive holds.
+    async fn query(&mut self, url: &str, size: u64) -> Result<Reply>;
+
+    /// Send a chunk starting `offset` bytes into the file.
+    async fn send(&mut self, url: &str, offset: u64, chunk: Vec<u8>, size: u64) -> Result<Reply>;
+}
+
+/// Upload of one file through a saved or new session.
+pub struct UploadSession {
+    key: String,
+    path: PathBuf,
+    size: u64,
+    mtime: u64,
+
+    /// Where sessions are saved
+    store: PathBuf,
+    chunk_size: usize,
+}
+
+impl UploadSession {
+    /// Session for uploading `path` into a Drive file or folder `target`.
+    pub fn new(target: &str, path: &Path, meta: &Metadata) -> Self {
+        let full_path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+
+        Self {
+            key: format!("{}:{}", target, full_path.display()),
+            path: path.to_path_buf(),
+            size: meta.len(),
+            mtime: sync::mtime_secs(meta),
+            store: paths::data_dir().join(SESSIONS_FILE),
+            chunk_size: CHUNK_SIZE,
+        }
+    }
+
+    /// Upload `file` in chunks, resuming a saved session if possible.
+    pub async fn upload<T: UploadTarget>(
+        &self,
+        target: &mut T,
+        file: &mut fs::File,
+        sent: &ByteCounter,
+        out: &Reporter,
+    ) -> Result<File> {
+        let mut resumed = None;
+
+        if let Some(saved) = self.saved() {
+            match target.query(&saved.url, self.size).await? {
+                Reply::Incomplete(offset) => {
+                    out.say(format!(
+                        "Resuming upload of {} at {}",
+                        self.path.display(), format_bytes(offset),
+                    ));
+                    sent.add(offset);
+                    resumed = Some((saved.url, offset));
+                }
+                Reply::Complete(done) => {
+                    sent.add(self.size);
+                    self.forget();
+                    return Ok(*done);
+                }
+                Reply::Expired => {
+                    out.say(format!("Restarting upload of {}", self.path.display()));
+                    self.forget();
+                }
+            }
+        }
+
+        let (url, mut offset) = match resumed {
+            Some(resumed) => resumed,
+            None => {
+                let url = target.start().await?;
+                self.save(&url, out);
+                (url, 0)
+            }
+        };
+
+        loop {
+            let len = (self.size - offset).min(self.chunk_size as u64) as usize;
+            let mut chunk = vec![0; len];
+            file.seek(SeekFrom::Start(offset))
+                .and_then(|_| file.read_exact(&mut chunk))
+                .with_context(|| format!("Failed to read {}", self.path.display()))?;
+
+            match target.send(&url, offset, chunk, self.size).await? {
+                Reply::Incomplete(committed) if committed > offset => {
+                    sent.add(committed - offset);
+                    offset = committed;
+                }
+                Reply::Incomplete(_) => bail!("Drive took none of the chunk at {} bytes", offset),
+                Reply::Complete(done) => {
+                    sent.add(self.size - offset);
+                    self.forget();
+                    return Ok(*done);
+                }
+                Reply::Expired => {
+                    self.forget();
+                    bail!("Drive dropped the upload session");
+                }
+            }
+        }
+    }
+
+    fn saved(&self) -> Option<SavedSession> {
+        let session = read_sessions(&self.store).ok()?.remove(&self.key)?;
+        let expires_at = session.started_at + Duration::days(SESSION_LIFETIME_DAYS);
+
+        if session.size == self.size && session.mtime == self.mtime && expires_at > Local::now() {
+            Some(session)
+        } else {
+            None
+        }
+    }
+
+    fn save(&self, url: &str, out: &Reporter) {
+        let result = update_sessions(&self.store, |sessions| {
+            sessions.insert(self.key.clone(), SavedSession {
+                url: url.to_string(),
+                size: self.size,
+                mtime: self.mtime,
+                started_at: Local::now(),
+            });
+            true
+        });
+
+        // A session that can't be saved only means a restart next time
+        if let Err(e) = result {
+            out.error(None, None, format!("Failed to save upload session: {:#}", e));
+        }
+    }
+
+    fn forget(&self) {
+        let _ = update_sessions(&self.store, |sessions| sessions.remove(&self.key).is_some());
+    }
+}
+
+/// Upload into a new or existing Drive file.
+pub struct DriveTarget<'a> {
+    hub: &'a Hub,
+    method: Method,
+    url: String,
+    metadata: File,
+    mime_type: Mime,
+    size: u64,
+
+    /// What the upload is, for retry messages
+    what: String,
+}
+
+impl<'a> DriveTarget<'a> {
+    /// Upload creating a file described by `metadata`.
+    pub fn create(hub: &'a Hub, metadata: File, mime_type: Mime, size: u64, what: String) -> Self {
+        Self {
+            hub,
+            method: Method::POST,
+            url: format!("{}?uploadType=resumable", UPLOAD_URL),
+            metadata,
+            mime_type,
+            size,
+            what,
+        }
+    }
+
+    /// Upload replacing the content of file `file_id`.
+    pub fn update(
+        hub: &'a Hub,
+        file_id: &str,
+        metadata: File,
+        mime_type: Mime,
+        size: u64,
+        what: String,
+    ) -> Self {
+        Self {
+            method: Method::PATCH,
+            url: format!("{}/{}?uploadType=resumable", UPLOAD_URL, file_id),
+            ..Self::create(hub, metadata, mime_type, size, what)
+        }
+    }
+
+    /// Make a request, retrying failures like other Drive calls. Each
+    /// request gets attempts of its own, so that a long upload isn't
+    /// given up over failures scattered across its chunks.
+    async fn request(
+        &self,
+        build: impl Fn(hyper::http::request::Builder) -> hyper::http::Result<Request<Body>> + Send,
+    ) -> Result<Response<Body>> {
+        let mut retry = self.hub.retrier(self.what.clone());
+
+        loop {
+            let token = self.hub.auth.get_token(&[Scope::Full.as_ref()]).await
+                .map_err(|e| anyhow!("Failed to get an access token: {}", e))?
+                .context("Not signed in to Google Drive")?;
+            let request = build(Request::builder().header(header::AUTHORIZATION, format!("Bearer {}", token)))?;
+
+            let response = match self.hub.client.request(request).await {
+                Ok(response) => response,
+                Err(e) => match retry.http_error(&e) {
+                    Retry::After(delay) => {
+                        tokio::time::sleep(delay).await;
+                        continue;
+                    }
+                    Retry::Abort => return Err(e.into()),
+                },
+            };
+
+            let status = response.status();
+            if status.is_success() || is_session_reply(status) {
+                return Ok(response);
+            }
+
+            let (parts, body) = response.into_parts();
+            let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+            let err = serde_json::from_slice(&body).ok();
+
+            match retry.http_failure(&Response::from_parts(parts, Body::empty()), err) {
+                Retry::After(delay) => tokio::time::sleep(delay).await,
+                Retry::Abort => bail!("{} {}", status, String::from_utf8_lossy(&body).trim()),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl UploadTarget for DriveTarget<'_> {
+    async fn start(&mut self) -> Result<String> {
+        let mut metadata = serde_json::to_value(&self.metadata)?;
+        client::remove_json_null_values(&mut metadata);
+        let metadata = metadata.to_string();
+
+        let (method, url) = (self.method.clone(), self.url.clone());
+        let (mime_type, size) = (self.mime_type.to_string(), self.size);
+
+        let response = self.request(|builder| {
+            builder.method(method.clone())
+                .uri(&url)
+                .header(header::CONTENT_TYPE, "application/json; charset=UTF-8")
+                .header("X-Upload-Content-Type", &mime_type)
+                .header("X-Upload-Content-Length", size)
+                .body(Body::from(metadata.clone()))
+        }).await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap_or_default();
+            bail!("{} {}", status, String::from_utf8_lossy(&body).trim());
+        }
+
+        response.headers()
+            .get(header::LOCATION)
+            .and_then(|url| url.to_str().ok())
+            .map(str::to_string)
+            .context("Drive didn't return an upload session")
+    }
+
+    async fn query(&mut self, url: &str, size: u64) -> Result<Reply> {
+        let response = self.request(|builder| {
+            builder.method(Method::PUT)
+                .uri(url)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", size))
+                .body(Body::empty())
+        }).await?;
+
+        reply(response).await
+    }
+
+    async fn send(&mut self, url: &str, offset: u64, chunk: Vec<u8>, size: u64) -> Result<Reply> {
+        let range = format!("bytes {}-{}/{}", offset, offset + chunk.len() as u64 - 1, size);
+
+        let response = self.request(|builder| {
+            builder.method(Method::PUT)
+                .uri(url)
+                .header(header::CONTENT_RANGE, &range)
+                .body(Body::from(chunk.clone()))
+        }).await?;
+
+        reply(response).await
+    }
+}
+
+/// Non-success statuses that describe the session rather than a failure.
+fn is_session_reply(status: StatusCode) -> bool {
+    status == StatusCode::PERMANENT_REDIRECT
+        || status == StatusCode::NOT_FOUND
+        || status == StatusCode::GONE
+}
+
+async fn reply(response: Response<Body>) -> Result<Reply> {
+    match response.status() {
+        StatusCode::PERMANENT_REDIRECT => Ok(Reply::Incomplete(committed(&response))),
+        StatusCode::NOT_FOUND | StatusCode::GONE => Ok(Reply::Expired),
+        _ => {
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+            let file = serde_json::from_slice(&body).context("Failed to parse Drive's reply")?;
+            Ok(Reply::Complete(Box::new(file)))
+        }
+    }
+}
+
+/// Bytes Drive holds, from a `Range: bytes=0-<last>` header.
+fn committed(response: &Response<Body>) -> u64 {
+    response.headers()
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| range.rsplit('-').next())
+        .and_then(|last| last.trim().parse::<u64>().ok())
+        .map_or(0, |last| last + 1)
+}
+
+fn read_sessions(path: &Path) -> Result<BTreeMap<String, SavedSession>> {
+    match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json)
+            .with_context(|| format!("Failed to parse {}", path.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Change the saved sessions under a lock, writing them back if asked.
+fn update_sessions(
+    path: &Path,
+    change: impl FnOnce(&mut BTreeMap<String, SavedSession>) -> bool,
+) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+
+    let lock_path = path.with_extension("json.lock");
+    let lock = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(&lock_path)
+        .and_then(|file| file.lock().map(|_| file))
+        .with_context(|| format!("Failed to lock {}", lock_path.display()))?;
+
+    let mut sessions = read_sessions(path)?;
+    let result = match change(&mut sessions) {
+        true => write_sessions(path, &sessions),
+        false => Ok(()),
+    };
+
+    drop(lock);
+    result
+}
+
+/// Save the sessions readable only by the current user.
+fn write_sessions(path: &Path, sessions: &BTreeMap<String, SavedSession>) -> Result<()> {
+    let tmp_path = path.with_extension("json.tmp");
+
+    // A leftover temporary file may have been created more permissive
+    match fs::remove_file(&tmp_path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("Failed to remove {}", tmp_path.display()));
+        }
+        _ => (),
+    }
+
+    let json = serde_json::to_string_pretty(sessions)?;
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .and_then(|mut file| file.write_all(json.as_bytes()))
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+    use crate::output::OutputMode;
+
+    const URL: &str = "https://upload.example/session";
+
+    /// Drive's side of an upload, failing after a number of chunks
+    #[derive(Default)]
+    struct FakeDrive {
+        received: Vec<u8>,
+        started: usize,
+        queried: usize,
+
+        /// Offsets of the chunks sent
+        sends: Vec<u64>,
+        fail_at_send: Option<usize>,
+        expired: bool,
+    }
+
+    #[async_trait]
+    impl UploadTarget for FakeDrive {
+        async fn start(&mut self) -> Result<String> {
+            self.started += 1;
+            self.received.clear();
+            self.expired = false;
+            Ok(URL.to_string())
+        }
+
+        async fn query(&mut self, url: &str, _size: u64) -> Result<Reply> {
+            assert_eq!(url, URL);
+            self.queried += 1;
+
+            if self.expired {
+                Ok(Reply::Expired)
+            } else {
+                Ok(Reply::Incomplete(self.received.len() as u64))
+            }
+        }
+
+        async fn send(&mut self, _url: &str, offset: u64, chunk: Vec<u8>, size: u64) -> Result<Reply> {
+            if self.fail_at_send == Some(self.sends.len()) {
+                self.fail_at_send = None;
+                bail!("connection reset");
+            }
+            self.sends.push(offset);
+
+            assert_eq!(offset, self.received.len() as u64);
+            self.received.extend(chunk);
+
+            if self.received.len() as u64 == size {
+                Ok(Reply::Complete(Box::new(File { id: Some("uploaded".to_string()), ..Default::default() })))
+            } else {
+                Ok(Reply::Incomplete(self.received.len() as u64))
+            }
+        }
+    }
+
+    struct Fixture {
+        _tmp: TempDir,
+        store: PathBuf,
+        path: PathBuf,
+        contents: Vec<u8>,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let tmp = TempDir::new().unwrap();
+            let path = tmp.path().join("big.bin");
+            let contents: Vec<u8> = (0..10u8).collect();
+            fs::write(&path, &contents).unwrap();
+
+            Self {
+                store: tmp.path().join("data").join(SESSIONS_FILE),
+                _tmp: tmp,
+                path,
+                contents,
+            }
+        }
+
+        fn session(&self) -> UploadSession {
+            let meta = fs::metadata(&self.path).unwrap();
+            UploadSession {
+                store: self.store.clone(),
+                chunk_size: 4,
+                ..UploadSession::new("folder", &self.path, &meta)
+            }
+        }
+
+        async fn upload(&self, drive: &mut FakeDrive) -> Result<File> {
+            let mut file = fs::File::open(&self.path).unwrap();
+            let out = Reporter::new(OutputMode::Human).buffered();
+
+            self.session().upload(drive, &mut file, &ByteCounter::default(), &out).await
+        }
+    }
+
+    #[tokio::test]
+    async fn resumes_at_the_offset_drive_committed() {
+        let fixture = Fixture::new();
+        let mut drive = FakeDrive { fail_at_send: Some(1), ..Default::default() };
+
+        assert!(fixture.upload(&mut drive).await.is_err());
+        assert_eq!(drive.sends, [0]);
+        assert!(fixture.session().saved().is_some());
+
+        let done = fixture.upload(&mut drive).await.unwrap();
+        assert_eq!(done.id.as_deref(), Some("uploaded"));
+        assert_eq!(drive.started, 1);
+        assert_eq!(drive.queried, 1);
+        assert_eq!(drive.sends, [0, 4, 8]);
+        assert_eq!(drive.received, fixture.contents);
+        assert!(fixture.session().saved().is_none());
+    }
+
+    #[tokio::test]
+    async fn restarts_expired_sessions_from_the_start() {
+        let fixture = Fixture::new();
+        let mut drive = FakeDrive { fail_at_send: Some(1), ..Default::default() };
+
+        assert!(fixture.upload(&mut drive).await.is_err());
+        drive.expired = true;
+        drive.sends.clear();
+
+        fixture.upload(&mut drive).await.unwrap();
+        assert_eq!(drive.started, 2);
+        assert_eq!(drive.sends, [0, 4, 8]);
+        assert_eq!(drive.received, fixture.contents);
+    }
+
+    #[tokio::test]
+    async fn changed_files_start_a_new_session() {
+        let fixture = Fixture::new();
+        let mut drive = FakeDrive { fail_at_send: Some(1), ..Default::default() };
+
+        assert!(fixture.upload(&mut drive).await.is_err());
+        fs::write(&fixture.path, b"different contents").unwrap();
+
+        fixture.upload(&mut drive).await.unwrap();
+        assert_eq!(drive.started, 2);
+        assert_eq!(drive.queried, 0);
+        assert_eq!(drive.received, b"different contents");
+    }
+
+    #[tokio::test]
+    async fn sessions_are_only_readable_by_the_user() {
+        let fixture = Fixture::new();
+        let mut drive = FakeDrive { fail_at_send: Some(0), ..Default::default() };
+
+        assert!(fixture.upload(&mut drive).await.is_err());
+        let mode = fs::metadata(&fixture.store).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn sessions_saved_side_by_side_are_all_kept() {
+        let tmp = TempDir::new().unwrap();
+        let store = tmp.path().join("sessions").join(SESSIONS_FILE);
+
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let store = &store;
+                scope.spawn(move || {
+                    update_sessions(store, |sessions| {
+                        sessions.insert(i.to_string(), SavedSession {
+                            url: format!("https://upload/{}", i),
+                            size: i,
+                            mtime: 0,
+                            started_at: Local::now(),
+                        });
+                        true
+                    })
+                    .unwrap();
+                });
+            }
+        });
+
+        assert_eq!(read_sessions(&store).unwrap().len(), 8);
+    }
+
+    #[test]
+    fn reads_committed_bytes_from_the_range_header() {
+        let response = |range: Option<&str>| {
+            let mut builder = Response::builder().status(StatusCode::PERMANENT_REDIRECT);
+            if let Some(range) = range {
+                builder = builder.header(header::RANGE, range);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        assert_eq!(committed(&response(Some("bytes=0-262143"))), 262_144);
+        assert_eq!(committed(&response(None)), 0);
+    }
+}
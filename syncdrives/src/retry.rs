@@ -0,0 +1,246 @@
+//! Retrying Google Drive calls that fail for a while
+
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use google_drive3::client::{Delegate, Retry};
+use google_drive3::hyper::{self, header::RETRY_AFTER, Body, Response, StatusCode};
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::output::{Event, Reporter};
+
+/// Reasons Drive gives for failures that go away by themselves
+const TRANSIENT_REASONS: [&str; 4] = [
+    "rateLimitExceeded",
+    "userRateLimitExceeded",
+    "backendError",
+    "internalError",
+];
+
+/// Reasons Drive gives for turning a call away before doing anything
+const RATE_LIMIT_REASONS: [&str; 2] = ["rateLimitExceeded", "userRateLimitExceeded"];
+
+/// How failed Drive calls are retried.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Attempts in all, including the first. 1 turns retrying off.
+    pub max_attempts: u32,
+
+    /// Delay before the first retry, doubled for each one after it
+    pub initial_delay_ms: u64,
+
+    /// Longest delay between attempts, unless Drive asks for longer
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_delay_ms: 1000,
+            max_delay_ms: 32_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Jittered exponential backoff before the given retry.
+    fn backoff(&self, retry: u32) -> Duration {
+        let delay = self.initial_delay_ms
+            .saturating_mul(1 << (retry - 1).min(20))
+            .min(self.max_delay_ms);
+        let jittered = rand::thread_rng().gen_range(delay / 2..=delay);
+
+        Duration::from_millis(jittered)
+    }
+}
+
+/// Delegate that retries one Drive call under a policy.
+pub struct Retrier {
+    policy: RetryPolicy,
+    out: Reporter,
+
+    /// What the call does, e.g. "list folder <id>"
+    what: String,
+    attempts: u32,
+
+    /// Whether repeating the call can't leave a duplicate
+    idempotent: bool,
+}
+
+impl Retrier {
+    pub fn new(policy: RetryPolicy, out: Reporter, what: impl Into<String>) -> Self {
+        Self { policy, out, what: what.into(), attempts: 1, idempotent: true }
+    }
+
+    /// Retrier for a call that mustn't be carried out twice.
+    pub fn for_create(policy: RetryPolicy, out: Reporter, what: impl Into<String>) -> Self {
+        Self { idempotent: false, ..Self::new(policy, out, what) }
+    }
+
+    fn retry(&mut self, retry_after: Option<Duration>, reason: String) -> Retry {
+        if self.attempts >= self.policy.max_attempts {
+            return Retry::Abort;
+        }
+
+        let delay = retry_after.unwrap_or_else(|| self.policy.backoff(self.attempts));
+        self.attempts += 1;
+
+        self.out.say(format!(
+            "Retrying {} in {:.1}s (attempt {} of {}): {}",
+            self.what, delay.as_secs_f64(), self.attempts, self.policy.max_attempts, reason,
+        ));
+        self.out.emit(Event::Retry {
+            what: self.what.clone(),
+            attempt: self.attempts,
+            max_attempts: self.policy.max_attempts,
+            delay_ms: delay.as_millis() as u64,
+            reason,
+        });
+
+        Retry::After(delay)
+    }
+}
+
+impl Delegate for Retrier {
+    fn http_error(&mut self, err: &hyper::Error) -> Retry {
+        // Only a failed connection is sure not to have reached Drive
+        if !self.idempotent && !err.is_connect() {
+            return Retry::Abort;
+        }
+
+        self.retry(None, err.to_string())
+    }
+
+    fn http_failure(&mut self, response: &Response<Body>, err: Option<serde_json::Value>) -> Retry {
+        let status = response.status();
+        let reason = err.as_ref().and_then(error_reason);
+
+        let rate_limited = status == StatusCode::TOO_MANY_REQUESTS
+            || reason.is_some_and(|r| RATE_LIMIT_REASONS.contains(&r));
+        let transient = rate_limited
+            || status.is_server_error()
+            || reason.is_some_and(|r| TRANSIENT_REASONS.contains(&r));
+
+        // A server error may come after the call was carried out
+        if !transient || (!self.idempotent && !rate_limited) {
+            return Retry::Abort;
+        }
+
+        let reason = match reason {
+            Some(reason) => format!("{} {}", status.as_u16(), reason),
+            None => status.to_string(),
+        };
+        self.retry(retry_after(response), reason)
+    }
+}
+
+/// First reason in a Drive error response, such as `rateLimitExceeded`.
+fn error_reason(err: &serde_json::Value) -> Option<&str> {
+    err.pointer("/error/errors/0/reason")?.as_str()
+}
+
+/// How long a `Retry-After` header asks to wait.
+fn retry_after(response: &Response<Body>) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let at = DateTime::parse_from_rfc2822(value).ok()?.with_timezone(&Utc);
+    (at - Utc::now()).to_std().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::OutputMode;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy { max_attempts: 3, initial_delay_ms: 10, max_delay_ms: 10 }
+    }
+
+    fn quiet() -> Reporter {
+        Reporter::new(OutputMode::Human).buffered()
+    }
+
+    fn failure(status: u16, reason: Option<&str>) -> (Response<Body>, Option<serde_json::Value>) {
+        let response = Response::builder().status(status).body(Body::empty()).unwrap();
+        let err = reason.map(|r| serde_json::json!({ "error": { "errors": [{ "reason": r }] } }));
+        (response, err)
+    }
+
+    fn retries(retrier: &mut Retrier, status: u16, reason: Option<&str>) -> bool {
+        let (response, err) = failure(status, reason);
+        matches!(retrier.http_failure(&response, err), Retry::After(_))
+    }
+
+    #[test]
+    fn retries_transient_failures_up_to_the_limit() {
+        let mut retrier = Retrier::new(policy(), quiet(), "test");
+
+        assert!(retries(&mut retrier, 503, None));
+        assert!(retries(&mut retrier, 403, Some("rateLimitExceeded")));
+        assert!(!retries(&mut retrier, 503, None));
+    }
+
+    #[test]
+    fn gives_up_on_lasting_failures() {
+        let mut retrier = Retrier::new(policy(), quiet(), "test");
+
+        assert!(!retries(&mut retrier, 404, None));
+        assert!(!retries(&mut retrier, 403, Some("insufficientPermissions")));
+    }
+
+    #[test]
+    fn creates_are_only_retried_when_turned_away() {
+        let mut retrier = Retrier::for_create(policy(), quiet(), "test");
+
+        assert!(!retries(&mut retrier, 500, None));
+        assert!(!retries(&mut retrier, 503, Some("backendError")));
+        assert!(retries(&mut retrier, 429, None));
+        assert!(retries(&mut retrier, 403, Some("userRateLimitExceeded")));
+    }
+
+    #[test]
+    fn honours_retry_after() {
+        let response = Response::builder()
+            .status(429)
+            .header(RETRY_AFTER, "7")
+            .body(Body::empty())
+            .unwrap();
+
+        assert_eq!(retry_after(&response), Some(Duration::from_secs(7)));
+    }
+
+    #[test]
+    fn honours_retry_after_dates() {
+        let at = |secs: i64| (Utc::now() + chrono::Duration::seconds(secs)).to_rfc2822();
+        let response = |value: &str| Response::builder()
+            .status(503)
+            .header(RETRY_AFTER, value)
+            .body(Body::empty())
+            .unwrap();
+
+        let wait = retry_after(&response(&at(60))).unwrap();
+        assert!(wait > Duration::from_secs(55) && wait <= Duration::from_secs(60), "{:?}", wait);
+
+        assert_eq!(retry_after(&response(&at(-60))), None);
+        assert_eq!(retry_after(&response("soon")), None);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_longest_delay() {
+        let policy = RetryPolicy { max_attempts: 50, initial_delay_ms: 100, max_delay_ms: 1000 };
+
+        for (retry, delay) in [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (40, 1000)] {
+            let backoff = policy.backoff(retry);
+            assert!(
+                backoff >= Duration::from_millis(delay / 2) && backoff <= Duration::from_millis(delay),
+                "retry {}: {:?}", retry, backoff,
+            );
+        }
+    }
+}
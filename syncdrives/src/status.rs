@@ -0,0 +1,235 @@
+//! Drive health reporting for Drive Syncer
+
+use std::path::Path;
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use nix::sys::statvfs::statvfs;
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::manifest::Manifest;
+use crate::output::format_bytes;
+use crate::util::{self, DriveInfo};
+
+#[derive(Debug, Serialize)]
+pub struct DriveStatus {
+    pub drive: String,
+    pub source: String,
+    pub mountpoint: String,
+    pub mountpoint_exists: bool,
+    pub mounted: bool,
+    pub total_bytes: Option<u64>,
+    pub free_bytes: Option<u64>,
+    pub base_dir_exists: bool,
+
+    /// Directories syncing writes to, checked when the base directory
+    /// exists
+    pub dirs: Vec<DirStatus>,
+    pub last_synced: Option<DateTime<Local>>,
+    pub tracked_files: Option<usize>,
+}
+
+/// A directory syncing writes to on a drive.
+#[derive(Debug, Serialize)]
+pub struct DirStatus {
+    /// Path under the drive's base directory
+    pub path: String,
+
+    /// What gets synced there, e.g. "subdirectory `docs`"
+    pub synced: String,
+    pub exists: bool,
+}
+
+impl DriveStatus {
+    /// Inspect a drive without mounting or changing anything.
+    pub fn check(
+        dest: &DriveInfo,
+        cfg: &Config,
+        user: &str,
+        last_synced: Option<DateTime<Local>>,
+    ) -> Result<Self> {
+        let mountpoint_exists = Path::new(&dest.mountpoint).is_dir();
+        let mounted = mountpoint_exists && util::is_mounted(&dest.mountpoint);
+
+        let (total_bytes, free_bytes) = if mounted {
+            match statvfs(dest.mountpoint.as_str()) {
+                Ok(stat) => {
+                    let frag = stat.fragment_size();
+                    (
+                        Some(stat.blocks() * frag),
+                        Some(stat.blocks_available() * frag),
+                    )
+                }
+                Err(_) => (None, None),
+            }
+        } else {
+            (None, None)
+        };
+
+        let base_dir_exists = mounted && Path::new(&dest.base_dir).is_dir();
+        let manifest = if base_dir_exists {
+            Manifest::load(&dest.base_dir, false).ok()
+        } else {
+            None
+        };
+
+        let dirs = match base_dir_exists {
+            true => cfg.drive_dirs(&dest.nickname, user)?
+                .into_iter()
+                .map(|(path, synced)| DirStatus {
+                    exists: Path::new(&dest.base_dir).join(&path).is_dir(),
+                    path,
+                    synced,
+                })
+                .collect(),
+            false => Vec::new(),
+        };
+
+        Ok(Self {
+            drive: dest.nickname.clone(),
+            source: dest.mount.source(),
+            mountpoint: dest.mountpoint.clone(),
+            mountpoint_exists,
+            mounted,
+            total_bytes,
+            free_bytes,
+            base_dir_exists,
+            dirs,
+            last_synced,
+            tracked_files: manifest.as_ref().map(|m| m.files.len()),
+        })
+    }
+
+    /// Human-readable report of the drive's health.
+    pub fn lines(&self) -> Vec<String> {
+        let yes_no = |b: bool| if b { "yes" } else { "no" };
+        let field = |label: &str, value: &str| format!("  {:<19}{}", label, value);
+
+        let mut lines = vec![
+            format!("{} ({} at {})", self.drive, self.source, self.mountpoint),
+            field("mountpoint exists:", yes_no(self.mountpoint_exists)),
+            field("mounted:", yes_no(self.mounted)),
+        ];
+
+        if let (Some(total), Some(free)) = (self.total_bytes, self.free_bytes) {
+            let space = format!("{} free of {}", format_bytes(free), format_bytes(total));
+            lines.push(field("space:", space.as_str()));
+        }
+
+        if self.mounted {
+            lines.push(field("base dir exists:", yes_no(self.base_dir_exists)));
+        }
+
+        if self.base_dir_exists {
+            for dir in self.dirs.iter() {
+                let exists = format!("{} ({})", yes_no(dir.exists), dir.synced);
+                lines.push(field(format!("{}/:", dir.path).as_str(), exists.as_str()));
+            }
+
+            let last_synced = self.last_synced
+                .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_else(|| "never".to_string());
+            lines.push(field("last synced:", last_synced.as_str()));
+
+            if let Some(count) = self.tracked_files {
+                lines.push(field("tracked files:", count.to_string().as_str()));
+            }
+        }
+
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use chrono::TimeZone;
+    use tempfile::TempDir;
+
+    use crate::config::Drive;
+
+    /// Drive on the root filesystem, which is always mounted, with its
+    /// base directory at `base_dir`.
+    fn drive(base_dir: &Path) -> DriveInfo {
+        let toml = format!(
+            "nickname = \"USB\"\nmount = {{ type = \"mounted\", path = \"/\" }}\nbase_dir = \"{}\"",
+            base_dir.display(),
+        );
+        DriveInfo::from_drive(&toml::from_str::<Drive>(&toml).unwrap())
+    }
+
+    fn config() -> Config {
+        toml::from_str(r#"
+            subdirs = [
+                "docs",
+                { name = "music", dest = "media/{drive}/{name}" },
+                { name = "work", drives = ["Other"] },
+            ]
+            hidden_files = [".bashrc"]
+            groups = [
+                { name = "photos", dir = "shared/photos" },
+                { name = "elsewhere", members = ["Other"] },
+            ]
+            drives = []
+        "#).unwrap()
+    }
+
+    #[test]
+    fn reports_space_and_configured_directories_of_mounted_drives() {
+        let tmp = TempDir::new().unwrap();
+        fs::create_dir_all(tmp.path().join("wsl/ann/docs")).unwrap();
+        fs::create_dir_all(tmp.path().join("shared/photos")).unwrap();
+        let last_synced = Local.with_ymd_and_hms(2024, 5, 1, 12, 30, 0).unwrap();
+
+        let status = DriveStatus::check(&drive(tmp.path()), &config(), "ann", Some(last_synced)).unwrap();
+
+        assert!(status.mountpoint_exists && status.mounted && status.base_dir_exists);
+        let (total, free) = (status.total_bytes.unwrap(), status.free_bytes.unwrap());
+        assert!(total > 0 && free <= total);
+        assert_eq!(status.tracked_files, Some(0));
+
+        let lines = status.lines();
+        assert_eq!(lines[0], "USB (/ at /)");
+        assert!(lines[3].starts_with("  space:") && lines[3].contains(" free of "), "{}", lines[3]);
+        assert_eq!(lines[4..], [
+            "  base dir exists:   yes",
+            "  wsl/ann/docs/:     yes (subdirectory `docs`)",
+            "  media/USB/music/:  no (subdirectory `music`)",
+            "  wsl/ann/:          yes (hidden files)",
+            "  shared/photos/:    yes (group `photos`)",
+            "  last synced:       2024-05-01 12:30:00",
+            "  tracked files:     0",
+        ]);
+    }
+
+    #[test]
+    fn reports_missing_base_directories() {
+        let tmp = TempDir::new().unwrap();
+
+        let status = DriveStatus::check(&drive(&tmp.path().join("gone")), &config(), "ann", None).unwrap();
+
+        assert!(status.mounted);
+        assert!(status.total_bytes.is_some() && status.free_bytes.is_some());
+        assert!(!status.base_dir_exists);
+        assert!(status.dirs.is_empty());
+        assert_eq!(status.tracked_files, None);
+
+        let lines = status.lines();
+        assert_eq!(lines.last().unwrap(), "  base dir exists:   no");
+        assert_eq!(lines.len(), 5);
+    }
+
+    #[test]
+    fn reports_drives_that_are_not_mounted() {
+        let tmp = TempDir::new().unwrap();
+        let toml = format!("mount = {{ type = \"mounted\", path = \"{}\" }}", tmp.path().display());
+        let dest = DriveInfo::from_drive(&toml::from_str::<Drive>(&toml).unwrap());
+
+        let status = DriveStatus::check(&dest, &config(), "ann", None).unwrap();
+
+        assert!(status.mountpoint_exists && !status.mounted);
+        assert_eq!((status.total_bytes, status.free_bytes), (None, None));
+        assert_eq!(status.lines()[1..], ["  mountpoint exists: yes", "  mounted:           no"]);
+    }
+}
@@ -0,0 +1,1089 @@
+//! Native directory syncing for Drive Syncer
+//!
+//! Every sync is first worked out as a [`SyncPlan`] listing what would
+//! happen to each path; a dry-run prints the plan and a real run applies it.
+
+use std::fmt;
+use std::fs::{self, File, FileTimes, Metadata};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::filter::Filter;
+use crate::manifest::file_md5;
+use crate::progress::Progress;
+
+/// Options controlling how a source tree is synced to a destination.
+#[derive(Clone, Copy, Debug)]
+pub struct SyncOptions {
+    /// Preserve permissions and modification times (rsync `-a`)
+    pub archive: bool,
+
+    /// Skip files that are newer on the destination (rsync `--update`)
+    pub update: bool,
+
+    /// Delete destination entries missing from the source (rsync `--delete`)
+    pub delete: bool,
+
+    /// Skip files that already exist on the destination
+    /// (rsync `--ignore-existing`)
+    pub ignore_existing: bool,
+
+    /// Also delete destination files that changed since the last sync
+    pub delete_changed: bool,
+
+    /// Compare files of the same size by md5 rather than modification
+    /// time (rsync `--checksum`)
+    pub checksum: bool,
+}
+
+impl SyncOptions {
+    /// Options for syncing local directories to a drive
+    /// (`-a --update --delete --no-links`).
+    pub fn local() -> Self {
+        Self {
+            archive: true,
+            update: true,
+            delete: true,
+            ignore_existing: false,
+            delete_changed: false,
+            checksum: false,
+        }
+    }
+
+    /// Options for syncing between drives
+    /// (`--recursive --ignore-existing`).
+    pub fn cross_drive() -> Self {
+        Self {
+            archive: false,
+            update: false,
+            delete: false,
+            ignore_existing: true,
+            delete_changed: false,
+            checksum: false,
+        }
+    }
+
+    /// Options for making the destination an exact copy of the source
+    /// (`-a --delete`).
+    pub fn mirror() -> Self {
+        Self {
+            archive: true,
+            update: false,
+            delete: true,
+            ignore_existing: false,
+            delete_changed: false,
+            checksum: false,
+        }
+    }
+
+    /// Options for plainly copying files over whatever exists (`cp`).
+    pub fn overwrite() -> Self {
+        Self {
+            archive: false,
+            update: false,
+            delete: false,
+            ignore_existing: false,
+            delete_changed: false,
+            checksum: false,
+        }
+    }
+
+    /// Options for each direction of a two-way sync, whose actions are
+    /// worked out against the manifest rather than from these flags.
+    pub fn two_way() -> Self {
+        Self {
+            archive: true,
+            update: false,
+            delete: false,
+            ignore_existing: false,
+            delete_changed: false,
+            checksum: false,
+        }
+    }
+}
+
+/// Running totals of the actions taken across several plans.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct SyncStats {
+    pub created: usize,
+    pub updated: usize,
+    pub deleted: usize,
+    pub skipped: usize,
+    pub bytes: u64,
+
+    /// Files changed both locally and on the drive
+    #[serde(default)]
+    pub conflicts: usize,
+}
+
+impl SyncStats {
+    pub fn add_plan(&mut self, plan: &SyncPlan) {
+        self.created += plan.count(ActionKind::Create);
+        self.updated += plan.count(ActionKind::Update);
+        self.deleted += plan.count(ActionKind::Delete);
+        self.skipped += plan.count(ActionKind::Skip);
+        self.bytes += plan.bytes_to_copy();
+    }
+
+    pub fn add(&mut self, other: &SyncStats) {
+        self.created += other.created;
+        self.updated += other.updated;
+        self.deleted += other.deleted;
+        self.skipped += other.skipped;
+        self.bytes += other.bytes;
+        self.conflicts += other.conflicts;
+    }
+}
+
+// PLAN
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ActionKind {
+    Create,
+    Update,
+    Delete,
+    Skip,
+}
+
+impl fmt::Display for ActionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self {
+            ActionKind::Create => "create",
+            ActionKind::Update => "update",
+            ActionKind::Delete => "delete",
+            ActionKind::Skip => "skip",
+        };
+        f.pad(kind)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EntryKind {
+    File,
+    Dir,
+    Other,
+}
+
+/// Why an action was chosen for a path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Reason {
+    /// Path doesn't exist on the destination
+    Missing,
+    /// File sizes differ
+    SizeChanged,
+    /// File sizes match but modification times differ
+    MtimeChanged,
+    /// Destination has a different kind of entry at this path
+    KindChanged,
+    /// Destination path doesn't exist in the source
+    NotInSource,
+    /// Destination file is newer than the source
+    NewerOnDest,
+    /// Destination already has this path
+    AlreadyExists,
+    /// File sizes match but contents differ
+    ContentChanged,
+    /// Size and modification time, or contents, match
+    Unchanged,
+    /// Source entry is a symlink or other non-regular file
+    NotRegular,
+    /// Destination entry changed since the last sync
+    ChangedOnDest,
+    /// Source entry was deleted since the last sync
+    DeletedInSource,
+    /// Outcome of a conflicting change on both sides
+    Conflict,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            Reason::Missing => "missing on destination",
+            Reason::SizeChanged => "size changed",
+            Reason::MtimeChanged => "modification time changed",
+            Reason::ContentChanged => "content changed",
+            Reason::KindChanged => "entry kind changed",
+            Reason::NotInSource => "not in source",
+            Reason::NewerOnDest => "newer on destination",
+            Reason::AlreadyExists => "already exists on destination",
+            Reason::Unchanged => "unchanged",
+            Reason::NotRegular => "not a regular file",
+            Reason::ChangedOnDest => "changed on destination since last sync",
+            Reason::DeletedInSource => "deleted in source since last sync",
+            Reason::Conflict => "conflict resolution",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// A single change a sync would make, relative to the synced directories.
+#[derive(Clone, Debug)]
+pub struct SyncAction {
+    pub kind: ActionKind,
+    pub path: PathBuf,
+    pub entry: EntryKind,
+    pub size: u64,
+    pub reason: Reason,
+}
+
+impl SyncAction {
+    fn new(
+        kind: ActionKind,
+        path: PathBuf,
+        meta: &Metadata,
+        reason: Reason,
+    ) -> Self {
+        let entry = if meta.is_dir() {
+            EntryKind::Dir
+        } else if meta.is_file() {
+            EntryKind::File
+        } else {
+            EntryKind::Other
+        };
+        let size = if meta.is_file() { meta.len() } else { 0 };
+
+        Self { kind, path, entry, size, reason }
+    }
+
+    /// Action on a single file.
+    pub fn file(kind: ActionKind, path: PathBuf, size: u64, reason: Reason) -> Self {
+        Self { kind, path, entry: EntryKind::File, size, reason }
+    }
+
+    /// Whether the action changes anything on the destination.
+    pub fn is_change(&self) -> bool {
+        self.kind != ActionKind::Skip
+    }
+}
+
+impl fmt::Display for SyncAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let slash = if self.entry == EntryKind::Dir { "/" } else { "" };
+        write!(
+            f, "{:<6} {}{} ({})",
+            self.kind, self.path.display(), slash, self.reason,
+        )
+    }
+}
+
+/// Every action needed to sync one directory with another.
+#[derive(Clone, Debug)]
+pub struct SyncPlan {
+    pub src_dir: PathBuf,
+    pub dest_dir: PathBuf,
+    pub opts: SyncOptions,
+    pub actions: Vec<SyncAction>,
+}
+
+impl SyncPlan {
+    /// Work out how to sync the contents of `src_dir` into `dest_dir`
+    /// without touching either.
+    ///
+    /// Symbolic links and other non-regular files are skipped, and
+    /// excluded paths are left alone on both sides.
+    pub fn new(
+        src_dir: &Path,
+        dest_dir: &Path,
+        opts: &SyncOptions,
+        filter: &Filter,
+    ) -> Result<Self> {
+        if !src_dir.is_dir() {
+            bail!("Source directory `{}` does not exist", src_dir.display());
+        }
+
+        let mut plan = Self {
+            src_dir: src_dir.to_path_buf(),
+            dest_dir: dest_dir.to_path_buf(),
+            opts: *opts,
+            actions: Vec::new(),
+        };
+
+        plan.plan_dir(Path::new(""), dest_dir.is_dir(), &mut filter.clone())?;
+
+        Ok(plan)
+    }
+
+    /// Actions that change the destination.
+    pub fn changes(&self) -> impl Iterator<Item = &SyncAction> {
+        self.actions.iter().filter(|a| a.is_change())
+    }
+
+    pub fn count(&self, kind: ActionKind) -> usize {
+        self.actions.iter().filter(|a| a.kind == kind).count()
+    }
+
+    /// Number of files the plan would copy.
+    pub fn files_to_copy(&self) -> usize {
+        self.actions.iter()
+            .filter(|a| matches!(a.kind, ActionKind::Create | ActionKind::Update))
+            .filter(|a| a.entry != EntryKind::Dir)
+            .count()
+    }
+
+    /// Total size of the files the plan would copy.
+    pub fn bytes_to_copy(&self) -> u64 {
+        self.actions.iter()
+            .filter(|a| matches!(a.kind, ActionKind::Create | ActionKind::Update))
+            .map(|a| a.size)
+            .sum()
+    }
+
+    /// Work out how to copy the named files from `src_dir` into
+    /// `dest_dir`, ignoring everything else in either directory.
+    ///
+    /// Like `cp`, symlinks among the named files are followed.
+    pub fn for_files(
+        src_dir: &Path,
+        dest_dir: &Path,
+        names: &[String],
+        opts: &SyncOptions,
+    ) -> Result<Self> {
+        let mut plan = Self {
+            src_dir: src_dir.to_path_buf(),
+            dest_dir: dest_dir.to_path_buf(),
+            opts: *opts,
+            actions: Vec::new(),
+        };
+
+        let dest_exists = dest_dir.is_dir();
+        let mut filter = Filter::default();
+
+        for name in names.iter() {
+            let src_path = src_dir.join(name);
+            let src_meta = fs::metadata(&src_path).with_context(|| {
+                format!("Failed to read {}", src_path.display())
+            })?;
+
+            plan.plan_entry(PathBuf::from(name), &src_meta, dest_exists, &mut filter)?;
+        }
+
+        Ok(plan)
+    }
+
+    fn plan_dir(
+        &mut self,
+        rel_dir: &Path,
+        dest_exists: bool,
+        filter: &mut Filter,
+    ) -> Result<()> {
+        filter.read_ignore_file(&self.src_dir, rel_dir)?;
+
+        let src_entries: Vec<(String, Metadata)> = read_dir_sorted(&self.src_dir.join(rel_dir))?
+            .into_iter()
+            .filter(|(name, meta)| !filter.is_excluded(&rel_dir.join(name), meta.is_dir()))
+            .collect();
+
+        for (name, src_meta) in src_entries.iter() {
+            self.plan_entry(rel_dir.join(name), src_meta, dest_exists, filter)?;
+        }
+
+        if self.opts.delete && dest_exists {
+            self.plan_deletes(rel_dir, &src_entries, filter)?;
+        }
+
+        Ok(())
+    }
+
+    /// Delete what a destination directory has that the source doesn't,
+    /// except for excluded paths and the directories holding them.
+    fn plan_deletes(
+        &mut self,
+        rel_dir: &Path,
+        src_entries: &[(String, Metadata)],
+        filter: &Filter,
+    ) -> Result<()> {
+        let dest_entries = read_dir_sorted(&self.dest_dir.join(rel_dir))?;
+
+        for (name, dest_meta) in dest_entries.iter() {
+            let rel_path = rel_dir.join(name);
+
+            let in_src = src_entries.iter().any(|(n, m)| {
+                n == name && (m.is_dir() || m.is_file())
+            });
+            if in_src || filter.is_excluded(&rel_path, dest_meta.is_dir()) {
+                continue;
+            }
+
+            let holds_excluded = dest_meta.is_dir()
+                && walk_files(&self.dest_dir.join(&rel_path))?
+                    .iter()
+                    .any(|(p, _)| filter.is_excluded(&rel_path.join(p), false));
+
+            if holds_excluded {
+                self.plan_deletes(&rel_path, &[], filter)?;
+            } else {
+                self.push(ActionKind::Delete, rel_path, dest_meta, Reason::NotInSource);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn plan_entry(
+        &mut self,
+        rel_path: PathBuf,
+        src_meta: &Metadata,
+        dest_exists: bool,
+        filter: &mut Filter,
+    ) -> Result<()> {
+        let dest_meta = if dest_exists {
+            symlink_metadata(&self.dest_dir.join(&rel_path))?
+        } else {
+            None
+        };
+
+        if !src_meta.is_dir() && !src_meta.is_file() {
+            self.push(ActionKind::Skip, rel_path, src_meta, Reason::NotRegular);
+            return Ok(());
+        }
+
+        let kind_changed = dest_meta.as_ref().is_some_and(|m| {
+            m.is_dir() != src_meta.is_dir() || m.is_symlink()
+        });
+
+        if kind_changed {
+            if self.opts.ignore_existing {
+                self.push(ActionKind::Skip, rel_path, src_meta, Reason::AlreadyExists);
+                return Ok(());
+            }
+
+            let meta = dest_meta.as_ref().unwrap();
+            self.push(ActionKind::Delete, rel_path.clone(), meta, Reason::KindChanged);
+        }
+
+        let dest_meta = if kind_changed { None } else { dest_meta };
+
+        if src_meta.is_dir() {
+            let start = self.actions.len();
+            if dest_meta.is_none() {
+                self.push(ActionKind::Create, rel_path.clone(), src_meta, Reason::Missing);
+            }
+            self.plan_dir(&rel_path, dest_meta.is_some(), filter)?;
+
+            // Only directories leading to included files get created
+            let nothing_inside = self.actions.len() == start + 1;
+            if dest_meta.is_none() && nothing_inside && !filter.includes_all(&rel_path, true) {
+                self.actions.truncate(start);
+            }
+            return Ok(());
+        }
+
+        let (kind, reason) = match &dest_meta {
+            None => (ActionKind::Create, Reason::Missing),
+            Some(_) if self.opts.ignore_existing => {
+                (ActionKind::Skip, Reason::AlreadyExists)
+            }
+            Some(meta) => compare_files(
+                &self.src_dir.join(&rel_path),
+                src_meta,
+                &self.dest_dir.join(&rel_path),
+                meta,
+                &self.opts,
+            )?,
+        };
+        self.push(kind, rel_path, src_meta, reason);
+
+        Ok(())
+    }
+
+    /// Turn deletions of destination entries that `is_safe` rejects into
+    /// skips. A directory is only deleted if every file in it is safe.
+    pub fn keep_unsafe_deletes(
+        &mut self,
+        is_safe: impl Fn(&Path, &Metadata) -> bool,
+    ) -> Result<()> {
+        for action in self.actions.iter_mut() {
+            if action.kind != ActionKind::Delete || action.reason != Reason::NotInSource {
+                continue;
+            }
+
+            let dest_path = self.dest_dir.join(&action.path);
+            let safe = if action.entry == EntryKind::Dir {
+                walk_files(&dest_path)?
+                    .iter()
+                    .all(|(p, m)| is_safe(&action.path.join(p), m))
+            } else {
+                is_safe(&action.path, &fs::symlink_metadata(&dest_path)?)
+            };
+
+            if !safe {
+                action.kind = ActionKind::Skip;
+                action.reason = Reason::ChangedOnDest;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn push(
+        &mut self,
+        kind: ActionKind,
+        path: PathBuf,
+        meta: &Metadata,
+        reason: Reason,
+    ) {
+        self.actions.push(SyncAction::new(kind, path, meta, reason));
+    }
+
+    /// Carry out the plan's actions in order, counting copied files
+    /// towards `progress`.
+    pub fn apply(&self, progress: &Progress) -> Result<()> {
+        fs::create_dir_all(&self.dest_dir).with_context(|| {
+            format!("Failed to create {}", self.dest_dir.display())
+        })?;
+
+        let mut touched_dirs: Vec<PathBuf> = Vec::new();
+
+        for action in self.changes() {
+            let src_path = self.src_dir.join(&action.path);
+            let dest_path = self.dest_dir.join(&action.path);
+
+            match (action.kind, action.entry) {
+                (ActionKind::Delete, _) => remove_entry(&dest_path)?,
+                (_, EntryKind::Dir) => {
+                    fs::create_dir(&dest_path).with_context(|| {
+                        format!("Failed to create {}", dest_path.display())
+                    })?;
+                    touched_dirs.push(action.path.clone());
+                }
+                _ => {
+                    progress.working_on(&action.path.to_string_lossy());
+                    copy_file(&src_path, &dest_path, self.opts.archive, progress)?;
+                    progress.file_done(None);
+                }
+            }
+
+            if let Some(parent) = action.path.parent() {
+                touched_dirs.push(parent.to_path_buf());
+            }
+        }
+
+        if self.opts.archive {
+            // Restore directory times deepest first, since changing a
+            // directory's contents updates its mtime
+            touched_dirs.sort_by(|a, b| {
+                b.components().count().cmp(&a.components().count())
+                    .then_with(|| a.cmp(b))
+            });
+            touched_dirs.dedup();
+
+            for dir in touched_dirs.iter() {
+                let dest_dir = self.dest_dir.join(dir);
+
+                // Explicitly planned deletes may leave a directory behind
+                // that the source no longer has
+                let src_meta = fs::metadata(self.src_dir.join(dir));
+                if let (Ok(src_meta), true) = (src_meta, dest_dir.is_dir()) {
+                    copy_times(&src_meta, &dest_dir)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn compare_files(
+    src_path: &Path,
+    src_meta: &Metadata,
+    dest_path: &Path,
+    dest_meta: &Metadata,
+    opts: &SyncOptions,
+) -> Result<(ActionKind, Reason)> {
+    let src_mtime = mtime_secs(src_meta);
+    let dest_mtime = mtime_secs(dest_meta);
+
+    let compared = if opts.update && dest_mtime > src_mtime {
+        (ActionKind::Skip, Reason::NewerOnDest)
+    } else if src_meta.len() != dest_meta.len() {
+        (ActionKind::Update, Reason::SizeChanged)
+    } else if opts.checksum {
+        if file_md5(src_path)? != file_md5(dest_path)? {
+            (ActionKind::Update, Reason::ContentChanged)
+        } else {
+            (ActionKind::Skip, Reason::Unchanged)
+        }
+    } else if src_mtime != dest_mtime {
+        (ActionKind::Update, Reason::MtimeChanged)
+    } else {
+        (ActionKind::Skip, Reason::Unchanged)
+    };
+
+    Ok(compared)
+}
+
+// FILESYSTEM
+
+/// Copy a file via a temporary sibling so that an interrupted copy
+/// never leaves a truncated file at the destination.
+fn copy_file(
+    src_path: &Path,
+    dest_path: &Path,
+    keep_times: bool,
+    progress: &Progress,
+) -> Result<()> {
+    let tmp_path = temp_path(dest_path);
+
+    if let Some(parent) = dest_path.parent().filter(|p| !p.is_dir()) {
+        fs::create_dir_all(parent).with_context(|| {
+            format!("Failed to create {}", parent.display())
+        })?;
+    }
+
+    let copied = (|| {
+        if progress.is_hidden() {
+            fs::copy(src_path, &tmp_path)
+        } else {
+            copy_counted(src_path, &tmp_path, progress)
+        }
+        .with_context(|| format!("Failed to copy {}", src_path.display()))?;
+
+        if keep_times {
+            copy_times(&fs::metadata(src_path)?, &tmp_path)?;
+        }
+
+        fs::rename(&tmp_path, dest_path).with_context(|| {
+            format!("Failed to write {}", dest_path.display())
+        })
+    })();
+
+    // A leftover temporary file would look like an extra file next time
+    if copied.is_err() {
+        fs::remove_file(&tmp_path).ok();
+    }
+    copied
+}
+
+/// Copy a file's content and permissions like `fs::copy`, counting the
+/// bytes copied as they go.
+fn copy_counted(src_path: &Path, dest_path: &Path, progress: &Progress) -> io::Result<u64> {
+    let src = File::open(src_path)?;
+    let permissions = src.metadata()?.permissions();
+
+    let mut dest = File::create(dest_path)?;
+    let bytes = io::copy(&mut progress.wrap_read(src), &mut dest)?;
+    dest.set_permissions(permissions)?;
+
+    Ok(bytes)
+}
+
+fn copy_times(src_meta: &Metadata, dest_path: &Path) -> Result<()> {
+    let times = FileTimes::new()
+        .set_accessed(src_meta.accessed()?)
+        .set_modified(src_meta.modified()?);
+
+    File::options()
+        .write(dest_path.is_file())
+        .read(true)
+        .open(dest_path)
+        .and_then(|f| f.set_times(times))
+        .with_context(|| {
+            format!("Failed to set times on {}", dest_path.display())
+        })
+}
+
+fn remove_entry(path: &Path) -> Result<()> {
+    let removed = match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    };
+
+    removed.with_context(|| format!("Failed to delete {}", path.display()))
+}
+
+/// Sibling of `path` to write its new content to before renaming it
+/// into place.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    path.with_file_name(format!(".{}.syncdrives-tmp", name))
+}
+
+/// Modification time truncated to whole seconds, since filesystems
+/// like FAT and NTFS don't agree on finer resolutions.
+pub fn mtime_secs(meta: &Metadata) -> u64 {
+    meta.modified()
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn symlink_metadata(path: &Path) -> Result<Option<Metadata>> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) => Ok(Some(meta)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => bail!("Failed to read {}: {}", path.display(), e),
+    }
+}
+
+/// Every regular file beneath `dir`, relative to it, in path order.
+pub fn walk_files(dir: &Path) -> Result<Vec<(PathBuf, Metadata)>> {
+    let mut files = Vec::new();
+    walk_files_into(dir, Path::new(""), None, &mut files)?;
+    Ok(files)
+}
+
+/// Every regular file beneath `dir` that `filter` doesn't exclude.
+pub fn walk_filtered(dir: &Path, filter: &Filter) -> Result<Vec<(PathBuf, Metadata)>> {
+    let mut files = Vec::new();
+    walk_files_into(dir, Path::new(""), Some(&mut filter.clone()), &mut files)?;
+    Ok(files)
+}
+
+fn walk_files_into(
+    root: &Path,
+    rel_dir: &Path,
+    mut filter: Option<&mut Filter>,
+    files: &mut Vec<(PathBuf, Metadata)>,
+) -> Result<()> {
+    if let Some(filter) = filter.as_deref_mut() {
+        filter.read_ignore_file(root, rel_dir)?;
+    }
+
+    for (name, meta) in read_dir_sorted(&root.join(rel_dir))? {
+        let rel_path = rel_dir.join(name);
+
+        if filter.as_ref().is_some_and(|f| f.is_excluded(&rel_path, meta.is_dir())) {
+            continue;
+        }
+
+        if meta.is_dir() {
+            walk_files_into(root, &rel_path, filter.as_deref_mut(), files)?;
+        } else if meta.is_file() {
+            files.push((rel_path, meta));
+        }
+    }
+
+    Ok(())
+}
+
+/// Directory entries sorted by name, without following symlinks.
+fn read_dir_sorted(dir: &Path) -> Result<Vec<(String, Metadata)>> {
+    let mut entries = Vec::new();
+
+    let read_dir = fs::read_dir(dir).with_context(|| {
+        format!("Failed to read {}", dir.display())
+    })?;
+
+    for entry in read_dir {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        entries.push((name, entry.metadata()?));
+    }
+
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    /// Old enough that every test file written after it is newer
+    const OLD: u64 = 1_000_000_000;
+
+    fn write(path: &Path, contents: &str, mtime: u64) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+        set_mtime(path, mtime);
+    }
+
+    fn set_mtime(path: &Path, mtime: u64) {
+        File::options().write(true).open(path).unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(mtime))
+            .unwrap();
+    }
+
+    fn read(path: &Path) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    fn hidden() -> Progress {
+        Progress::new(None, String::new(), 0, 0)
+    }
+
+    /// Source and destination directories inside a temporary one.
+    fn dirs() -> (TempDir, PathBuf, PathBuf) {
+        let tmp = TempDir::new().unwrap();
+        let src = tmp.path().join("src");
+        let dest = tmp.path().join("dest");
+        fs::create_dir(&src).unwrap();
+        fs::create_dir(&dest).unwrap();
+        (tmp, src, dest)
+    }
+
+    fn plan_sync(src: &Path, dest: &Path, opts: SyncOptions) -> SyncPlan {
+        SyncPlan::new(src, dest, &opts, &Filter::default()).unwrap()
+    }
+
+    fn action<'a>(plan: &'a SyncPlan, path: &str) -> &'a SyncAction {
+        plan.actions.iter()
+            .find(|a| a.path == Path::new(path))
+            .unwrap_or_else(|| panic!("no action for {}", path))
+    }
+
+    #[test]
+    fn creates_updates_and_deletes() {
+        let (_tmp, src, dest) = dirs();
+        write(&src.join("new.txt"), "new", OLD);
+        write(&src.join("sub/changed.txt"), "longer now", OLD + 10);
+        write(&dest.join("sub/changed.txt"), "short", OLD);
+        write(&dest.join("gone.txt"), "gone", OLD);
+        write(&dest.join("gone/inside.txt"), "gone", OLD);
+
+        let plan = plan_sync(&src, &dest, SyncOptions::local());
+        plan.apply(&hidden()).unwrap();
+
+        assert_eq!(read(&dest.join("new.txt")), "new");
+        assert_eq!(read(&dest.join("sub/changed.txt")), "longer now");
+        assert!(!dest.join("gone.txt").exists());
+        assert!(!dest.join("gone").exists());
+
+        // Archive mode carries modification times over
+        let meta = fs::metadata(dest.join("sub/changed.txt")).unwrap();
+        assert_eq!(mtime_secs(&meta), OLD + 10);
+
+        // Nothing is left to do afterwards
+        let again = plan_sync(&src, &dest, SyncOptions::local());
+        assert_eq!(again.changes().count(), 0);
+    }
+
+    #[test]
+    fn cross_drive_ignores_existing_and_keeps_extra_files() {
+        let (_tmp, src, dest) = dirs();
+        write(&src.join("both.txt"), "from source", OLD + 10);
+        write(&src.join("only_src.txt"), "copied", OLD);
+        write(&dest.join("both.txt"), "from dest", OLD);
+        write(&dest.join("only_dest.txt"), "kept", OLD);
+
+        let plan = plan_sync(&src, &dest, SyncOptions::cross_drive());
+
+        let both = action(&plan, "both.txt");
+        assert_eq!((both.kind, both.reason), (ActionKind::Skip, Reason::AlreadyExists));
+        assert_eq!(plan.count(ActionKind::Delete), 0);
+
+        plan.apply(&hidden()).unwrap();
+
+        assert_eq!(read(&dest.join("both.txt")), "from dest");
+        assert_eq!(read(&dest.join("only_src.txt")), "copied");
+        assert_eq!(read(&dest.join("only_dest.txt")), "kept");
+    }
+
+    #[test]
+    fn update_keeps_newer_destination_files() {
+        let (_tmp, src, dest) = dirs();
+        write(&src.join("a.txt"), "older", OLD);
+        write(&dest.join("a.txt"), "newer edit", OLD + 10);
+
+        let plan = plan_sync(&src, &dest, SyncOptions::local());
+        let a = action(&plan, "a.txt");
+        assert_eq!((a.kind, a.reason), (ActionKind::Skip, Reason::NewerOnDest));
+
+        plan.apply(&hidden()).unwrap();
+        assert_eq!(read(&dest.join("a.txt")), "newer edit");
+
+        // Without --update the source wins
+        let mirror = plan_sync(&src, &dest, SyncOptions::mirror());
+        let a = action(&mirror, "a.txt");
+        assert_eq!((a.kind, a.reason), (ActionKind::Update, Reason::SizeChanged));
+    }
+
+    #[test]
+    fn compares_size_then_mtime_or_checksum() {
+        let (_tmp, src, dest) = dirs();
+        write(&src.join("size.txt"), "abc", OLD);
+        write(&dest.join("size.txt"), "abcd", OLD);
+        write(&src.join("mtime.txt"), "abc", OLD + 10);
+        write(&dest.join("mtime.txt"), "abc", OLD);
+        write(&src.join("content.txt"), "abc", OLD);
+        write(&dest.join("content.txt"), "xyz", OLD);
+        write(&src.join("same.txt"), "abc", OLD);
+        write(&dest.join("same.txt"), "abc", OLD);
+
+        let by_mtime = plan_sync(&src, &dest, SyncOptions::mirror());
+        let reasons = |plan: &SyncPlan| -> Vec<(String, ActionKind, Reason)> {
+            plan.actions.iter()
+                .map(|a| (a.path.display().to_string(), a.kind, a.reason))
+                .collect()
+        };
+
+        assert_eq!(reasons(&by_mtime), [
+            ("content.txt".to_string(), ActionKind::Skip, Reason::Unchanged),
+            ("mtime.txt".to_string(), ActionKind::Update, Reason::MtimeChanged),
+            ("same.txt".to_string(), ActionKind::Skip, Reason::Unchanged),
+            ("size.txt".to_string(), ActionKind::Update, Reason::SizeChanged),
+        ]);
+
+        let opts = SyncOptions { checksum: true, ..SyncOptions::mirror() };
+        let by_checksum = plan_sync(&src, &dest, opts);
+
+        assert_eq!(reasons(&by_checksum), [
+            ("content.txt".to_string(), ActionKind::Update, Reason::ContentChanged),
+            ("mtime.txt".to_string(), ActionKind::Skip, Reason::Unchanged),
+            ("same.txt".to_string(), ActionKind::Skip, Reason::Unchanged),
+            ("size.txt".to_string(), ActionKind::Update, Reason::SizeChanged),
+        ]);
+    }
+
+    #[test]
+    fn does_not_follow_symlinks() {
+        let (tmp, src, dest) = dirs();
+        let outside = tmp.path().join("outside");
+        write(&outside.join("secret.txt"), "outside", OLD);
+        write(&src.join("real.txt"), "real", OLD);
+        symlink(&outside, src.join("linked_dir")).unwrap();
+        symlink(src.join("real.txt"), src.join("linked_file")).unwrap();
+
+        let plan = plan_sync(&src, &dest, SyncOptions::local());
+
+        for name in ["linked_dir", "linked_file"] {
+            let link = action(&plan, name);
+            assert_eq!((link.kind, link.reason), (ActionKind::Skip, Reason::NotRegular));
+        }
+        // Nothing beneath the linked directory gets planned
+        assert_eq!(plan.actions.len(), 3);
+
+        plan.apply(&hidden()).unwrap();
+
+        assert!(dest.join("real.txt").is_file());
+        assert!(fs::symlink_metadata(dest.join("linked_dir")).is_err());
+        assert!(fs::symlink_metadata(dest.join("linked_file")).is_err());
+    }
+
+    #[test]
+    fn replaces_files_through_a_temporary_copy() {
+        let (tmp, src, dest) = dirs();
+        write(&src.join("a.txt"), "new contents", OLD + 10);
+        write(&dest.join("a.txt"), "old", OLD);
+
+        // A hard link shares the old file's inode, which a rename leaves
+        // alone but writing in place would change
+        let other_link = tmp.path().join("other_link");
+        fs::hard_link(dest.join("a.txt"), &other_link).unwrap();
+
+        plan_sync(&src, &dest, SyncOptions::mirror()).apply(&hidden()).unwrap();
+
+        assert_eq!(read(&dest.join("a.txt")), "new contents");
+        assert_eq!(read(&other_link), "old");
+        assert!(!temp_path(&dest.join("a.txt")).exists());
+    }
+
+    #[test]
+    fn failed_copy_leaves_destination_intact() {
+        let (_tmp, src, dest) = dirs();
+        write(&dest.join("a.txt"), "old", OLD);
+
+        let copied = copy_file(&src.join("missing.txt"), &dest.join("a.txt"), true, &hidden());
+
+        assert!(copied.is_err());
+        assert_eq!(read(&dest.join("a.txt")), "old");
+    }
+
+    #[test]
+    fn failed_copy_leaves_no_temporary_file() {
+        let (_tmp, src, dest) = dirs();
+        write(&src.join("a.txt"), "new", OLD);
+
+        // Renaming over a directory with files in it fails once the
+        // content is copied
+        write(&dest.join("a.txt/inner.txt"), "inner", OLD);
+        let copied = copy_file(&src.join("a.txt"), &dest.join("a.txt"), true, &hidden());
+
+        assert!(copied.is_err());
+        assert!(!temp_path(&dest.join("a.txt")).exists());
+        assert_eq!(read(&dest.join("a.txt/inner.txt")), "inner");
+    }
+
+    /// Every path beneath `dir` with its contents and modification time.
+    fn snapshot(dir: &Path) -> Vec<(PathBuf, String, u64)> {
+        walk_files(dir).unwrap().into_iter()
+            .map(|(p, m)| (p.clone(), read(&dir.join(&p)), mtime_secs(&m)))
+            .collect()
+    }
+
+    #[test]
+    fn dry_run_plans_actions_without_touching_destination() {
+        let (_tmp, src, dest) = dirs();
+        write(&src.join("docs/new.md"), "new", OLD);
+        write(&src.join("docs/same.md"), "same", OLD);
+        write(&src.join("notes.txt"), "edited notes", OLD + 10);
+        write(&dest.join("docs/same.md"), "same", OLD);
+        write(&dest.join("notes.txt"), "notes", OLD);
+        write(&dest.join("stale.txt"), "stale", OLD);
+        fs::create_dir(dest.join("empty")).unwrap();
+
+        let before = snapshot(&dest);
+        let plan = plan_sync(&src, &dest, SyncOptions::local());
+
+        let lines: Vec<String> = plan.actions.iter().map(|a| a.to_string()).collect();
+        assert_eq!(lines, [
+            "create docs/new.md (missing on destination)",
+            "skip   docs/same.md (unchanged)",
+            "update notes.txt (size changed)",
+            "delete empty/ (not in source)",
+            "delete stale.txt (not in source)",
+        ]);
+        assert_eq!(plan.files_to_copy(), 2);
+        assert_eq!(plan.bytes_to_copy(), 15);
+
+        assert_eq!(snapshot(&dest), before);
+        assert!(dest.join("empty").is_dir());
+    }
+
+    #[test]
+    fn plans_new_directories_before_their_contents() {
+        let (_tmp, src, dest) = dirs();
+        write(&src.join("a/b/c.txt"), "c", OLD);
+
+        let plan = plan_sync(&src, &dest.join("missing"), SyncOptions::local());
+
+        let planned: Vec<(ActionKind, EntryKind, String)> = plan.actions.iter()
+            .map(|a| (a.kind, a.entry, a.path.display().to_string()))
+            .collect();
+        assert_eq!(planned, [
+            (ActionKind::Create, EntryKind::Dir, "a".to_string()),
+            (ActionKind::Create, EntryKind::Dir, "a/b".to_string()),
+            (ActionKind::Create, EntryKind::File, "a/b/c.txt".to_string()),
+        ]);
+        assert!(!dest.join("missing").exists());
+    }
+
+    #[test]
+    fn for_files_plans_only_named_files() {
+        let (_tmp, src, dest) = dirs();
+        write(&src.join(".bashrc"), "bash", OLD);
+        write(&src.join(".profile"), "profile", OLD);
+        write(&src.join("other.txt"), "other", OLD);
+        write(&dest.join(".profile"), "profile", OLD);
+        write(&dest.join("unrelated.txt"), "unrelated", OLD);
+
+        let names = [".bashrc".to_string(), ".profile".to_string()];
+        let before = snapshot(&dest);
+        let plan = SyncPlan::for_files(&src, &dest, &names, &SyncOptions::overwrite()).unwrap();
+
+        let lines: Vec<String> = plan.actions.iter().map(|a| a.to_string()).collect();
+        assert_eq!(lines, [
+            "create .bashrc (missing on destination)",
+            "skip   .profile (unchanged)",
+        ]);
+        assert_eq!(snapshot(&dest), before);
+
+        let missing = [".missing".to_string()];
+        assert!(SyncPlan::for_files(&src, &dest, &missing, &SyncOptions::overwrite()).is_err());
+    }
+}
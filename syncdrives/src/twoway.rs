@@ -0,0 +1,616 @@
+//! Two-way syncing between a local directory and a drive
+//!
+//! Both sides are compared with the drive's manifest, which records the
+//! state the last sync left them in. A file changed on only one side is
+//! copied to (or deleted from) the other; a file changed on both sides
+//! is a conflict and gets settled by a [`ConflictPolicy`].
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, Metadata};
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::filter::Filter;
+use crate::manifest::{FileRecord, Manifest};
+use crate::output::Reporter;
+use crate::progress::Progress;
+use crate::sync::{self, ActionKind, Reason, SyncAction, SyncOptions, SyncPlan};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    /// Keep whichever side was modified last
+    #[default]
+    Newest,
+
+    /// Keep both, saving the drive's version under a conflict name
+    KeepBoth,
+
+    /// Ask which side to keep
+    Prompt,
+}
+
+/// Which version of a conflicting file was kept.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Resolution {
+    Local,
+    Drive,
+    Both,
+}
+
+/// A file changed on both sides since the last sync.
+#[derive(Clone, Debug, Serialize)]
+pub struct Conflict {
+    pub path: PathBuf,
+
+    /// Local version, if it wasn't deleted
+    pub local_version: Option<FileRecord>,
+
+    /// Drive version, if it wasn't deleted
+    pub drive_version: Option<FileRecord>,
+
+    pub resolution: Resolution,
+
+    /// Where the drive's version went when both were kept
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub renamed: Option<PathBuf>,
+}
+
+/// Changes to make in both directions to bring two directories together.
+#[derive(Debug)]
+pub struct TwoWayPlan {
+    /// Local to drive
+    pub push: SyncPlan,
+
+    /// Drive to local
+    pub pull: SyncPlan,
+
+    pub conflicts: Vec<Conflict>,
+
+    /// Paths left out on both sides
+    filter: Filter,
+}
+
+impl TwoWayPlan {
+    /// Work out how to sync `local_dir` and `drive_dir` with each other,
+    /// using the drive's manifest as the baseline. Paths the filter
+    /// excludes are left alone on both sides.
+    pub fn new(
+        local_dir: &Path,
+        drive_dir: &Path,
+        manifest: &Manifest,
+        policy: ConflictPolicy,
+        drive_nickname: &str,
+        filter: &Filter,
+        out: &Reporter,
+    ) -> Result<Self> {
+        if !local_dir.is_dir() {
+            bail!("Source directory `{}` does not exist", local_dir.display());
+        }
+
+        let local = files_by_path(local_dir, filter)?;
+        let drive = files_by_path(drive_dir, filter)?;
+
+        let prefix = manifest.relative(drive_dir);
+        let baseline: BTreeMap<PathBuf, &FileRecord> = manifest.files.iter()
+            .filter_map(|(p, r)| {
+                p.strip_prefix(&prefix).ok().map(|p| (p.to_path_buf(), r))
+            })
+            .collect();
+
+        let paths: BTreeSet<&PathBuf> = local.keys()
+            .chain(drive.keys())
+            .chain(baseline.keys())
+            .collect();
+
+        let opts = SyncOptions::two_way();
+        let mut plan = Self {
+            push: SyncPlan {
+                src_dir: local_dir.to_path_buf(),
+                dest_dir: drive_dir.to_path_buf(),
+                opts,
+                actions: Vec::new(),
+            },
+            pull: SyncPlan {
+                src_dir: drive_dir.to_path_buf(),
+                dest_dir: local_dir.to_path_buf(),
+                opts,
+                actions: Vec::new(),
+            },
+            conflicts: Vec::new(),
+            filter: filter.clone(),
+        };
+
+        for path in paths {
+            let l = local.get(path);
+            let d = drive.get(path);
+            let base = baseline.get(path).copied();
+
+            match (is_changed(l, base), is_changed(d, base)) {
+                (false, false) => (),
+                (true, false) => plan.push.actions.extend(copy_action(path, l, d, None)),
+                (false, true) => plan.pull.actions.extend(copy_action(path, d, l, None)),
+                (true, true) => {
+                    if !is_same(l, d) {
+                        plan.add_conflict(path, l, d, policy, drive_nickname, out)?;
+                    }
+                }
+            }
+        }
+
+        Ok(plan)
+    }
+
+    fn add_conflict(
+        &mut self,
+        path: &Path,
+        l: Option<&Metadata>,
+        d: Option<&Metadata>,
+        policy: ConflictPolicy,
+        drive_nickname: &str,
+        out: &Reporter,
+    ) -> Result<()> {
+        let resolution = match (l, d) {
+            // A change always wins over a deletion
+            (Some(_), None) => Resolution::Local,
+            (None, Some(_)) => Resolution::Drive,
+            _ => match policy {
+                ConflictPolicy::Newest => newest(l, d),
+                ConflictPolicy::KeepBoth => Resolution::Both,
+                ConflictPolicy::Prompt => out.suspend(|| prompt(path, l, d, drive_nickname))?,
+            },
+        };
+
+        let mut renamed = None;
+
+        match resolution {
+            Resolution::Local => {
+                self.push.actions.extend(copy_action(path, l, d, Some(Reason::Conflict)));
+            }
+            Resolution::Drive => {
+                self.pull.actions.extend(copy_action(path, d, l, Some(Reason::Conflict)));
+            }
+            Resolution::Both => {
+                // The drive's version is moved aside on the drive before
+                // applying, then copied locally under its new name
+                let sides = [self.push.src_dir.as_path(), self.push.dest_dir.as_path()];
+                let new_path = conflict_path(path, drive_nickname, sides);
+                let size = d.map(|m| m.len()).unwrap_or(0);
+
+                self.push.actions.extend(copy_action(path, l, None, Some(Reason::Conflict)));
+                self.pull.actions.push(SyncAction::file(
+                    ActionKind::Create, new_path.clone(), size, Reason::Conflict,
+                ));
+                renamed = Some(new_path);
+            }
+        }
+
+        self.conflicts.push(Conflict {
+            path: path.to_path_buf(),
+            local_version: l.map(FileRecord::from_metadata),
+            drive_version: d.map(FileRecord::from_metadata),
+            resolution,
+            renamed,
+        });
+
+        Ok(())
+    }
+
+    /// Carry out the plan: set aside conflicting drive files that are
+    /// being kept, then push and pull.
+    pub fn apply(&self, progress: &Progress) -> Result<()> {
+        for conflict in self.conflicts.iter() {
+            if let Some(renamed) = &conflict.renamed {
+                let from = self.push.dest_dir.join(&conflict.path);
+                let to = self.push.dest_dir.join(renamed);
+
+                // Renaming would silently replace a file that turned up
+                // there since planning
+                if fs::symlink_metadata(&to).is_ok() {
+                    bail!("Can't rename {} to {}, which exists", from.display(), to.display());
+                }
+                fs::rename(&from, &to).with_context(|| {
+                    format!("Failed to rename {} to {}", from.display(), to.display())
+                })?;
+            }
+        }
+
+        self.push.apply(progress)?;
+        self.pull.apply(progress)?;
+
+        Ok(())
+    }
+
+    /// Record every file that now matches on both sides as the new
+    /// baseline in the drive's manifest.
+    pub fn update_manifest(&self, manifest: &mut Manifest) -> Result<()> {
+        let prefix = manifest.relative(&self.push.dest_dir);
+        let local = files_by_path(&self.push.src_dir, &self.filter)?;
+
+        manifest.forget(&prefix);
+
+        for (path, meta) in files_by_path(&self.push.dest_dir, &self.filter)? {
+            if is_same(local.get(&path), Some(&meta)) {
+                manifest.record(prefix.join(path))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn files_by_path(dir: &Path, filter: &Filter) -> Result<BTreeMap<PathBuf, Metadata>> {
+    if !dir.is_dir() {
+        return Ok(BTreeMap::new());
+    }
+
+    Ok(sync::walk_filtered(dir, filter)?.into_iter().collect())
+}
+
+/// Whether one side differs from the baseline.
+fn is_changed(meta: Option<&Metadata>, base: Option<&FileRecord>) -> bool {
+    match (meta, base) {
+        (Some(meta), Some(base)) => !base.matches(meta),
+        (None, None) => false,
+        _ => true,
+    }
+}
+
+/// Whether both sides hold the same file, or neither does.
+fn is_same(a: Option<&Metadata>, b: Option<&Metadata>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => FileRecord::from_metadata(a).matches(b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Action that makes the other side match `from`.
+fn copy_action(
+    path: &Path,
+    from: Option<&Metadata>,
+    to: Option<&Metadata>,
+    reason: Option<Reason>,
+) -> Option<SyncAction> {
+    let path = path.to_path_buf();
+
+    match (from, to) {
+        (Some(from), None) => Some(SyncAction::file(
+            ActionKind::Create, path, from.len(), reason.unwrap_or(Reason::Missing),
+        )),
+        (Some(from), Some(to)) => {
+            let changed = if from.len() != to.len() {
+                Reason::SizeChanged
+            } else {
+                Reason::MtimeChanged
+            };
+            Some(SyncAction::file(
+                ActionKind::Update, path, from.len(), reason.unwrap_or(changed),
+            ))
+        }
+        (None, Some(to)) => Some(SyncAction::file(
+            ActionKind::Delete, path, to.len(), reason.unwrap_or(Reason::DeletedInSource),
+        )),
+        (None, None) => None,
+    }
+}
+
+fn newest(l: Option<&Metadata>, d: Option<&Metadata>) -> Resolution {
+    let mtime = |m: Option<&Metadata>| m.map(sync::mtime_secs).unwrap_or(0);
+
+    if mtime(d) > mtime(l) {
+        Resolution::Drive
+    } else {
+        Resolution::Local
+    }
+}
+
+/// Ask on the terminal which side of a conflict to keep. Without a
+/// terminal to ask on, both are kept. Progress bars need to be out of
+/// the way first.
+fn prompt(
+    path: &Path,
+    l: Option<&Metadata>,
+    d: Option<&Metadata>,
+    drive_nickname: &str,
+) -> Result<Resolution> {
+    // Drives synced side by side take turns asking
+    static TERMINAL: Mutex<()> = Mutex::new(());
+
+    if !io::stdin().is_terminal() {
+        return Ok(Resolution::Both);
+    }
+    let _turn = TERMINAL.lock().unwrap();
+
+    let describe = |m: Option<&Metadata>| match m {
+        Some(m) => {
+            let modified = m.modified()
+                .map(|t| chrono::DateTime::<Local>::from(t).format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_default();
+            format!("{} bytes, modified {}", m.len(), modified)
+        }
+        None => "deleted".to_string(),
+    };
+
+    eprintln!("Conflict with {}: {}", drive_nickname, path.display());
+    eprintln!("  local: {}", describe(l));
+    eprintln!("  drive: {}", describe(d));
+
+    loop {
+        eprint!("Keep [l]ocal, [d]rive or [b]oth? ");
+        io::stderr().flush()?;
+
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer)? == 0 {
+            return Ok(Resolution::Both);
+        }
+
+        match answer.trim().to_lowercase().as_str() {
+            "l" | "local" => return Ok(Resolution::Local),
+            "d" | "drive" => return Ok(Resolution::Drive),
+            "b" | "both" => return Ok(Resolution::Both),
+            _ => (),
+        }
+    }
+}
+
+/// Name for the drive's copy of a conflicting file, e.g.
+/// `notes.conflict-usb-20240101.txt`, counting up from
+/// `notes.conflict-usb-20240101-2.txt` until the name is free in every
+/// one of `dirs`.
+fn conflict_path(path: &Path, drive_nickname: &str, dirs: [&Path; 2]) -> PathBuf {
+    let tag: String = drive_nickname.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+        .collect();
+    let date = Local::now().format("%Y%m%d");
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    (1..)
+        .map(|n| {
+            let suffix = if n == 1 { String::new() } else { format!("-{}", n) };
+            let name = match path.extension() {
+                Some(ext) => format!(
+                    "{}.conflict-{}-{}{}.{}", stem, tag, date, suffix, ext.to_string_lossy(),
+                ),
+                None => format!("{}.conflict-{}-{}{}", stem, tag, date, suffix),
+            };
+            path.with_file_name(name)
+        })
+        .find(|p| dirs.iter().all(|dir| fs::symlink_metadata(dir.join(p)).is_err()))
+        .expect("some conflict name is free")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::output::OutputMode;
+    use tempfile::TempDir;
+
+    const OLD: u64 = 1_000_000_000;
+
+    fn write(path: &Path, contents: &str, mtime: u64) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+        File::options().write(true).open(path).unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(mtime))
+            .unwrap();
+    }
+
+    fn read(path: &Path) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    fn hidden() -> Progress {
+        Progress::new(None, String::new(), 0, 0)
+    }
+
+    /// A local directory and a drive that were last synced holding the
+    /// same `files`, with the drive's manifest as the baseline.
+    struct Synced {
+        _tmp: TempDir,
+        local: PathBuf,
+        drive: PathBuf,
+        manifest: Manifest,
+    }
+
+    impl Synced {
+        fn new(files: &[(&str, &str)]) -> Self {
+            let tmp = TempDir::new().unwrap();
+            let local = tmp.path().join("local");
+            let base = tmp.path().join("drive");
+            let drive = base.join("docs");
+            fs::create_dir_all(&local).unwrap();
+            fs::create_dir_all(&drive).unwrap();
+
+            let mut manifest = Manifest::load(&base.to_string_lossy(), false).unwrap();
+            for (name, contents) in files.iter() {
+                write(&local.join(name), contents, OLD);
+                write(&drive.join(name), contents, OLD);
+                manifest.record(Path::new("docs").join(name)).unwrap();
+            }
+            manifest.synced_at = Some(Local::now());
+
+            Self { _tmp: tmp, local, drive, manifest }
+        }
+
+        fn plan(&self, policy: ConflictPolicy) -> TwoWayPlan {
+            let quiet = Reporter::new(OutputMode::Human).buffered();
+            TwoWayPlan::new(&self.local, &self.drive, &self.manifest, policy, "USB Drive", &Filter::default(), &quiet)
+                .unwrap()
+        }
+
+        fn apply(&mut self, plan: &TwoWayPlan) {
+            plan.apply(&hidden()).unwrap();
+            plan.update_manifest(&mut self.manifest).unwrap();
+        }
+    }
+
+    fn actions(plan: &SyncPlan) -> Vec<(ActionKind, String, Reason)> {
+        plan.actions.iter()
+            .map(|a| (a.kind, a.path.display().to_string(), a.reason))
+            .collect()
+    }
+
+    #[test]
+    fn copies_one_sided_changes_each_way() {
+        let mut synced = Synced::new(&[
+            ("local_edit.txt", "a"),
+            ("drive_edit.txt", "b"),
+            ("local_gone.txt", "c"),
+            ("untouched.txt", "d"),
+        ]);
+        write(&synced.local.join("local_edit.txt"), "local edit", OLD + 10);
+        write(&synced.drive.join("drive_edit.txt"), "drive edit", OLD + 10);
+        write(&synced.local.join("local_new.txt"), "new", OLD);
+        fs::remove_file(synced.local.join("local_gone.txt")).unwrap();
+
+        let plan = synced.plan(ConflictPolicy::Newest);
+
+        assert_eq!(actions(&plan.push), [
+            (ActionKind::Update, "local_edit.txt".to_string(), Reason::SizeChanged),
+            (ActionKind::Delete, "local_gone.txt".to_string(), Reason::DeletedInSource),
+            (ActionKind::Create, "local_new.txt".to_string(), Reason::Missing),
+        ]);
+        assert_eq!(actions(&plan.pull), [
+            (ActionKind::Update, "drive_edit.txt".to_string(), Reason::SizeChanged),
+        ]);
+        assert!(plan.conflicts.is_empty());
+
+        synced.apply(&plan);
+
+        assert_eq!(read(&synced.drive.join("local_edit.txt")), "local edit");
+        assert_eq!(read(&synced.local.join("drive_edit.txt")), "drive edit");
+        assert!(!synced.drive.join("local_gone.txt").exists());
+
+        // Both sides now match the new baseline
+        let again = synced.plan(ConflictPolicy::Newest);
+        assert!(again.push.actions.is_empty() && again.pull.actions.is_empty());
+    }
+
+    #[test]
+    fn newest_keeps_whichever_side_changed_last() {
+        let mut synced = Synced::new(&[("local_wins.txt", "a"), ("drive_wins.txt", "b")]);
+        write(&synced.local.join("local_wins.txt"), "local, later", OLD + 20);
+        write(&synced.drive.join("local_wins.txt"), "drive, earlier", OLD + 10);
+        write(&synced.local.join("drive_wins.txt"), "local, earlier", OLD + 10);
+        write(&synced.drive.join("drive_wins.txt"), "drive, later", OLD + 20);
+
+        let plan = synced.plan(ConflictPolicy::Newest);
+
+        let resolutions: Vec<(String, Resolution)> = plan.conflicts.iter()
+            .map(|c| (c.path.display().to_string(), c.resolution))
+            .collect();
+        assert_eq!(resolutions, [
+            ("drive_wins.txt".to_string(), Resolution::Drive),
+            ("local_wins.txt".to_string(), Resolution::Local),
+        ]);
+        assert_eq!(actions(&plan.push), [
+            (ActionKind::Update, "local_wins.txt".to_string(), Reason::Conflict),
+        ]);
+        assert_eq!(actions(&plan.pull), [
+            (ActionKind::Update, "drive_wins.txt".to_string(), Reason::Conflict),
+        ]);
+
+        synced.apply(&plan);
+
+        for side in [&synced.local, &synced.drive] {
+            assert_eq!(read(&side.join("local_wins.txt")), "local, later");
+            assert_eq!(read(&side.join("drive_wins.txt")), "drive, later");
+        }
+    }
+
+    #[test]
+    fn keep_both_renames_the_drives_copy() {
+        let mut synced = Synced::new(&[("notes.txt", "a")]);
+        write(&synced.local.join("notes.txt"), "local edit", OLD + 10);
+        write(&synced.drive.join("notes.txt"), "drive edit", OLD + 20);
+
+        let plan = synced.plan(ConflictPolicy::KeepBoth);
+
+        let renamed = format!("notes.conflict-usb-drive-{}.txt", Local::now().format("%Y%m%d"));
+        assert_eq!(plan.conflicts.len(), 1);
+        assert_eq!(plan.conflicts[0].resolution, Resolution::Both);
+        assert_eq!(plan.conflicts[0].renamed, Some(PathBuf::from(&renamed)));
+
+        synced.apply(&plan);
+
+        for side in [&synced.local, &synced.drive] {
+            assert_eq!(read(&side.join("notes.txt")), "local edit");
+            assert_eq!(read(&side.join(&renamed)), "drive edit");
+        }
+    }
+
+    #[test]
+    fn repeated_conflicts_keep_every_drive_version() {
+        let mut synced = Synced::new(&[("notes.txt", "a")]);
+        let date = Local::now().format("%Y%m%d");
+
+        for (round, mtime) in [(1, OLD + 10), (2, OLD + 30)] {
+            write(&synced.local.join("notes.txt"), &format!("local edit {}", round), mtime);
+            write(&synced.drive.join("notes.txt"), &format!("drive edit {}", round), mtime + 10);
+
+            let plan = synced.plan(ConflictPolicy::KeepBoth);
+            synced.apply(&plan);
+        }
+
+        let first = format!("notes.conflict-usb-drive-{}.txt", date);
+        let second = format!("notes.conflict-usb-drive-{}-2.txt", date);
+        for side in [&synced.local, &synced.drive] {
+            assert_eq!(read(&side.join("notes.txt")), "local edit 2");
+            assert_eq!(read(&side.join(&first)), "drive edit 1");
+            assert_eq!(read(&side.join(&second)), "drive edit 2");
+        }
+    }
+
+    #[test]
+    fn conflict_names_skip_files_on_either_side() {
+        let mut synced = Synced::new(&[("notes.txt", "a")]);
+        let date = Local::now().format("%Y%m%d");
+        write(&synced.local.join("notes.txt"), "local edit", OLD + 10);
+        write(&synced.drive.join("notes.txt"), "drive edit", OLD + 20);
+
+        // A local file of the user's that happens to have the name, and
+        // one on the drive left out of syncing
+        let taken = format!("notes.conflict-usb-drive-{}.txt", date);
+        let also_taken = format!("notes.conflict-usb-drive-{}-2.txt", date);
+        write(&synced.local.join(&taken), "mine", OLD);
+        write(&synced.drive.join(&also_taken), "theirs", OLD);
+
+        let plan = synced.plan(ConflictPolicy::KeepBoth);
+        let renamed = format!("notes.conflict-usb-drive-{}-3.txt", date);
+        assert_eq!(plan.conflicts[0].renamed, Some(PathBuf::from(&renamed)));
+
+        synced.apply(&plan);
+
+        assert_eq!(read(&synced.local.join(&taken)), "mine");
+        assert_eq!(read(&synced.drive.join(&also_taken)), "theirs");
+        assert_eq!(read(&synced.local.join(&renamed)), "drive edit");
+    }
+
+    #[test]
+    fn changes_win_over_deletions_and_matching_changes_agree() {
+        let synced = Synced::new(&[("edited.txt", "a"), ("same.txt", "b")]);
+        fs::remove_file(synced.local.join("edited.txt")).unwrap();
+        write(&synced.drive.join("edited.txt"), "drive edit", OLD + 10);
+        write(&synced.local.join("same.txt"), "same edit", OLD + 10);
+        write(&synced.drive.join("same.txt"), "same edit", OLD + 10);
+
+        let plan = synced.plan(ConflictPolicy::KeepBoth);
+
+        assert_eq!(plan.conflicts.len(), 1);
+        assert_eq!(plan.conflicts[0].path, Path::new("edited.txt"));
+        assert_eq!(plan.conflicts[0].resolution, Resolution::Drive);
+        assert_eq!(actions(&plan.pull), [
+            (ActionKind::Create, "edited.txt".to_string(), Reason::Conflict),
+        ]);
+        assert!(plan.push.actions.is_empty());
+    }
+}
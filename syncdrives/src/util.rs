@@ -2,19 +2,37 @@
 
 use std::fs;
 use std::io::{Error, ErrorKind};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Output};
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 
-use crate::config::Drive;
+use crate::config::{Config, DeletePolicy, Drive, GroupMode, MountBackend, SyncGroup};
+use crate::filter::{Filter, FilterRules};
+use crate::manifest::{Manifest, Tracking};
+use crate::output::{Event, Phase, Reporter};
+use crate::sync::{ActionKind, Reason, SyncOptions, SyncPlan, SyncStats};
+use crate::twoway::{Conflict, ConflictPolicy, Resolution, TwoWayPlan};
 
 #[derive(Debug)]
 pub struct DriveInfo {
-    pub letter: String,
+    pub mount: MountBackend,
     pub nickname: String,
     pub base_dir: String,
     pub mountpoint: String,
+    pub keep_mounted: bool,
     pub err: Option<DestError>,
+
+    /// What to sync onto the drive
+    pub filter: FilterRules,
+
+    /// Whether this run mounted the drive
+    pub mounted: bool,
+
+    /// Changes made to the drive by this run
+    pub stats: SyncStats,
+
+    /// Files changed both locally and on the drive, in two-way syncs
+    pub conflicts: Vec<Conflict>,
 }
 
 impl DriveInfo {
@@ -26,20 +44,30 @@ impl DriveInfo {
     pub fn from_drive(drive: &Drive) -> Self {
         make_drive_info(drive)
     }
+
+    /// Whether the drive gets unmounted at the end of the run.
+    pub fn needs_unmount(&self) -> bool {
+        self.mounted && !self.keep_mounted
+    }
 }
 
 fn make_drive_info(drive: &Drive) -> DriveInfo {
-    let letter = drive.get_letter();
+    let mount = drive.mount.clone();
     let nickname = drive.get_nickname();
     let base_dir = drive.get_base_dir();
     let mountpoint = drive.get_mountpoint();
 
     DriveInfo {
-        letter,
+        mount,
         nickname,
         base_dir,
         mountpoint,
+        keep_mounted: drive.keep_mounted,
         err: None,
+        filter: drive.filter.clone(),
+        mounted: false,
+        stats: SyncStats::default(),
+        conflicts: Vec::new(),
     }
 }
 
@@ -60,7 +88,34 @@ impl DestError {
 
 // MOUNTING
 
-pub fn mount_drive(dest: &DriveInfo) -> Result<()> {
+/// Mount a drive if it isn't already, returning whether it was
+/// mounted by this call.
+pub fn mount_drive(dest: &DriveInfo) -> Result<bool> {
+    match &dest.mount {
+        MountBackend::Drvfs { .. } => mount_drvfs(dest),
+        MountBackend::Block { .. } => mount_block(dest),
+        MountBackend::Mounted { path } => {
+            if !is_mounted(path) {
+                bail!("Nothing is mounted at {}", path);
+            }
+            Ok(false)
+        }
+    }
+}
+
+pub fn unmount_drive(dest: &DriveInfo) -> Result<()> {
+    let umount = Command::new("umount")
+        .arg(dest.mountpoint.as_str())
+        .output();
+
+    if let Err(e) = check_output(umount) {
+        bail!("Failed to unmount {}: {}", dest.mountpoint, e);
+    }
+
+    Ok(())
+}
+
+fn mount_drvfs(dest: &DriveInfo) -> Result<bool> {
     // Try to create mountpoint
     if let Err(e) = fs::create_dir(&dest.mountpoint) {
         match e.kind() {
@@ -69,241 +124,582 @@ pub fn mount_drive(dest: &DriveInfo) -> Result<()> {
         }
     }
 
-    if is_mountpoint_empty(&dest.mountpoint) {
+    let is_empty = is_mountpoint_empty(&dest.mountpoint)
+        .with_context(|| format!("Failed to read {}", dest.mountpoint))?;
+
+    if is_empty {
         // Mount the drive contents at mountpoint
+        let letter = dest.mount.source();
         let mount = Command::new("mount")
-            .args(["-t", "drvfs", dest.letter.as_str(), dest.mountpoint.as_str()])
+            .args(["-t", "drvfs", letter.as_str(), dest.mountpoint.as_str()])
             .output();
 
-        if !is_success(&mount) {
-            bail!("Failed to mount {} at {}", dest.letter, dest.mountpoint);
+        if let Err(e) = check_output(mount) {
+            bail!("Failed to mount {} at {}: {}", letter, dest.mountpoint, e);
         }
+
+        return Ok(true);
     }
 
-    Ok(())
+    Ok(false)
+}
+
+fn mount_block(dest: &DriveInfo) -> Result<bool> {
+    let MountBackend::Block { fstype, options, .. } = &dest.mount else {
+        bail!("{} is not a block device", dest.nickname);
+    };
+
+    let device = dest.mount.device_path().unwrap_or_default();
+
+    if let Some(source) = mounted_source(&dest.mountpoint) {
+        if !is_same_device(Path::new(&source), &device) {
+            bail!("{} is mounted at {} instead of {}", source, dest.mountpoint, dest.mount.source());
+        }
+        return Ok(false);
+    }
+
+    if !device.exists() {
+        bail!("Device {} not found", dest.mount.source());
+    }
+
+    fs::create_dir_all(&dest.mountpoint)
+        .with_context(|| format!("Failed to create {}", dest.mountpoint))?;
+
+    let mut cmd = Command::new("mount");
+    if let Some(fstype) = fstype {
+        cmd.args(["-t", fstype.as_str()]);
+    }
+    if let Some(options) = options {
+        cmd.args(["-o", options.as_str()]);
+    }
+
+    // Mount the device at mountpoint
+    let mount = cmd.arg(&device).arg(&dest.mountpoint).output();
+
+    if let Err(e) = check_output(mount) {
+        bail!("Failed to mount {} at {}: {}", device.display(), dest.mountpoint, e);
+    }
+
+    Ok(true)
 }
 
-fn is_mountpoint_empty(mountpoint: &String) -> bool {
+/// Check whether a filesystem is mounted at exactly `mountpoint`.
+pub fn is_mounted(mountpoint: &str) -> bool {
+    match fs::read_to_string("/proc/mounts") {
+        Ok(mounts) => find_mount(&mounts, mountpoint).is_some(),
+        Err(_) => !is_mountpoint_empty(mountpoint).unwrap_or(false),
+    }
+}
+
+/// What's mounted at exactly `mountpoint`, if anything.
+fn mounted_source(mountpoint: &str) -> Option<String> {
+    find_mount(&fs::read_to_string("/proc/mounts").ok()?, mountpoint)
+}
+
+/// Source of the last mount at `mountpoint` in a /proc/mounts listing,
+/// which hides any earlier ones.
+fn find_mount(mounts: &str, mountpoint: &str) -> Option<String> {
+    let target = match mountpoint.trim_end_matches('/') {
+        "" => "/",
+        path => path,
+    };
+
+    mounts.lines()
+        .rev()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some((fields.next()?, unescape_mount_path(fields.next()?)))
+        })
+        .find(|(_, m)| m == target)
+        .map(|(source, _)| unescape_mount_path(source))
+}
+
+/// Whether two device paths lead to the same device node.
+fn is_same_device(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Undo the octal escaping of spaces and such in /proc/mounts.
+fn unescape_mount_path(path: &str) -> String {
+    path.replace("\\040", " ")
+        .replace("\\011", "\t")
+        .replace("\\012", "\n")
+        .replace("\\134", "\\")
+}
+
+fn is_mountpoint_empty(mountpoint: &str) -> Result<bool, Error> {
     // Check if mountpoint is empty
     match PathBuf::from(mountpoint)
         .read_dir()
         .map(|mut i| i.next().is_none())
     {
-        Ok(is_empty) => is_empty,
-        Err(e) => match e.kind() {
-            ErrorKind::InvalidInput => true,
-            _ => {
-                eprintln!("{}", e);
-                false
-            }
-        }
+        Ok(is_empty) => Ok(is_empty),
+        Err(e) if e.kind() == ErrorKind::InvalidInput => Ok(true),
+        Err(e) => Err(e),
     }
 }
 
 // SYNCING
 
 pub fn sync_dirs_with_local(
-    dest: &DriveInfo,
+    dest: &mut DriveInfo,
+    cfg: &Config,
     base_src_dir: &str,
-    subdirs: &Vec<String>,
-    hidden_files: &Vec<String>,
     user: &str,
     dry_run: bool,
-) -> Result<()> {
-    let mut rsync_opts = vec![
-        "-a", "--no-links", "--itemize-changes", "--update", "--delete",
-    ];
+    manifest: &mut Manifest,
+    out: &Reporter,
+) -> Result<SyncStats> {
+    let mut stats = SyncStats::default();
 
-    if dry_run {
-        rsync_opts.push("--dry-run");
-    }
-
-    if !hidden_files.is_empty() {
+    if let Some(hidden_files) = cfg.hidden_files.as_ref().filter(|f| !f.is_empty()) {
         // Sync hidden files
-        if let Err(e) = copy_hidden_files(
+        let plan = copy_hidden_files(
             base_src_dir,
-            dest.base_dir.as_str(),
-            dest.nickname.as_str(),
-            &hidden_files,
+            dest,
+            hidden_files,
             user,
-            dry_run
-        ) {
-            bail!("{}", e);
-        }
+            dry_run,
+            manifest,
+            out,
+        )?;
+        stats.add_plan(&plan);
     }
 
     // Sync with local subdirectories
-    for subdir in subdirs.iter() {
-        let src_dir = format!("{}/{}/", base_src_dir, subdir);
-        let dest_dir = format!("{}/wsl/{}/{}/", dest.base_dir, user, subdir);
-
-        let rsync = run_rsync(
-            src_dir.as_str(),
-            dest_dir.as_str(),
-            "Local",
-            dest.nickname.as_str(),
-            subdir,
-            &rsync_opts
-        );
+    // Prompting would get in the way of machine-readable output
+    let policy = match cfg.conflict_policy {
+        ConflictPolicy::Prompt if !out.is_human() => ConflictPolicy::KeepBoth,
+        policy => policy,
+    };
+    let arrow = if cfg.two_way { "<->" } else { "->" };
+
+    for subdir in cfg.subdirs.iter().filter(|s| s.goes_to(&dest.nickname)) {
+        let filter = Filter::new(&[&cfg.filter, &subdir.filter, &dest.filter])
+            .with_context(|| format!("Bad include or exclude rules for {}", subdir.name))?;
+        let opts = SyncOptions {
+            delete: subdir.delete != DeletePolicy::Never,
+            delete_changed: subdir.delete == DeletePolicy::Always,
+            checksum: subdir.checksum,
+            ..SyncOptions::local()
+        };
+
+        let src_dir = format!("{}/", subdir.get_source(base_src_dir));
+        let dest_dir = format!("{}/", subdir.get_dest(&dest.base_dir, user, &dest.nickname)?);
+        let subdir = subdir.name.as_str();
+
+        out.say(format!(
+            "\nLocal {sdir}/ {arrow} {dest} {sdir}/",
+            dest=dest.nickname, sdir=subdir, arrow=arrow,
+        ));
+
+        if manifest.has_baseline() {
+            report_drive_changes(manifest, &dest_dir, dest.nickname.as_str(), out)?;
+        }
 
-        if is_success(&rsync) {
-            print_rsync_output_lines(&rsync);
+        if cfg.two_way {
+            let plan = TwoWayPlan::new(
+                Path::new(&src_dir),
+                Path::new(&dest_dir),
+                manifest,
+                policy,
+                dest.nickname.as_str(),
+                &filter,
+                out,
+            )
+            .and_then(|plan| {
+                run_two_way(&plan, dest.nickname.as_str(), dry_run, manifest, out)?;
+                Ok(plan)
+            })
+            .with_context(|| {
+                format!("Failed to sync `{}` and `{}` both ways", dest_dir, src_dir)
+            })?;
+
+            stats.add_plan(&plan.push);
+            stats.add_plan(&plan.pull);
+            stats.conflicts += plan.conflicts.len();
+
+            let nickname = dest.nickname.as_str();
+            out.emit(Event::subdir(
+                nickname, "Local", Phase::LocalSync, subdir, &plan.push, dry_run,
+            ));
+            out.emit(Event::subdir(
+                nickname, nickname, Phase::Pull, subdir, &plan.pull, dry_run,
+            ));
+
+            dest.conflicts.extend(plan.conflicts);
+        } else {
+            let plan = SyncPlan::new(
+                Path::new(&src_dir),
+                Path::new(&dest_dir),
+                &opts,
+                &filter,
+            )
+            .and_then(|mut plan| {
+                run_plan(&mut plan, dest.nickname.as_str(), Phase::LocalSync, dry_run, manifest, out)?;
+                Ok(plan)
+            })
+            .with_context(|| {
+                format!("Failed to sync `{}` with `{}`", dest_dir, src_dir)
+            })?;
+
+            stats.add_plan(&plan);
+            out.emit(Event::subdir(
+                dest.nickname.as_str(), "Local", Phase::LocalSync, subdir, &plan, dry_run,
+            ));
+        }
 
-            if dry_run {
-                println!("Would sync `{}` with `{}`", dest_dir, src_dir);
-            } else {
-                println!("Synced `{}` with `{}`", dest_dir, src_dir);
-            }
+        if dry_run {
+            out.say(format!("Would sync `{}` with `{}`", dest_dir, src_dir));
         } else {
-            bail!("Failed to sync `{}` with `{}`", dest_dir, src_dir);
+            out.say(format!("Synced `{}` with `{}`", dest_dir, src_dir));
         }
     }
 
-    Ok(())
+    Ok(stats)
 }
 
+/// Sync a group's directory on `src` to the same directory on `dest`,
+/// following the global rules and `dest`'s own.
 pub fn sync_dir(
-    src_dir: &str,
-    dest_dir: &str,
-    src_nickname: &str,
+    group: &SyncGroup,
+    src: &DriveInfo,
+    dest: &DriveInfo,
+    rules: &FilterRules,
+    dry_run: bool,
+    manifest: &mut Manifest,
+    out: &Reporter,
+) -> Result<SyncStats> {
+    let dir = group.get_dir()?;
+    let src_dir = format!("{}/{}/", src.base_dir, dir);
+    let dest_dir = format!("{}/{}/", dest.base_dir, dir);
+
+    out.say(format!(
+        "\n{src} {dir}/ -> {dest} {dir}/",
+        src=src.nickname, dest=dest.nickname, dir=dir,
+    ));
+
+    // A drive that never had the implicit group's directory just has
+    // nothing to add to it
+    if group.implicit && !Path::new(&src_dir).is_dir() {
+        out.say(format!("Nothing to sync, {} has no {}/", src.nickname, dir));
+        return Ok(SyncStats::default());
+    }
+
+    let opts = match group.mode {
+        GroupMode::Union => SyncOptions::cross_drive(),
+        GroupMode::Mirror => SyncOptions::mirror(),
+        GroupMode::Hub => SyncOptions { delete: false, ..SyncOptions::mirror() },
+    };
+
+    let filter = Filter::new(&[rules, &dest.filter])
+        .with_context(|| format!("Bad include or exclude rules for {}", dest.nickname))?;
+
+    let plan = SyncPlan::new(Path::new(&src_dir), Path::new(&dest_dir), &opts, &filter)
+        .and_then(|mut plan| {
+            run_plan(&mut plan, dest.nickname.as_str(), Phase::CrossSync, dry_run, manifest, out)?;
+            Ok(plan)
+        })
+        .with_context(|| {
+            format!("Failed to sync `{}` with `{}`", dest_dir, src_dir)
+        })?;
+
+    out.emit(Event::subdir(
+        dest.nickname.as_str(), src.nickname.as_str(), Phase::CrossSync, &dir, &plan, dry_run,
+    ));
+
+    if dry_run {
+        out.say(format!("Would sync `{}` with `{}`", dest_dir, src_dir));
+    } else {
+        out.say(format!("Synced `{}` with `{}`", dest_dir, src_dir));
+    }
+
+    let mut stats = SyncStats::default();
+    stats.add_plan(&plan);
+
+    Ok(stats)
+}
+
+/// Report a sync plan's changes and apply it unless this is a dry-run.
+///
+/// If the drive has been synced before, deletions of files that changed
+/// on the drive since then are skipped rather than carried out, unless
+/// the plan's options say to delete them anyway.
+fn run_plan(
+    plan: &mut SyncPlan,
     dest_nickname: &str,
+    phase: Phase,
     dry_run: bool,
+    manifest: &mut Manifest,
+    out: &Reporter,
 ) -> Result<()> {
-    let mut rsync_opts = vec![
-        "--itemize-changes", "--recursive", "--ignore-existing",
-    ];
+    if manifest.has_baseline() && !plan.opts.delete_changed {
+        let prefix = manifest.relative(&plan.dest_dir);
+        plan.keep_unsafe_deletes(|path, meta| {
+            manifest.tracking(&prefix.join(path), meta) == Tracking::Unchanged
+        })?;
+    }
 
-    if dry_run {
-        rsync_opts.push("--dry-run");
+    let notable = plan.actions.iter()
+        .filter(|a| a.is_change() || a.reason == Reason::ChangedOnDest);
+
+    for action in notable {
+        out.say(action);
+        out.emit(Event::file(dest_nickname, phase, action, dry_run));
     }
 
-    let rsync = run_rsync(
-        src_dir, dest_dir, src_nickname, dest_nickname, "synced", &rsync_opts,
-    );
+    out.say(format!(
+        "{} to create, {} to update, {} to delete, {} skipped ({} bytes to copy)",
+        plan.count(ActionKind::Create),
+        plan.count(ActionKind::Update),
+        plan.count(ActionKind::Delete),
+        plan.count(ActionKind::Skip),
+        plan.bytes_to_copy(),
+    ));
+
+    if !dry_run {
+        let progress = out.progress(
+            progress_label(dest_nickname, &plan.dest_dir),
+            plan.files_to_copy(),
+            plan.bytes_to_copy(),
+        );
+        plan.apply(&progress)?;
+        manifest.update_from_plan(plan)?;
+    }
+
+    Ok(())
+}
+
+/// Report a two-way plan's changes and conflicts, and apply it unless
+/// this is a dry-run.
+fn run_two_way(
+    plan: &TwoWayPlan,
+    dest_nickname: &str,
+    dry_run: bool,
+    manifest: &mut Manifest,
+    out: &Reporter,
+) -> Result<()> {
+    let directions = [
+        (&plan.push, Phase::LocalSync, format!("Local -> {}", dest_nickname)),
+        (&plan.pull, Phase::Pull, format!("{} -> Local", dest_nickname)),
+    ];
 
-    if is_success(&rsync) {
-        let output = get_stdout(&rsync);
-        if !output.is_empty() {
-            println!("{}", output);
+    for (sync_plan, phase, label) in directions.iter() {
+        if sync_plan.changes().next().is_none() {
+            continue;
         }
 
-        if dry_run {
-            println!("Would sync `{}` with `{}`", dest_dir, src_dir);
-        } else {
-            println!("Synced `{}` with `{}`", dest_dir, src_dir);
+        out.say(format!("{}:", label));
+        for action in sync_plan.changes() {
+            out.say(format!("  {}", action));
+            out.emit(Event::file(dest_nickname, *phase, action, dry_run));
         }
-    } else {
-        bail!("Failed to sync `{}` with `{}`", dest_dir, src_dir);
+    }
+
+    for conflict in plan.conflicts.iter() {
+        let kept = match (conflict.resolution, &conflict.renamed) {
+            (Resolution::Local, _) => "keeping local".to_string(),
+            (Resolution::Drive, _) => format!("keeping {}", dest_nickname),
+            (Resolution::Both, Some(renamed)) => {
+                format!("keeping both, {}'s as {}", dest_nickname, renamed.display())
+            }
+            (Resolution::Both, None) => "keeping both".to_string(),
+        };
+
+        out.say(format!("Conflict: {} ({})", conflict.path.display(), kept));
+        out.emit(Event::Conflict {
+            drive: dest_nickname.to_string(),
+            dir: plan.push.dest_dir.clone(),
+            conflict: conflict.clone(),
+            dry_run,
+        });
+    }
+
+    out.say(format!(
+        "{} to push, {} to pull, {} conflicts ({} bytes to copy)",
+        plan.push.changes().count(),
+        plan.pull.changes().count(),
+        plan.conflicts.len(),
+        plan.push.bytes_to_copy() + plan.pull.bytes_to_copy(),
+    ));
+
+    if !dry_run {
+        let progress = out.progress(
+            progress_label(dest_nickname, &plan.push.dest_dir),
+            plan.push.files_to_copy() + plan.pull.files_to_copy(),
+            plan.push.bytes_to_copy() + plan.pull.bytes_to_copy(),
+        );
+        plan.apply(&progress)?;
+        plan.update_manifest(manifest)?;
     }
 
     Ok(())
 }
 
-fn run_rsync(
-    src_dir: &str,
+/// Label of a progress bar for syncing `dir` on a drive, e.g. `A docs/`.
+fn progress_label(dest_nickname: &str, dir: &Path) -> String {
+    let name = dir.file_name().unwrap_or_default().to_string_lossy();
+    format!("{} {}/", dest_nickname, name)
+}
+
+/// Report files under `dest_dir` that changed on the drive since its
+/// last sync.
+fn report_drive_changes(
+    manifest: &Manifest,
     dest_dir: &str,
-    src_nickname: &str,
     dest_nickname: &str,
-    subdir: &str,
-    rsync_opts: &Vec<&str>,
-) -> Result<Output, Error> {
-    println!(
-        "\n{src} {sdir}/ -> {dest} {sdir}/",
-        src=src_nickname, dest=dest_nickname, sdir=subdir,
-    );
-
-    // Try to create subdirectory path
-    let _ = fs::create_dir_all(dest_dir)?;
-
-    // Run rsync command
-    Command::new("rsync")
-        .args(rsync_opts)
-        .args([src_dir, dest_dir])
-        .output()
+    out: &Reporter,
+) -> Result<()> {
+    let diff = manifest.diff(Path::new(dest_dir))?;
+    if diff.is_empty() {
+        return Ok(());
+    }
+
+    out.say(format!(
+        "Changed on {} since last sync: {} added, {} modified, {} removed",
+        dest_nickname, diff.added.len(), diff.modified.len(), diff.removed.len(),
+    ));
+    for (label, paths) in [
+        ("added", &diff.added),
+        ("modified", &diff.modified),
+        ("removed", &diff.removed),
+    ] {
+        for path in paths.iter() {
+            out.say(format!("  {:<8} {}", label, path.display()));
+        }
+    }
+
+    out.emit(Event::DriveChanges {
+        drive: dest_nickname.to_string(),
+        dir: PathBuf::from(dest_dir),
+        added: diff.added,
+        modified: diff.modified,
+        removed: diff.removed,
+    });
+
+    Ok(())
 }
 
 // COPYING
 
 fn copy_hidden_files(
     src_dir: &str,
-    base_dest_dir: &str,
-    dest_nickname: &str,
-    files: &Vec<String>,
+    dest: &DriveInfo,
+    files: &[String],
     user: &str,
     dry_run: bool,
-) -> Result<()> {
-    let dest_dir = format!("{}/wsl/{}/", base_dest_dir, user);
+    manifest: &mut Manifest,
+    out: &Reporter,
+) -> Result<SyncPlan> {
+    let dest_dir = format!("{}/wsl/{}/", dest.base_dir, user);
+    let dest_nickname = dest.nickname.as_str();
+
+    out.say(format!("\nLocal hidden files -> {}", dest_nickname));
+    out.say(format!("`{}`", files.join("`, `")));
+
+    let plan = SyncPlan::for_files(
+        Path::new(src_dir),
+        Path::new(&dest_dir),
+        files,
+        &SyncOptions::overwrite(),
+    )
+    .and_then(|mut plan| {
+        run_plan(&mut plan, dest_nickname, Phase::LocalSync, dry_run, manifest, out)?;
+        Ok(plan)
+    })
+    .with_context(|| {
+        format!("Could not copy hidden files from `{}/` to `{}`", src_dir, dest_dir)
+    })?;
+
+    out.emit(Event::subdir(
+        dest_nickname, "Local", Phase::LocalSync, "hidden files", &plan, dry_run,
+    ));
 
     if dry_run {
-        println!("Would copy hidden files from `{}/` to `{}`", src_dir, dest_dir);
+        out.say(format!("Would copy hidden files from `{}/` to `{}`", src_dir, dest_dir));
     } else {
-        let cp = run_cp_hidden_files(
-            src_dir, dest_dir.as_str(), dest_nickname, files,
-        );
-
-        if is_success(&cp) {
-            println!("Copied hidden files from `{}/` to `{}`", src_dir, dest_dir);
-        } else {
-            bail!("Could not copy hidden files from `{}/` to `{}`", src_dir, dest_dir);
-        }
+        out.say(format!("Copied hidden files from `{}/` to `{}`", src_dir, dest_dir));
     }
 
-    Ok(())
+    Ok(plan)
 }
 
-fn run_cp_hidden_files(
-    src_dir: &str,
-    dest_dir: &str,
-    dest_nickname: &str,
-    files: &Vec<String>,
-) -> Result<Output, Error> {
-    println!("\nLocal hidden files -> {}", dest_nickname);
-    println!("`{}`", files.join("`, `"));
+// COMMAND OUTPUT
+
+/// Fail with what a command printed to stderr, or its exit status,
+/// unless it succeeded.
+fn check_output(output: Result<Output, Error>) -> Result<(), String> {
+    let output = output.map_err(|e| e.to_string())?;
 
-    let mut hidden_files: Vec<String> = Vec::new();
-    for filename in files.iter() {
-        let path = format!("{}/{}", src_dir, filename);
-        hidden_files.push(path);
+    if output.status.success() {
+        return Ok(());
     }
 
-    // Run cp command
-    Command::new("cp").args(hidden_files).arg(dest_dir).output()
+    match String::from_utf8_lossy(&output.stderr).trim() {
+        "" => Err(output.status.to_string()),
+        stderr => Err(stderr.to_string()),
+    }
 }
 
-// COMMAND OUTPUT
-
-fn print_rsync_output_lines(output: &Result<Output, Error>) {
-    for line in get_stdout(output).lines() {
-        if line.starts_with(">") {
-            println!("{}", line);
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    const MOUNTS: &str = "\
+/dev/sda1 / ext4 rw 0 0
+/dev/sdb1 /mnt/usb ext4 rw 0 0
+/dev/sdc1 /mnt/usb ext4 rw 0 0
+/dev/sdd1 /mnt/my\\040drive vfat rw 0 0
+";
+
+    fn drive(mount: &str, keep_mounted: bool) -> DriveInfo {
+        let toml = format!("mount = {}\nkeep_mounted = {}", mount, keep_mounted);
+        DriveInfo::from_drive(&toml::from_str(&toml).unwrap())
     }
-}
 
-fn get_stdout(output: &Result<Output, Error>) -> String {
-    let out = output.as_ref().unwrap();
-    String::from_utf8_lossy(&out.stdout).to_string()
-}
+    #[test]
+    fn only_drives_mounted_by_the_run_get_unmounted() {
+        let mut usb = drive(r#"{ type = "block", device = "/dev/sdb1" }"#, false);
+        assert!(!usb.needs_unmount());
+        usb.mounted = true;
+        assert!(usb.needs_unmount());
 
-fn is_success(output: &Result<Output, Error>) -> bool {
-    let mut success: bool = false;
-
-    match output {
-        Ok(output) => {
-            if output.status.success() {
-                success = true;
-            } else {
-                let mut err_str = String::from_utf8_lossy(&output.stderr);
-                if err_str.is_empty() {
-                    err_str = output.status.to_string().into();
-                }
-                eprintln!("{}", err_str);
-            }
-        }
-        Err(e) => {
-            eprintln!("{}", e);
-        }
+        let mut kept = drive(r#"{ type = "block", device = "/dev/sdc1" }"#, true);
+        kept.mounted = true;
+        assert!(!kept.needs_unmount());
+
+        // Already mounted filesystems are never the run's to unmount
+        let root = drive(r#"{ type = "mounted", path = "/" }"#, false);
+        assert!(!mount_drive(&root).unwrap());
+
+        let tmp = TempDir::new().unwrap();
+        let missing = drive(&format!(r#"{{ type = "mounted", path = "{}" }}"#, tmp.path().display()), false);
+        assert!(mount_drive(&missing).is_err());
+    }
+
+    #[test]
+    fn finds_the_last_mount_at_a_mountpoint() {
+        assert_eq!(find_mount(MOUNTS, "/mnt/usb/").as_deref(), Some("/dev/sdc1"));
+        assert_eq!(find_mount(MOUNTS, "/mnt/my drive").as_deref(), Some("/dev/sdd1"));
+        assert_eq!(find_mount(MOUNTS, "/").as_deref(), Some("/dev/sda1"));
+        assert_eq!(find_mount(MOUNTS, "/mnt"), None);
     }
 
-    success
+    #[test]
+    fn device_links_lead_to_the_same_device() {
+        let tmp = TempDir::new().unwrap();
+        let (sdb1, sdc1) = (tmp.path().join("sdb1"), tmp.path().join("sdc1"));
+        fs::write(&sdb1, "").unwrap();
+        fs::write(&sdc1, "").unwrap();
+        symlink(&sdb1, tmp.path().join("by-label")).unwrap();
+
+        assert!(is_same_device(&tmp.path().join("by-label"), &sdb1));
+        assert!(!is_same_device(&tmp.path().join("by-label"), &sdc1));
+        assert!(!is_same_device(&tmp.path().join("missing"), &tmp.path().join("missing")));
+    }
 }